@@ -0,0 +1,92 @@
+// Session authentication - JWT tokens for production security.
+//
+// Session tokens are HS256 JWTs signed with `session.secret`. Browsers
+// present them as the `access_token.<jwt>` WebSocket subprotocol; other
+// clients may use `Authorization: Bearer <jwt>` where HTTP headers are
+// available.
+
+use chrono::Utc;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation, decode, encode};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+pub use jsonwebtoken::errors::Error as TokenError;
+
+/// Subject of a verified client certificate (common name, or the full
+/// distinguished name when the certificate has no CN). Attached to requests
+/// as an extension by the TLS listener.
+#[derive(Debug, Clone)]
+pub struct ClientIdentity {
+    pub subject: String,
+}
+
+/// JWT claims structure for session tokens.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    pub iat: i64,
+    pub exp: i64,
+    /// Subject the session is issued to (user or service identity).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    /// Settings profile to apply; falls back to the "default" profile.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    /// Any additional claims, carried through untouched.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, Value>,
+}
+
+impl Claims {
+    /// Claims for a token valid from now for `ttl_secs` seconds.
+    pub fn new(ttl_secs: i64) -> Self {
+        let now = Utc::now().timestamp();
+        Self {
+            iat: now,
+            exp: now + ttl_secs,
+            sub: None,
+            profile: None,
+            extra: serde_json::Map::new(),
+        }
+    }
+
+    /// The `tenant` claim: which Deepgram tenant the session is billed to.
+    pub fn tenant(&self) -> Option<&str> {
+        self.extra.get("tenant").and_then(Value::as_str)
+    }
+}
+
+/// Creates a signed JWT carrying the given claims.
+pub fn issue_token(secret: &[u8], claims: &Claims) -> Result<String, TokenError> {
+    encode(
+        &Header::new(Algorithm::HS256),
+        claims,
+        &EncodingKey::from_secret(secret),
+    )
+}
+
+/// Verifies a JWT token string and returns its claims.
+pub fn validate_token(token_str: &str, secret: &[u8]) -> Result<Claims, TokenError> {
+    let mut validation = Validation::new(Algorithm::HS256);
+    validation.required_spec_claims = std::collections::HashSet::new();
+    validation.validate_exp = true;
+    let data = decode::<Claims>(token_str, &DecodingKey::from_secret(secret), &validation)?;
+    Ok(data.claims)
+}
+
+/// Extracts and validates a JWT from the `access_token.<jwt>` subprotocol.
+/// Returns the full subprotocol string and the token claims if valid, None if invalid.
+pub fn validate_ws_token(protocols: &[String], secret: &[u8]) -> Option<(String, Claims)> {
+    for proto in protocols {
+        if let Some(token_str) = proto.strip_prefix("access_token.")
+            && let Ok(claims) = validate_token(token_str, secret)
+        {
+            return Some((proto.clone(), claims));
+        }
+    }
+    None
+}
+
+/// Compare secrets without leaking the position of the first mismatch.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
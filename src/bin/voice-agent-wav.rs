@@ -0,0 +1,439 @@
+// Headless voice agent client driven by WAV files.
+//
+//   voice-agent-wav turn1.wav turn2.wav --proxy http://localhost:8081 --output agent.wav
+//   voice-agent-wav turn1.wav --direct --prompt "You are a terse assistant."
+//
+// Each WAV file is one user turn, streamed in real time as the microphone.
+// Silence is sent between turns so the agent's end-of-speech detection
+// behaves as it would live. By default the next turn starts once the agent
+// has finished speaking (plus --pause); with --barge-in-ms it starts that
+// long after the agent begins speaking, interrupting it. Received TTS audio
+// is written to --output and the ConversationText transcript is printed.
+
+use bytes::Bytes;
+use clap::Parser;
+use futures_util::StreamExt;
+use rust_voice_agent::client::{AgentClient, AgentEvent, AgentSender};
+use rust_voice_agent::config::{AppConfig, merge_json};
+use rust_voice_agent::protocol::{AgentMessage, Role};
+use serde_json::{Value, json};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// Length of each audio chunk sent to the agent.
+const CHUNK: Duration = Duration::from_millis(20);
+
+#[derive(Parser)]
+#[command(
+    name = "voice-agent-wav",
+    version,
+    about = "Drive a voice agent session with WAV files as the microphone"
+)]
+struct Args {
+    /// WAV files played as consecutive user turns (16-bit PCM; stereo is downmixed).
+    #[arg(required = true)]
+    inputs: Vec<PathBuf>,
+    /// Proxy base URL, including any base path.
+    #[arg(
+        long,
+        default_value = "http://localhost:8081",
+        conflicts_with = "direct"
+    )]
+    proxy: String,
+    /// Session token to use instead of fetching one from /api/session.
+    #[arg(long, value_name = "JWT")]
+    token: Option<String>,
+    /// Connect straight to Deepgram with the configured API key.
+    #[arg(long)]
+    direct: bool,
+    /// Config file for --direct (overrides CONFIG_FILE).
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// Tenant whose API key and agent URL --direct uses (defaults to `[deepgram]`).
+    #[arg(long, requires = "direct")]
+    tenant: Option<String>,
+    /// JSON file merged over the default Settings body.
+    #[arg(long, value_name = "FILE")]
+    settings: Option<PathBuf>,
+    /// System prompt for the agent.
+    #[arg(long)]
+    prompt: Option<String>,
+    /// Write the agent's audio to this WAV file.
+    #[arg(long, short, value_name = "FILE")]
+    output: Option<PathBuf>,
+    /// Sample rate requested for the agent's audio.
+    #[arg(long, value_name = "HZ", default_value_t = 24000)]
+    output_rate: u32,
+    /// Silence before the first turn.
+    #[arg(long, value_name = "SECS", default_value_t = 1.0)]
+    lead_in: f64,
+    /// Silence after the agent finishes speaking before the next turn.
+    #[arg(long, value_name = "SECS", default_value_t = 1.0)]
+    pause: f64,
+    /// Start the next turn this long after the agent starts speaking (barge-in).
+    #[arg(long, value_name = "MS")]
+    barge_in_ms: Option<u64>,
+    /// Give up waiting for the agent to respond to a turn after this long.
+    #[arg(long, value_name = "SECS", default_value_t = 30.0)]
+    response_timeout: f64,
+}
+
+/// What the simulated microphone is doing.
+enum Phase {
+    /// Sending silence until `until`, then playing turn `next` (or
+    /// finishing, after the last turn).
+    Silence { until: Instant, next: usize },
+    /// Playing turn `turn` from sample `pos`.
+    Playing { turn: usize, pos: usize },
+    /// Turn finished; waiting for the agent to respond before turn `next`.
+    Waiting { next: usize, deadline: Instant },
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args).await {
+        eprintln!("ERROR: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(args: Args) -> Result<(), String> {
+    let (sample_rate, turns) = read_inputs(&args.inputs)?;
+    let settings = build_settings(&args, sample_rate)?;
+
+    let client = if args.direct {
+        let config = AppConfig::load_with_secrets(args.config.clone())
+            .await
+            .map_err(|e| e.to_string())?;
+        let tenant = config
+            .reloadable
+            .tenants
+            .get(args.tenant.as_deref())
+            .ok_or_else(|| match &args.tenant {
+                Some(id) => format!("tenant '{}' is not defined in the configuration", id),
+                None => "deepgram.api_key is not set; pass --tenant".to_string(),
+            })?;
+        AgentClient::direct(&tenant.agent_url, tenant.api_key.expose())
+    } else {
+        let client = AgentClient::proxy(&args.proxy);
+        match &args.token {
+            Some(token) => client.map(|c| c.with_token(token.clone())),
+            None => client,
+        }
+    }
+    .map_err(|e| e.to_string())?;
+
+    let mut output = match &args.output {
+        Some(path) => Some(
+            hound::WavWriter::create(
+                path,
+                hound::WavSpec {
+                    channels: 1,
+                    sample_rate: args.output_rate,
+                    bits_per_sample: 16,
+                    sample_format: hound::SampleFormat::Int,
+                },
+            )
+            .map_err(|e| format!("cannot create {}: {}", path.display(), e))?,
+        ),
+        None => None,
+    };
+    let mut carry: Option<u8> = None;
+
+    let mut session = client
+        .session(settings)
+        .connect()
+        .await
+        .map_err(|e| e.to_string())?;
+    let sender = session.sender();
+
+    let started = Instant::now();
+    let chunk_samples = (sample_rate as usize * CHUNK.as_millis() as usize) / 1000;
+    let mut script = Script {
+        inputs: &args.inputs,
+        turns: &turns,
+        chunk_samples,
+        silence: vec![0i16; chunk_samples],
+        started,
+        phase: Phase::Silence {
+            until: Instant::now() + Duration::from_secs_f64(args.lead_in),
+            next: 0,
+        },
+        pause: Duration::from_secs_f64(args.pause),
+        barge_in: args.barge_in_ms.map(Duration::from_millis),
+        response_timeout: Duration::from_secs_f64(args.response_timeout),
+        output_rate: args.output_rate,
+        reply_started: None,
+        reply_samples: 0,
+    };
+    let mut ticker = tokio::time::interval(CHUNK);
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                let Some(chunk) = script.next_chunk(Instant::now()) else { break };
+                send_pcm(&sender, chunk).await?;
+            }
+            event = session.next() => {
+                let Some(event) = event else { break };
+                match event {
+                    AgentEvent::Message(msg) => script.on_message(&msg),
+                    AgentEvent::Audio(data) => {
+                        script.reply_samples += data.len() as u64 / 2;
+                        if let Some(writer) = output.as_mut() {
+                            write_pcm(writer, &mut carry, &data)?;
+                        }
+                    }
+                    AgentEvent::Text(_) => {}
+                    AgentEvent::Closed { code, reason } => {
+                        println!("[{:6.2}s] session closed: {} {}", secs(started), code, reason);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    let _ = session.close().await;
+    if let Some(writer) = output {
+        writer
+            .finalize()
+            .map_err(|e| format!("failed to write output: {}", e))?;
+    }
+    Ok(())
+}
+
+/// Turn-taking state for the simulated user.
+struct Script<'a> {
+    inputs: &'a [PathBuf],
+    turns: &'a [Vec<i16>],
+    chunk_samples: usize,
+    silence: Vec<i16>,
+    started: Instant,
+    phase: Phase,
+    pause: Duration,
+    barge_in: Option<Duration>,
+    response_timeout: Duration,
+    output_rate: u32,
+    /// When the agent's current reply started and how much audio it has
+    /// sent, used to estimate when playback of the reply would end.
+    reply_started: Option<Instant>,
+    reply_samples: u64,
+}
+
+impl Script<'_> {
+    /// The next 20ms of microphone audio, or `None` once every turn has
+    /// been played and answered.
+    fn next_chunk(&mut self, now: Instant) -> Option<&[i16]> {
+        match self.phase {
+            Phase::Silence { until, next } if now >= until && next >= self.turns.len() => None,
+            Phase::Silence { until, next } if now >= until => {
+                println!(
+                    "[{:6.2}s] >> playing {}",
+                    secs(self.started),
+                    self.inputs[next].display()
+                );
+                self.phase = Phase::Playing { turn: next, pos: 0 };
+                Some(&self.silence)
+            }
+            Phase::Playing { turn, pos } => {
+                let samples = &self.turns[turn];
+                let end = (pos + self.chunk_samples).min(samples.len());
+                self.phase = if end == samples.len() {
+                    Phase::Waiting {
+                        next: turn + 1,
+                        deadline: now + self.response_timeout,
+                    }
+                } else {
+                    Phase::Playing { turn, pos: end }
+                };
+                Some(&samples[pos..end])
+            }
+            Phase::Waiting { next, deadline } if now >= deadline => {
+                eprintln!(
+                    "[{:6.2}s] no response from the agent, continuing",
+                    secs(self.started)
+                );
+                self.phase = Phase::Silence { until: now, next };
+                Some(&self.silence)
+            }
+            _ => Some(&self.silence),
+        }
+    }
+
+    /// Print transcript lines and advance turn-taking on agent events.
+    fn on_message(&mut self, msg: &AgentMessage) {
+        let t = secs(self.started);
+        match msg {
+            AgentMessage::ConversationText { role, content } => {
+                let who = match role {
+                    Role::User => "user",
+                    Role::Assistant => "agent",
+                    _ => "other",
+                };
+                println!("[{:6.2}s] {}: {}", t, who, content);
+            }
+            AgentMessage::UserStartedSpeaking => {
+                println!("[{:6.2}s] -- user started speaking", t);
+            }
+            AgentMessage::AgentStartedSpeaking { total_latency, .. } => {
+                println!(
+                    "[{:6.2}s] -- agent started speaking (latency {:.2}s)",
+                    t, total_latency
+                );
+                self.reply_started = Some(Instant::now());
+                self.reply_samples = 0;
+                // Barge in with the next turn, if there is one
+                if let (Some(delay), Phase::Waiting { next, .. }) = (self.barge_in, &self.phase)
+                    && *next < self.turns.len()
+                {
+                    self.phase = Phase::Silence {
+                        until: Instant::now() + delay,
+                        next: *next,
+                    };
+                }
+            }
+            AgentMessage::AgentAudioDone => {
+                println!("[{:6.2}s] -- agent audio done", t);
+                if let Phase::Waiting { next, .. } = self.phase {
+                    // Wait for the reply to finish "playing", then pause
+                    let playback = Duration::from_secs_f64(
+                        self.reply_samples as f64 / f64::from(self.output_rate),
+                    );
+                    let ends = self
+                        .reply_started
+                        .map_or(Instant::now(), |s| s + playback)
+                        .max(Instant::now());
+                    self.phase = Phase::Silence {
+                        until: ends + self.pause,
+                        next,
+                    };
+                }
+            }
+            AgentMessage::Error { description, code } => {
+                eprintln!("[{:6.2}s] agent error {}: {}", t, code, description);
+            }
+            AgentMessage::Warning { description, code } => {
+                eprintln!("[{:6.2}s] agent warning {}: {}", t, code, description);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn secs(started: Instant) -> f64 {
+    started.elapsed().as_secs_f64()
+}
+
+/// Read every input as mono 16-bit samples; all files must share a sample rate.
+fn read_inputs(paths: &[PathBuf]) -> Result<(u32, Vec<Vec<i16>>), String> {
+    let mut rate = None;
+    let mut turns = Vec::new();
+    for path in paths {
+        let mut reader =
+            hound::WavReader::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let spec = reader.spec();
+        if spec.sample_format != hound::SampleFormat::Int || spec.bits_per_sample != 16 {
+            return Err(format!(
+                "{}: only 16-bit PCM WAV is supported",
+                path.display()
+            ));
+        }
+        match rate {
+            None => rate = Some(spec.sample_rate),
+            Some(r) if r != spec.sample_rate => {
+                return Err(format!(
+                    "{}: sample rate {} differs from {} in the first file",
+                    path.display(),
+                    spec.sample_rate,
+                    r
+                ));
+            }
+            Some(_) => {}
+        }
+        let samples: Vec<i16> = reader
+            .samples::<i16>()
+            .collect::<Result<_, _>>()
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        // Downmix to mono
+        let channels = usize::from(spec.channels.max(1));
+        let mono = samples
+            .chunks(channels)
+            .map(|frame| {
+                let sum: i32 = frame.iter().map(|&s| i32::from(s)).sum();
+                (sum / frame.len() as i32) as i16
+            })
+            .collect();
+        turns.push(mono);
+    }
+    Ok((rate.unwrap_or(16000), turns))
+}
+
+/// Default Settings body, overlaid with --settings and --prompt. The input
+/// audio format always follows the WAV files.
+fn build_settings(args: &Args, sample_rate: u32) -> Result<Value, String> {
+    let mut settings = json!({
+        "audio": {
+            "output": {
+                "encoding": "linear16",
+                "sample_rate": args.output_rate,
+                "container": "none"
+            }
+        },
+        "agent": {
+            "listen": { "provider": { "type": "deepgram", "model": "nova-3" } },
+            "think": {
+                "provider": { "type": "open_ai", "model": "gpt-4o-mini" },
+                "prompt": "You are a helpful voice assistant."
+            },
+            "speak": { "provider": { "type": "deepgram", "model": "aura-2-thalia-en" } }
+        }
+    });
+    if let Some(path) = &args.settings {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        let overlay: Value = serde_json::from_str(&text)
+            .map_err(|e| format!("{}: invalid JSON: {}", path.display(), e))?;
+        merge_json(&mut settings, &overlay);
+    }
+    if let Some(prompt) = &args.prompt {
+        merge_json(
+            &mut settings,
+            &json!({ "agent": { "think": { "prompt": prompt } } }),
+        );
+    }
+    merge_json(
+        &mut settings,
+        &json!({ "audio": { "input": { "encoding": "linear16", "sample_rate": sample_rate } } }),
+    );
+    Ok(settings)
+}
+
+async fn send_pcm(sender: &AgentSender, samples: &[i16]) -> Result<(), String> {
+    let bytes: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+    sender
+        .send_audio(Bytes::from(bytes))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Append little-endian PCM to the output, keeping an odd trailing byte for
+/// the next chunk.
+fn write_pcm<W: std::io::Write + std::io::Seek>(
+    writer: &mut hound::WavWriter<W>,
+    carry: &mut Option<u8>,
+    data: &[u8],
+) -> Result<(), String> {
+    let mut bytes = Vec::with_capacity(data.len() + 1);
+    bytes.extend(carry.take());
+    bytes.extend_from_slice(data);
+    let mut pairs = bytes.chunks_exact(2);
+    for pair in &mut pairs {
+        writer
+            .write_sample(i16::from_le_bytes([pair[0], pair[1]]))
+            .map_err(|e| format!("failed to write output: {}", e))?;
+    }
+    *carry = pairs.remainder().first().copied();
+    Ok(())
+}
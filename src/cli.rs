@@ -0,0 +1,480 @@
+// Command-line interface.
+//
+//   rust-voice-agent [serve] [--host H] [--port P] [--config FILE]
+//   rust-voice-agent token issue [--sub S] [--profile P] [--ttl SECS] [--claim K=V ...]
+//   rust-voice-agent token verify <TOKEN>
+//   rust-voice-agent config check [--config FILE]
+//   rust-voice-agent replay <RECORDING> [--url URL] [--token JWT] [--speed X]
+//   rust-voice-agent replay <RECORDING> --side agent [--listen ADDR] [--speed X]
+//   rust-voice-agent webhook listen [--listen ADDR] [--secret S] [--fail-first N]
+//
+// Running without a subcommand is the same as `serve`.
+
+use clap::{Args, Parser, Subcommand};
+use rust_voice_agent::config::{AppConfig, ConfigError, SigningConfig};
+#[cfg(feature = "webhooks")]
+use rust_voice_agent::webhooks;
+use rust_voice_agent::{Claims, issue_token, validate_token};
+use serde_json::Value;
+use std::path::PathBuf;
+#[cfg(feature = "recording")]
+use {
+    clap::ValueEnum,
+    rust_voice_agent::recording,
+    rust_voice_agent::replay::{self, AgentReplayOptions, ReplayAuth, ReplayOptions},
+    std::time::Duration,
+};
+
+#[derive(Parser)]
+#[command(
+    name = "rust-voice-agent",
+    version,
+    about = "Deepgram Voice Agent proxy server"
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Validate configuration and exit (same as `config check`).
+    #[arg(long, global = true, hide = true)]
+    pub check_config: bool,
+
+    #[command(flatten)]
+    pub config: ConfigArg,
+}
+
+impl Cli {
+    /// The config file to use: `--config` given after the subcommand, else
+    /// before it.
+    pub fn config_arg(&self) -> ConfigArg {
+        let command = match &self.command {
+            Some(Command::Serve(args)) => Some(&args.config),
+            Some(Command::Token(TokenCommand::Issue { config, .. }))
+            | Some(Command::Token(TokenCommand::Verify { config, .. }))
+            | Some(Command::Config(ConfigCommand::Check { config })) => Some(config),
+            #[cfg(feature = "recording")]
+            Some(Command::Replay(args)) => Some(&args.config),
+            _ => None,
+        };
+        command
+            .filter(|config| config.path.is_some())
+            .unwrap_or(&self.config)
+            .clone()
+    }
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the proxy server (default).
+    Serve(ServeArgs),
+    /// Issue or inspect session tokens without a running server.
+    #[command(subcommand)]
+    Token(TokenCommand),
+    /// Configuration utilities.
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Replay one side of a recorded session.
+    #[cfg(feature = "recording")]
+    Replay(ReplayArgs),
+    /// Webhook development tools.
+    #[cfg(feature = "webhooks")]
+    #[command(subcommand)]
+    Webhook(WebhookCommand),
+}
+
+#[derive(Args, Default)]
+pub struct ServeArgs {
+    /// Address to bind (overrides HOST and server.host).
+    #[arg(long)]
+    pub host: Option<String>,
+    /// Port to bind (overrides PORT and server.port).
+    #[arg(long)]
+    pub port: Option<u16>,
+    #[command(flatten)]
+    pub config: ConfigArg,
+}
+
+#[derive(Args, Default, Clone)]
+pub struct ConfigArg {
+    /// Config file (overrides CONFIG_FILE).
+    #[arg(long = "config", value_name = "FILE")]
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+pub enum TokenCommand {
+    /// Mint a session token signed with the configured session secret.
+    Issue {
+        /// Subject (`sub` claim).
+        #[arg(long)]
+        sub: Option<String>,
+        /// Settings profile (`profile` claim).
+        #[arg(long)]
+        profile: Option<String>,
+        /// Tenant whose Deepgram API key the session uses (`tenant` claim).
+        #[arg(long)]
+        tenant: Option<String>,
+        /// Token lifetime in seconds (defaults to limits.token_ttl_secs).
+        #[arg(long, value_name = "SECS")]
+        ttl: Option<i64>,
+        /// Extra claim as KEY=VALUE; VALUE is parsed as JSON when possible.
+        #[arg(long = "claim", value_name = "KEY=VALUE")]
+        claims: Vec<String>,
+        #[command(flatten)]
+        config: ConfigArg,
+    },
+    /// Decode and validate a session token, printing its claims.
+    Verify {
+        token: String,
+        #[command(flatten)]
+        config: ConfigArg,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Validate configuration, print a summary and exit.
+    Check {
+        #[command(flatten)]
+        config: ConfigArg,
+    },
+}
+
+#[cfg(feature = "recording")]
+#[derive(Args)]
+pub struct ReplayArgs {
+    /// Recording file to replay.
+    pub recording: PathBuf,
+    /// Which side of the recording to play.
+    #[arg(long, value_enum, default_value_t = ReplaySide::Client)]
+    pub side: ReplaySide,
+    /// Address to accept the client on when playing the agent side; point
+    /// the proxy's DEEPGRAM_AGENT_URL at it.
+    #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:9100")]
+    pub listen: String,
+    /// Agent endpoint to connect to (defaults to the tenant's agent URL).
+    #[arg(long)]
+    pub url: Option<String>,
+    /// Authenticate with a proxy session token instead of the Deepgram API key.
+    #[arg(long, value_name = "JWT")]
+    pub token: Option<String>,
+    /// Tenant whose API key and agent URL to use (defaults to `[deepgram]`).
+    #[arg(long)]
+    pub tenant: Option<String>,
+    /// Playback speed multiplier; 0 sends frames without delay.
+    #[arg(long, default_value_t = 1.0)]
+    pub speed: f64,
+    /// Seconds to keep reading the other side after the last frame is sent.
+    #[arg(long, value_name = "SECS", default_value_t = 5)]
+    pub linger: u64,
+    /// Write the replayed session to a new recording.
+    #[arg(long, value_name = "FILE")]
+    pub output: Option<PathBuf>,
+    #[command(flatten)]
+    pub config: ConfigArg,
+}
+
+#[cfg(feature = "webhooks")]
+#[derive(Subcommand)]
+pub enum WebhookCommand {
+    /// Run a local receiver that prints deliveries and checks their signatures.
+    Listen {
+        /// Address to accept deliveries on.
+        #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:9200")]
+        listen: String,
+        /// Endpoint secret to verify `X-Webhook-Signature` against.
+        #[arg(long)]
+        secret: Option<String>,
+        /// Answer the first N deliveries with 503 to exercise retries.
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        fail_first: usize,
+    },
+}
+
+#[cfg(feature = "recording")]
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ReplaySide {
+    /// Send the client frames to an agent endpoint.
+    Client,
+    /// Serve the Deepgram frames to a connecting client.
+    Agent,
+}
+
+// ============================================================================
+// COMMANDS
+// ============================================================================
+
+/// Load configuration, including provider secrets, or exit with every
+/// validation error.
+pub async fn load_config(arg: &ConfigArg) -> AppConfig {
+    AppConfig::load_with_secrets(arg.path.clone())
+        .await
+        .unwrap_or_else(|e: ConfigError| {
+            eprint!("ERROR: {}", e);
+            std::process::exit(1);
+        })
+}
+
+/// `config check`
+pub async fn config_check(arg: &ConfigArg) {
+    let config = load_config(arg).await;
+    print!("{}", config.summary());
+    println!("Configuration OK");
+}
+
+/// `token issue` / `token verify`
+pub async fn token(command: TokenCommand) {
+    match command {
+        TokenCommand::Issue {
+            sub,
+            profile,
+            tenant,
+            ttl,
+            claims,
+            config,
+        } => {
+            let signing = load_signing_config(&config).await;
+            // Tenants and profiles live in the full configuration; only
+            // load it when the token names one
+            if tenant.is_some() || profile.is_some() {
+                let config = load_config(&config).await;
+                let resolved = config.reloadable.tenants.get(tenant.as_deref());
+                if let Some(id) = &tenant
+                    && resolved.is_none()
+                {
+                    fail(&format!(
+                        "tenant '{}' is not defined in the configuration",
+                        id
+                    ));
+                }
+                if let Some(name) = &profile {
+                    let found = match resolved {
+                        Some(resolved) => config.tenant_profile(resolved, name),
+                        None => config.profile(name),
+                    };
+                    if found.is_none() {
+                        fail(&format!(
+                            "profile '{}' is not defined in the configuration",
+                            name
+                        ));
+                    }
+                }
+            }
+            let ttl = ttl.unwrap_or(signing.token_ttl_secs);
+            if ttl <= 0 {
+                fail("--ttl must be greater than zero");
+            }
+
+            let mut token_claims = Claims::new(ttl);
+            token_claims.sub = sub;
+            token_claims.profile = profile;
+            if let Some(tenant) = tenant {
+                token_claims
+                    .extra
+                    .insert("tenant".to_string(), Value::String(tenant));
+            }
+            for pair in claims {
+                let Some((key, value)) = pair.split_once('=') else {
+                    fail(&format!("--claim '{}' must be KEY=VALUE", pair));
+                };
+                if ["iat", "exp", "sub", "profile", "tenant"].contains(&key) {
+                    fail(&format!("--claim cannot set reserved claim '{}'", key));
+                }
+                let value = serde_json::from_str(value)
+                    .unwrap_or_else(|_| Value::String(value.to_string()));
+                token_claims.extra.insert(key.to_string(), value);
+            }
+
+            match issue_token(&signing.session_secret, &token_claims) {
+                Ok(token) => println!("{}", token),
+                Err(e) => fail(&format!("failed to sign token: {}", e)),
+            }
+        }
+        TokenCommand::Verify { token, config } => {
+            let signing = load_signing_config(&config).await;
+            match validate_token(token.trim(), &signing.session_secret) {
+                Ok(claims) => {
+                    let pretty = serde_json::to_string_pretty(&claims).unwrap_or_default();
+                    println!("{}", pretty);
+                    eprintln!(
+                        "Token is valid, expires {}",
+                        chrono::DateTime::from_timestamp(claims.exp, 0)
+                            .map(|t| t.to_rfc3339())
+                            .unwrap_or_else(|| claims.exp.to_string())
+                    );
+                }
+                Err(e) => fail(&format!("token is invalid: {}", e)),
+            }
+        }
+    }
+}
+
+/// `replay`
+#[cfg(feature = "recording")]
+pub async fn replay(args: ReplayArgs) {
+    let records = recording::read_file(&args.recording).unwrap_or_else(|e| {
+        fail(&format!("cannot read {}: {}", args.recording.display(), e));
+    });
+    if args.speed < 0.0 {
+        fail("--speed must not be negative");
+    }
+
+    let result = match args.side {
+        ReplaySide::Client => replay_client(&records, args).await,
+        ReplaySide::Agent => replay_agent(&records, args).await,
+    };
+    match result {
+        Ok(summary) => {
+            println!(
+                "Replay finished: {} frames sent, {} text messages and {} audio bytes received",
+                summary.frames_sent, summary.text_received, summary.binary_bytes_received
+            );
+            if let Some((code, reason)) = summary.close {
+                println!("Remote closed the session: {} {}", code, reason);
+            }
+        }
+        Err(e) => fail(&format!("replay failed: {}", e)),
+    }
+}
+
+#[cfg(feature = "recording")]
+async fn replay_client(
+    records: &[recording::Record],
+    args: ReplayArgs,
+) -> Result<replay::ReplaySummary, String> {
+    let config = load_config(&args.config).await;
+    let Some(tenant) = config.reloadable.tenants.get(args.tenant.as_deref()) else {
+        return Err(match &args.tenant {
+            Some(id) => format!("tenant '{}' is not defined in the configuration", id),
+            None => "deepgram.api_key is not set; pass --tenant".to_string(),
+        });
+    };
+    let opts = ReplayOptions {
+        url: args.url.unwrap_or_else(|| tenant.agent_url.clone()),
+        auth: match args.token {
+            Some(token) => ReplayAuth::SessionToken(token),
+            None => ReplayAuth::ApiKey(tenant.api_key.expose().to_string()),
+        },
+        speed: args.speed,
+        linger: Duration::from_secs(args.linger),
+        output: args.output,
+    };
+
+    println!(
+        "Replaying {} ({} frames) against {}",
+        args.recording.display(),
+        records.len(),
+        opts.url
+    );
+    replay::replay_client_side(records, opts, print_received).await
+}
+
+#[cfg(feature = "recording")]
+async fn replay_agent(
+    records: &[recording::Record],
+    args: ReplayArgs,
+) -> Result<replay::ReplaySummary, String> {
+    let listener = tokio::net::TcpListener::bind(&args.listen)
+        .await
+        .unwrap_or_else(|e| fail(&format!("cannot listen on {}: {}", args.listen, e)));
+    println!(
+        "Serving the agent side of {} ({} frames) on ws://{}, waiting for a client",
+        args.recording.display(),
+        records.len(),
+        args.listen
+    );
+    let opts = AgentReplayOptions {
+        speed: args.speed,
+        linger: Duration::from_secs(args.linger),
+        output: args.output,
+    };
+    replay::replay_agent_side(records, listener, opts, print_received).await
+}
+
+/// Print a text message the other end of a replay sent.
+#[cfg(feature = "recording")]
+fn print_received(text: &str) {
+    println!("<- {}", text);
+}
+
+/// `webhook listen`
+#[cfg(feature = "webhooks")]
+pub async fn webhook(command: WebhookCommand) {
+    use axum::http::{HeaderMap, StatusCode};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let WebhookCommand::Listen {
+        listen,
+        secret,
+        fail_first,
+    } = command;
+    let received = Arc::new(AtomicUsize::new(0));
+    let app = axum::Router::new().fallback(move |headers: HeaderMap, body: axum::body::Bytes| {
+        let secret = secret.clone();
+        let received = received.clone();
+        async move {
+            let header = |name: &str| {
+                headers
+                    .get(name)
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or_default()
+                    .to_string()
+            };
+            let signature = match &secret {
+                Some(secret) => {
+                    let valid = webhooks::verify_signature(
+                        secret.as_bytes(),
+                        &header("x-webhook-timestamp"),
+                        &body,
+                        &header("x-webhook-signature"),
+                    );
+                    if valid { "valid" } else { "INVALID" }
+                }
+                None => "not checked",
+            };
+            let event: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+            println!(
+                "{} {} (signature {})",
+                event["type"].as_str().unwrap_or("?"),
+                header("x-webhook-id"),
+                signature
+            );
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&event).unwrap_or_default()
+            );
+
+            let n = received.fetch_add(1, Ordering::SeqCst);
+            if n < fail_first {
+                println!("-> 503 (failing {} of {})", n + 1, fail_first);
+                StatusCode::SERVICE_UNAVAILABLE
+            } else {
+                StatusCode::NO_CONTENT
+            }
+        }
+    });
+
+    let listener = tokio::net::TcpListener::bind(&listen)
+        .await
+        .unwrap_or_else(|e| fail(&format!("cannot listen on {}: {}", listen, e)));
+    println!("Receiving webhooks on http://{}", listen);
+    if let Err(e) = axum::serve(listener, app).await {
+        fail(&format!("server error: {}", e));
+    }
+}
+
+/// Only the session secret is needed to sign or check tokens.
+async fn load_signing_config(arg: &ConfigArg) -> SigningConfig {
+    SigningConfig::load(arg.path.clone())
+        .await
+        .unwrap_or_else(|e: ConfigError| {
+            eprint!("ERROR: {}", e);
+            std::process::exit(1);
+        })
+}
+
+fn fail(msg: &str) -> ! {
+    eprintln!("ERROR: {}", msg);
+    std::process::exit(1);
+}
@@ -0,0 +1,448 @@
+// Client SDK for the voice agent endpoint.
+//
+// Talks to an agent through this proxy (fetching a session token from
+// /api/session and connecting to /api/voice-agent with the
+// `access_token.<jwt>` subprotocol) or directly to a Deepgram-compatible
+// agent URL with an API key:
+//
+//   let client = AgentClient::proxy("http://localhost:8081")?;
+//   let mut session = client
+//       .session(settings)
+//       .on_function("get_weather", |call| async move { Ok(lookup(&call.arguments)) })
+//       .connect()
+//       .await?;
+//   tokio::spawn(session.sender().stream_audio(microphone));
+//   while let Some(event) = session.next().await { ... }
+//
+// Client-side function calls with a registered handler are answered
+// automatically; the request is still yielded as an event.
+
+use crate::protocol::{AgentMessage, ClientMessage, FunctionCall};
+use bytes::Bytes;
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, Stream, StreamExt};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::net::TcpStream;
+use tokio::sync::{Mutex, mpsc};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, connect_async, tungstenite};
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+type FunctionHandler = Arc<
+    dyn Fn(FunctionCall) -> Pin<Box<dyn Future<Output = Result<String, String>> + Send>>
+        + Send
+        + Sync,
+>;
+
+/// Events buffered between the socket reader and the consumer.
+const EVENT_BUFFER: usize = 256;
+
+/// Errors from the client SDK.
+#[derive(Debug)]
+pub enum ClientError {
+    /// The base or agent URL could not be used.
+    InvalidUrl(String),
+    /// Fetching a session token from the proxy failed.
+    Token(String),
+    /// The WebSocket connection failed or was closed.
+    WebSocket(Box<tungstenite::Error>),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::InvalidUrl(e) => write!(f, "invalid URL: {}", e),
+            ClientError::Token(e) => write!(f, "failed to get session token: {}", e),
+            ClientError::WebSocket(e) => write!(f, "websocket error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<tungstenite::Error> for ClientError {
+    fn from(e: tungstenite::Error) -> Self {
+        ClientError::WebSocket(Box::new(e))
+    }
+}
+
+/// Something received from the agent.
+#[derive(Debug, Clone)]
+pub enum AgentEvent {
+    /// A JSON message. Types this SDK does not know are `AgentMessage::Unknown`.
+    Message(AgentMessage),
+    /// Text that was not a recognizable agent message, passed through raw.
+    Text(String),
+    /// A chunk of TTS audio in the encoding requested in Settings.
+    Audio(Bytes),
+    /// The agent closed the session.
+    Closed { code: u16, reason: String },
+}
+
+// ============================================================================
+// CLIENT
+// ============================================================================
+
+enum Endpoint {
+    Proxy {
+        base: url::Url,
+        token: Option<String>,
+    },
+    Direct {
+        url: String,
+        api_key: String,
+    },
+}
+
+/// Connection settings for an agent endpoint.
+pub struct AgentClient {
+    endpoint: Endpoint,
+    http: reqwest::Client,
+}
+
+impl AgentClient {
+    /// Connect through this proxy at `base_url` (including any base path).
+    pub fn proxy(base_url: &str) -> Result<Self, ClientError> {
+        let mut base =
+            url::Url::parse(base_url).map_err(|e| ClientError::InvalidUrl(e.to_string()))?;
+        if !matches!(base.scheme(), "http" | "https") {
+            return Err(ClientError::InvalidUrl(format!(
+                "{} must use http:// or https://",
+                base_url
+            )));
+        }
+        // Treat the base as a directory so joins keep any base path
+        if !base.path().ends_with('/') {
+            base.set_path(&format!("{}/", base.path()));
+        }
+        Ok(Self {
+            endpoint: Endpoint::Proxy { base, token: None },
+            http: reqwest::Client::new(),
+        })
+    }
+
+    /// Connect straight to a Deepgram-compatible agent URL with an API key.
+    pub fn direct(agent_url: &str, api_key: &str) -> Result<Self, ClientError> {
+        let url = url::Url::parse(agent_url).map_err(|e| ClientError::InvalidUrl(e.to_string()))?;
+        if !matches!(url.scheme(), "ws" | "wss") {
+            return Err(ClientError::InvalidUrl(format!(
+                "{} must use ws:// or wss://",
+                agent_url
+            )));
+        }
+        Ok(Self {
+            endpoint: Endpoint::Direct {
+                url: agent_url.to_string(),
+                api_key: api_key.to_string(),
+            },
+            http: reqwest::Client::new(),
+        })
+    }
+
+    /// Use a pre-issued session token (e.g. from `token issue`) instead of
+    /// fetching one from /api/session. Ignored for direct connections.
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        if let Endpoint::Proxy { token: t, .. } = &mut self.endpoint {
+            *t = Some(token.into());
+        }
+        self
+    }
+
+    /// Fetch a session token from the proxy's /api/session.
+    pub async fn fetch_token(&self) -> Result<String, ClientError> {
+        let Endpoint::Proxy { base, .. } = &self.endpoint else {
+            return Err(ClientError::Token(
+                "direct connections do not use session tokens".to_string(),
+            ));
+        };
+        let url = base
+            .join("api/session")
+            .map_err(|e| ClientError::InvalidUrl(e.to_string()))?;
+        let response = self
+            .http
+            .get(url)
+            .send()
+            .await
+            .map_err(|e| ClientError::Token(e.to_string()))?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(ClientError::Token(format!(
+                "/api/session returned {}",
+                status
+            )));
+        }
+        let body: Value = response
+            .json()
+            .await
+            .map_err(|e| ClientError::Token(e.to_string()))?;
+        body.get("token")
+            .and_then(Value::as_str)
+            .map(str::to_string)
+            .ok_or_else(|| ClientError::Token("response has no token".to_string()))
+    }
+
+    /// Start configuring a session that sends `settings` (the body of the
+    /// Settings message, without `type`) once connected.
+    pub fn session(&self, settings: Value) -> SessionBuilder<'_> {
+        SessionBuilder {
+            client: self,
+            settings,
+            functions: HashMap::new(),
+        }
+    }
+
+    async fn open(&self) -> Result<Socket, ClientError> {
+        let (url, auth) = match &self.endpoint {
+            Endpoint::Proxy { base, token } => {
+                let token = match token {
+                    Some(t) => t.clone(),
+                    None => self.fetch_token().await?,
+                };
+                let mut url = base
+                    .join("api/voice-agent")
+                    .map_err(|e| ClientError::InvalidUrl(e.to_string()))?;
+                let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+                let _ = url.set_scheme(scheme);
+                (
+                    url.to_string(),
+                    ("Sec-WebSocket-Protocol", format!("access_token.{}", token)),
+                )
+            }
+            Endpoint::Direct { url, api_key } => {
+                (url.clone(), ("Authorization", format!("Token {}", api_key)))
+            }
+        };
+
+        let parsed = url::Url::parse(&url).map_err(|e| ClientError::InvalidUrl(e.to_string()))?;
+        let host = match parsed.port() {
+            Some(port) => format!("{}:{}", parsed.host_str().unwrap_or_default(), port),
+            None => parsed.host_str().unwrap_or_default().to_string(),
+        };
+        let request = tungstenite::http::Request::builder()
+            .uri(url.as_str())
+            .header("Host", host)
+            .header("Connection", "Upgrade")
+            .header("Upgrade", "websocket")
+            .header("Sec-WebSocket-Version", "13")
+            .header(
+                "Sec-WebSocket-Key",
+                tungstenite::handshake::client::generate_key(),
+            )
+            .header(auth.0, auth.1)
+            .body(())
+            .map_err(|e| ClientError::InvalidUrl(e.to_string()))?;
+
+        let (socket, _) = connect_async(request).await?;
+        Ok(socket)
+    }
+}
+
+// ============================================================================
+// SESSION
+// ============================================================================
+
+/// Configures function-call handlers before connecting.
+pub struct SessionBuilder<'a> {
+    client: &'a AgentClient,
+    settings: Value,
+    functions: HashMap<String, FunctionHandler>,
+}
+
+impl SessionBuilder<'_> {
+    /// Answer client-side calls to `name` with `handler`. `Ok` content is sent
+    /// as the FunctionCallResponse; `Err` is sent as `{"error": "..."}`.
+    pub fn on_function<F, Fut>(mut self, name: impl Into<String>, handler: F) -> Self
+    where
+        F: Fn(FunctionCall) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<String, String>> + Send + 'static,
+    {
+        self.functions
+            .insert(name.into(), Arc::new(move |call| Box::pin(handler(call))));
+        self
+    }
+
+    /// Connect, send Settings, and start receiving events.
+    pub async fn connect(self) -> Result<AgentSession, ClientError> {
+        let socket = self.client.open().await?;
+        let (sink, stream) = socket.split();
+        let sender = AgentSender {
+            sink: Arc::new(Mutex::new(sink)),
+        };
+
+        let mut settings = match self.settings {
+            Value::Object(map) => map,
+            _ => serde_json::Map::new(),
+        };
+        settings.remove("type");
+        sender.send(&ClientMessage::Settings { settings }).await?;
+
+        let (events_tx, events) = mpsc::channel(EVENT_BUFFER);
+        let reader = tokio::spawn(read_events(
+            stream,
+            events_tx,
+            sender.clone(),
+            Arc::new(self.functions),
+        ));
+        Ok(AgentSession {
+            sender,
+            events,
+            reader,
+        })
+    }
+}
+
+/// A live agent session. Yields `AgentEvent`s as a `Stream`; use `sender()`
+/// to send audio or messages concurrently.
+pub struct AgentSession {
+    sender: AgentSender,
+    events: mpsc::Receiver<AgentEvent>,
+    reader: tokio::task::JoinHandle<()>,
+}
+
+impl AgentSession {
+    /// Cloneable handle for sending to the agent.
+    pub fn sender(&self) -> AgentSender {
+        self.sender.clone()
+    }
+
+    /// Send a JSON message.
+    pub async fn send(&self, msg: &ClientMessage) -> Result<(), ClientError> {
+        self.sender.send(msg).await
+    }
+
+    /// Close the connection.
+    pub async fn close(self) -> Result<(), ClientError> {
+        let result = self.sender.close().await;
+        self.reader.abort();
+        result
+    }
+}
+
+impl Stream for AgentSession {
+    type Item = AgentEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<AgentEvent>> {
+        self.events.poll_recv(cx)
+    }
+}
+
+impl Drop for AgentSession {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// Sending half of a session.
+#[derive(Clone)]
+pub struct AgentSender {
+    sink: Arc<Mutex<SplitSink<Socket, tungstenite::Message>>>,
+}
+
+impl AgentSender {
+    /// Send a JSON message.
+    pub async fn send(&self, msg: &ClientMessage) -> Result<(), ClientError> {
+        self.send_raw(tungstenite::Message::Text(msg.to_json()))
+            .await
+    }
+
+    /// Send one chunk of microphone audio.
+    pub async fn send_audio(&self, chunk: Bytes) -> Result<(), ClientError> {
+        self.send_raw(tungstenite::Message::Binary(chunk.to_vec()))
+            .await
+    }
+
+    /// Send every chunk of `audio` as it arrives; returns when the stream ends.
+    /// Pace the stream in real time, as the agent expects live audio.
+    pub async fn stream_audio<S>(self, audio: S) -> Result<(), ClientError>
+    where
+        S: Stream<Item = Bytes> + Send,
+    {
+        let mut audio = std::pin::pin!(audio);
+        while let Some(chunk) = audio.next().await {
+            self.send_audio(chunk).await?;
+        }
+        Ok(())
+    }
+
+    /// Close the connection.
+    pub async fn close(&self) -> Result<(), ClientError> {
+        self.sink.lock().await.close().await?;
+        Ok(())
+    }
+
+    async fn send_raw(&self, msg: tungstenite::Message) -> Result<(), ClientError> {
+        self.sink.lock().await.send(msg).await?;
+        Ok(())
+    }
+}
+
+/// Turn socket frames into events, answering registered function calls.
+async fn read_events(
+    mut stream: SplitStream<Socket>,
+    events: mpsc::Sender<AgentEvent>,
+    sender: AgentSender,
+    functions: Arc<HashMap<String, FunctionHandler>>,
+) {
+    while let Some(msg) = stream.next().await {
+        let event = match msg {
+            Ok(tungstenite::Message::Text(text)) => match AgentMessage::parse(&text) {
+                Some(msg) => {
+                    if let AgentMessage::FunctionCallRequest { functions: calls } = &msg {
+                        for call in calls.iter().filter(|c| c.client_side) {
+                            if let Some(handler) = functions.get(&call.name) {
+                                tokio::spawn(answer_call(
+                                    handler.clone(),
+                                    call.clone(),
+                                    sender.clone(),
+                                ));
+                            }
+                        }
+                    }
+                    AgentEvent::Message(msg)
+                }
+                None => AgentEvent::Text(text),
+            },
+            Ok(tungstenite::Message::Binary(data)) => AgentEvent::Audio(Bytes::from(data)),
+            Ok(tungstenite::Message::Close(frame)) => {
+                let (code, reason) = frame
+                    .map(|f| (u16::from(f.code), f.reason.to_string()))
+                    .unwrap_or((1005, String::new()));
+                let _ = events.send(AgentEvent::Closed { code, reason }).await;
+                break;
+            }
+            Ok(_) => continue,
+            Err(e) => {
+                let _ = events
+                    .send(AgentEvent::Closed {
+                        code: 1006,
+                        reason: e.to_string(),
+                    })
+                    .await;
+                break;
+            }
+        };
+        if events.send(event).await.is_err() {
+            break;
+        }
+    }
+}
+
+async fn answer_call(handler: FunctionHandler, call: FunctionCall, sender: AgentSender) {
+    let content = match handler(call.clone()).await {
+        Ok(content) => content,
+        Err(e) => json!({ "error": e }).to_string(),
+    };
+    let response = ClientMessage::FunctionCallResponse {
+        id: call.id,
+        name: call.name,
+        content,
+    };
+    if let Err(e) = sender.send(&response).await {
+        tracing::warn!("Failed to send function call response: {}", e);
+    }
+}
@@ -0,0 +1,99 @@
+// Direct browser mode: short-lived Deepgram credentials.
+//
+// Instead of proxying, a client holding a session token can ask
+// `POST /api/direct-session` for a grant: a Deepgram access token valid for
+// `direct.ttl_secs`, minted with its tenant's API key through the Deepgram
+// auth API. The browser then opens the agent URL itself with the
+// `bearer, <access_token>` WebSocket subprotocols and sends the returned
+// profile Settings. The proxy never sees these sessions, so session limits,
+// recording, usage accounting, redaction and guardrails do not apply to them.
+//
+// Minting goes through `GrantIssuer` so tests and embedders can replace the
+// Deepgram call (`voice_agent_router_with_grants`). The built-in issuer
+// reaches Deepgram through the `[egress]` proxy like proxied sessions.
+
+use crate::config::{DirectConfig, EgressConfig, Tenant};
+use crate::egress;
+use crate::secrets::Secret;
+use serde::Deserialize;
+use serde_json::json;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+use tracing::error;
+
+/// A temporary Deepgram access token.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Grant {
+    pub access_token: Secret,
+    /// Seconds until the token expires.
+    pub expires_in: u64,
+}
+
+/// Future returned by `GrantIssuer::grant`.
+pub type GrantFuture<'a> = Pin<Box<dyn Future<Output = Result<Grant, String>> + Send + 'a>>;
+
+/// Mints temporary Deepgram credentials for a tenant, valid for
+/// `config.ttl_secs`.
+pub trait GrantIssuer: Send + Sync {
+    fn grant<'a>(&'a self, config: &'a DirectConfig, tenant: &'a Tenant) -> GrantFuture<'a>;
+}
+
+/// Grants from the Deepgram auth API at `direct.grant_url`, authenticated
+/// with the tenant's key.
+#[derive(Default)]
+pub struct DeepgramGrants {
+    client: reqwest::Client,
+}
+
+impl DeepgramGrants {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Grants requested through the proxy and with the CAs of `egress`.
+    pub fn with_egress(egress: &EgressConfig) -> Self {
+        match egress::http_client(egress) {
+            Ok(client) => Self { client },
+            Err(e) => {
+                error!("Deepgram grants will not use the egress settings: {}", e);
+                Self::default()
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct GrantResponse {
+    access_token: String,
+    #[serde(default)]
+    expires_in: Option<u64>,
+}
+
+impl GrantIssuer for DeepgramGrants {
+    fn grant<'a>(&'a self, config: &'a DirectConfig, tenant: &'a Tenant) -> GrantFuture<'a> {
+        Box::pin(async move {
+            let response = self
+                .client
+                .post(&config.grant_url)
+                .header(
+                    reqwest::header::AUTHORIZATION,
+                    format!("Token {}", tenant.api_key.expose()),
+                )
+                .timeout(Duration::from_secs(10))
+                .json(&json!({ "ttl_seconds": config.ttl_secs }))
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .map_err(|e| e.to_string())?;
+            let body: GrantResponse = response
+                .json()
+                .await
+                .map_err(|e| format!("invalid grant response: {}", e))?;
+            Ok(Grant {
+                access_token: Secret::new(body.access_token),
+                expires_in: body.expires_in.unwrap_or(config.ttl_secs),
+            })
+        })
+    }
+}
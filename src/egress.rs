@@ -0,0 +1,279 @@
+// Outbound connections to Deepgram, optionally through an egress proxy.
+//
+// Agent WebSocket connections are dialled here rather than by
+// `connect_async`, so they can go through `[egress] proxy`: an HTTP proxy
+// tunnelling with CONNECT (basic auth from the proxy URL), or a SOCKS5 proxy
+// (`socks` feature) with username/password auth. Either way the proxy
+// resolves host names. Hosts matching `no_proxy` are connected to directly.
+//
+// `ca_file` adds CA certificates to the system roots for TLS to Deepgram,
+// for TLS-inspecting proxies that re-sign its certificate. It is read for
+// every connection, so a replaced bundle applies from the next session.
+//
+// Deepgram grant requests (direct mode) use the same proxy and CAs through
+// `http_client`. The other outbound HTTP clients (webhooks, classifiers,
+// Vault) follow HTTPS_PROXY and NO_PROXY from the environment on their own.
+
+use crate::config::{EgressConfig, EgressProxy, ProxyKind};
+use base64::Engine;
+use std::net::IpAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_tungstenite::Connector;
+use zeroize::Zeroizing;
+
+/// Largest CONNECT response head accepted from an HTTP proxy.
+const MAX_CONNECT_RESPONSE: usize = 8192;
+
+// ============================================================================
+// CONNECTIONS
+// ============================================================================
+
+/// Open a TCP connection to the host of `url`, through the egress proxy
+/// unless the host is exempt.
+pub async fn connect(egress: &EgressConfig, url: &url::Url) -> Result<TcpStream, String> {
+    let host = url.host_str().ok_or("URL has no host")?;
+    let port = url.port_or_known_default().ok_or("URL has no port")?;
+    match &egress.proxy {
+        Some(proxy) if !bypasses(&egress.no_proxy, host, port) => match proxy.kind {
+            ProxyKind::Http => connect_http(proxy, &format!("{}:{}", host, port)).await,
+            ProxyKind::Socks5 => {
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                connect_socks5(proxy, host, port).await
+            }
+        },
+        _ => TcpStream::connect(format!("{}:{}", host, port))
+            .await
+            .map_err(|e| e.to_string()),
+    }
+}
+
+/// Whether `host` and `port` are exempt from the proxy by a `no_proxy`
+/// entry: `*`, an IP address or CIDR range containing it, or a domain it is
+/// or is under. Host names and addresses (IPv6 ones in brackets) followed by
+/// `:port` only exempt that port.
+pub fn bypasses(no_proxy: &[String], host: &str, port: u16) -> bool {
+    let host = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_ascii_lowercase();
+    let ip = host.parse::<IpAddr>().ok();
+    no_proxy.iter().any(|entry| {
+        let mut entry = entry.trim().to_ascii_lowercase();
+        if entry == "*" {
+            return true;
+        }
+        if let Some((name, entry_port)) = split_port(&entry) {
+            if entry_port != port {
+                return false;
+            }
+            entry = name;
+        }
+        let entry = entry.trim_start_matches('[').trim_end_matches(']');
+        if let Some(ip) = ip {
+            if let Ok(net) = entry.parse::<ipnet::IpNet>() {
+                return net.contains(&ip);
+            }
+            if let Ok(addr) = entry.parse::<IpAddr>() {
+                return addr == ip;
+            }
+        }
+        let domain = entry.trim_start_matches("*.").trim_start_matches('.');
+        host == domain
+            || host
+                .strip_suffix(domain)
+                .is_some_and(|prefix| prefix.ends_with('.'))
+    })
+}
+
+/// The host and port of a `no_proxy` entry that names a port.
+fn split_port(entry: &str) -> Option<(String, u16)> {
+    // A bare IPv6 address is all colons
+    if entry.parse::<IpAddr>().is_ok() || entry.parse::<ipnet::IpNet>().is_ok() {
+        return None;
+    }
+    let (host, port) = entry.rsplit_once(':')?;
+    Some((host.to_string(), port.parse().ok()?))
+}
+
+/// Tunnel to `authority` (`host:port`) through an HTTP proxy with CONNECT.
+async fn connect_http(proxy: &EgressProxy, authority: &str) -> Result<TcpStream, String> {
+    let mut stream = TcpStream::connect((proxy.host.as_str(), proxy.port))
+        .await
+        .map_err(|e| format!("cannot reach proxy {}: {}", proxy, e))?;
+
+    let mut request = Zeroizing::new(format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority));
+    if let Some((user, password)) = &proxy.credentials {
+        let credentials = Zeroizing::new(format!("{}:{}", user, password.expose()));
+        request.push_str(&format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            base64::engine::general_purpose::STANDARD.encode(credentials.as_bytes())
+        ));
+    }
+    request.push_str("\r\n");
+    stream
+        .write_all(request.as_bytes())
+        .await
+        .map_err(|e| format!("proxy {}: {}", proxy, e))?;
+
+    // Byte by byte, so nothing past the response head is consumed
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_CONNECT_RESPONSE {
+            return Err(format!("proxy {}: response head too large", proxy));
+        }
+        let byte = stream
+            .read_u8()
+            .await
+            .map_err(|e| format!("proxy {} closed the connection: {}", proxy, e))?;
+        head.push(byte);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let status_line = head.lines().next().unwrap_or_default();
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse::<u16>().ok());
+    match status {
+        Some(200..=299) => Ok(stream),
+        Some(407) => Err(format!("proxy {} requires authentication", proxy)),
+        _ => Err(format!(
+            "proxy {} refused CONNECT {}: {}",
+            proxy, authority, status_line
+        )),
+    }
+}
+
+/// Connect to `host:port` through a SOCKS5 proxy.
+#[cfg(feature = "socks")]
+async fn connect_socks5(proxy: &EgressProxy, host: &str, port: u16) -> Result<TcpStream, String> {
+    use tokio_socks::tcp::Socks5Stream;
+
+    let address = (proxy.host.as_str(), proxy.port);
+    let stream = match &proxy.credentials {
+        Some((user, password)) => {
+            Socks5Stream::connect_with_password(address, (host, port), user, password.expose())
+                .await
+        }
+        None => Socks5Stream::connect(address, (host, port)).await,
+    }
+    .map_err(|e| format!("SOCKS5 proxy {}: {}", proxy, e))?;
+    Ok(stream.into_inner())
+}
+
+#[cfg(not(feature = "socks"))]
+async fn connect_socks5(proxy: &EgressProxy, _host: &str, _port: u16) -> Result<TcpStream, String> {
+    Err(format!(
+        "SOCKS5 proxy {} requires building with `--features socks`",
+        proxy
+    ))
+}
+
+// ============================================================================
+// TLS
+// ============================================================================
+
+/// TLS connector trusting the CAs of `egress.ca_file` as well as the system
+/// ones; `None` when there are no extra CAs.
+pub fn tls_connector(egress: &EgressConfig) -> Result<Option<Connector>, String> {
+    let Some(path) = &egress.ca_file else {
+        return Ok(None);
+    };
+    let pem = std::fs::read(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+    let certs = native_tls::Certificate::stack_from_pem(&pem)
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut builder = native_tls::TlsConnector::builder();
+    for cert in certs {
+        builder.add_root_certificate(cert);
+    }
+    let connector = builder.build().map_err(|e| e.to_string())?;
+    Ok(Some(Connector::NativeTls(connector)))
+}
+
+/// HTTP client for Deepgram API calls, with the egress proxy and CAs.
+#[cfg(feature = "direct")]
+pub fn http_client(egress: &EgressConfig) -> Result<reqwest::Client, String> {
+    let mut builder = reqwest::Client::builder();
+    if let Some(proxy) = &egress.proxy {
+        let scheme = match proxy.kind {
+            ProxyKind::Http => "http",
+            ProxyKind::Socks5 => "socks5h",
+        };
+        let host = if proxy.host.contains(':') {
+            format!("[{}]", proxy.host)
+        } else {
+            proxy.host.clone()
+        };
+        let mut url =
+            url::Url::parse(&format!("{}://{}", scheme, host)).map_err(|e| e.to_string())?;
+        let _ = url.set_port(Some(proxy.port));
+        if let Some((user, password)) = &proxy.credentials {
+            let _ = url.set_username(user);
+            let _ = url.set_password(Some(password.expose()));
+        }
+        let proxy = reqwest::Proxy::all(url.as_str())
+            .map_err(|e| e.to_string())?
+            .no_proxy(reqwest::NoProxy::from_string(&egress.no_proxy.join(",")));
+        builder = builder.proxy(proxy);
+    }
+    if let Some(path) = &egress.ca_file {
+        let pem =
+            std::fs::read(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        for cert in reqwest::Certificate::from_pem_bundle(&pem).map_err(|e| e.to_string())? {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+    builder.build().map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn no_proxy(entries: &[&str]) -> Vec<String> {
+        entries.iter().map(|e| e.to_string()).collect()
+    }
+
+    #[test]
+    fn a_star_exempts_every_host() {
+        assert!(bypasses(&no_proxy(&["*"]), "agent.deepgram.com", 443));
+        assert!(!bypasses(&[], "agent.deepgram.com", 443));
+    }
+
+    #[test]
+    fn domains_exempt_their_subdomains() {
+        let list = no_proxy(&["deepgram.com", ".corp.example", "*.internal"]);
+        assert!(bypasses(&list, "deepgram.com", 443));
+        assert!(bypasses(&list, "Agent.Deepgram.com", 443));
+        assert!(bypasses(&list, "proxy.corp.example", 443));
+        assert!(bypasses(&list, "corp.example", 443));
+        assert!(bypasses(&list, "vault.internal", 8200));
+        assert!(!bypasses(&list, "notdeepgram.com", 443));
+        assert!(!bypasses(&list, "deepgram.com.evil.example", 443));
+    }
+
+    #[test]
+    fn ports_limit_an_entry() {
+        let list = no_proxy(&["agent.deepgram.com:8443", "10.0.0.1:443", "[::1]:8443"]);
+        assert!(bypasses(&list, "agent.deepgram.com", 8443));
+        assert!(!bypasses(&list, "agent.deepgram.com", 443));
+        assert!(bypasses(&list, "10.0.0.1", 443));
+        assert!(!bypasses(&list, "10.0.0.1", 80));
+        assert!(bypasses(&list, "[::1]", 8443));
+        assert!(!bypasses(&list, "[::1]", 443));
+    }
+
+    #[test]
+    fn addresses_and_ranges_exempt_ips() {
+        let list = no_proxy(&["127.0.0.1", "10.0.0.0/8", "::1", "fd00::/8", "[fe80::1]"]);
+        assert!(bypasses(&list, "127.0.0.1", 443));
+        assert!(bypasses(&list, "10.20.30.40", 443));
+        assert!(!bypasses(&list, "11.0.0.1", 443));
+        assert!(bypasses(&list, "[::1]", 443));
+        assert!(bypasses(&list, "[fd12:3456::1]", 443));
+        assert!(bypasses(&list, "[fe80::1]", 443));
+        assert!(!bypasses(&list, "[2001:db8::1]", 443));
+        // Ranges only match addresses, not names
+        assert!(!bypasses(&list, "localhost", 443));
+    }
+}
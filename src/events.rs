@@ -0,0 +1,380 @@
+// Event bus integration for conversation events.
+//
+// Every JSON message that passes through a proxied session, in either
+// direction, is published to the configured `[events]` sink as a
+// `SessionEvent` tagged with the session id, tenant and subject, framed by
+// `SessionStarted` / `SessionEnded` events from the proxy itself.
+//
+// Events go through a bounded queue drained by a background task. When the
+// sink is slow or unreachable the queue fills up and further events are
+// dropped (and counted in the logs) rather than holding up audio forwarding.
+//
+// Built-in sinks: a JSON lines file, NATS (`nats` feature) and Redis Streams
+// (`redis` feature). Implement `EventSink` to publish anywhere else and pass
+// it to `voice_agent_router_with_sink`.
+
+use crate::auth::Claims;
+use crate::config::{EventSinkConfig, EventsConfig};
+use crate::proxy::ClosedBy;
+use chrono::Utc;
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+/// Delay between attempts to connect the configured sink.
+const CONNECT_RETRY: Duration = Duration::from_secs(5);
+
+// ============================================================================
+// EVENTS
+// ============================================================================
+
+/// Where an event originated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    /// Sent by the agent to the client.
+    Agent,
+    /// Sent by the client to the agent.
+    Client,
+    /// Session lifecycle, reported by the proxy.
+    Proxy,
+}
+
+/// One published event.
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionEvent {
+    pub session_id: String,
+    /// `tenant` claim of the session token.
+    pub tenant: Option<String>,
+    /// `sub` claim of the session token.
+    pub subject: Option<String>,
+    /// RFC 3339 time the proxy saw the message.
+    pub timestamp: String,
+    pub source: Source,
+    /// Protocol message type, e.g. `ConversationText`.
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// The message as sent on the wire.
+    pub message: Value,
+}
+
+// ============================================================================
+// SINKS
+// ============================================================================
+
+/// Future returned by `EventSink::publish`.
+pub type PublishFuture<'a> = Pin<Box<dyn Future<Output = Result<(), String>> + Send + 'a>>;
+
+/// Destination for session events. `publish` is called for one event at a
+/// time from a background task, so it may take as long as it needs.
+pub trait EventSink: Send + Sync {
+    fn publish<'a>(&'a self, event: &'a SessionEvent) -> PublishFuture<'a>;
+}
+
+/// Appends events to a file, one JSON object per line.
+pub struct FileSink {
+    file: tokio::sync::Mutex<tokio::fs::File>,
+}
+
+impl FileSink {
+    pub async fn open(path: &std::path::Path) -> std::io::Result<Self> {
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        Ok(Self {
+            file: tokio::sync::Mutex::new(file),
+        })
+    }
+}
+
+impl EventSink for FileSink {
+    fn publish<'a>(&'a self, event: &'a SessionEvent) -> PublishFuture<'a> {
+        Box::pin(async move {
+            let mut line = serde_json::to_vec(event).map_err(|e| e.to_string())?;
+            line.push(b'\n');
+            self.file
+                .lock()
+                .await
+                .write_all(&line)
+                .await
+                .map_err(|e| e.to_string())
+        })
+    }
+}
+
+/// Publishes each event to `<subject>.<type>` on a NATS server.
+#[cfg(feature = "nats")]
+pub struct NatsSink {
+    client: async_nats::Client,
+    subject: String,
+}
+
+#[cfg(feature = "nats")]
+impl NatsSink {
+    pub async fn connect(url: &str, subject: &str) -> Result<Self, String> {
+        let client = async_nats::connect(url)
+            .await
+            .map_err(|e| format!("cannot connect to NATS at {}: {}", url, e))?;
+        Ok(Self {
+            client,
+            subject: subject.to_string(),
+        })
+    }
+}
+
+#[cfg(feature = "nats")]
+impl EventSink for NatsSink {
+    fn publish<'a>(&'a self, event: &'a SessionEvent) -> PublishFuture<'a> {
+        Box::pin(async move {
+            let payload = serde_json::to_vec(event).map_err(|e| e.to_string())?;
+            self.client
+                .publish(
+                    format!("{}.{}", self.subject, event.kind),
+                    bytes::Bytes::from(payload),
+                )
+                .await
+                .map_err(|e| e.to_string())
+        })
+    }
+}
+
+/// Appends events to a Redis stream (`XADD`), trimmed to about `max_len`
+/// entries. Entries carry `type`, `session_id` and the full `event` JSON.
+#[cfg(feature = "redis")]
+pub struct RedisSink {
+    connection: redis::aio::ConnectionManager,
+    stream: String,
+    max_len: usize,
+}
+
+#[cfg(feature = "redis")]
+impl RedisSink {
+    pub async fn connect(url: &str, stream: &str, max_len: usize) -> Result<Self, String> {
+        let client =
+            redis::Client::open(url).map_err(|e| format!("invalid Redis URL {}: {}", url, e))?;
+        let connection = client
+            .get_connection_manager()
+            .await
+            .map_err(|e| format!("cannot connect to Redis at {}: {}", url, e))?;
+        Ok(Self {
+            connection,
+            stream: stream.to_string(),
+            max_len,
+        })
+    }
+}
+
+#[cfg(feature = "redis")]
+impl EventSink for RedisSink {
+    fn publish<'a>(&'a self, event: &'a SessionEvent) -> PublishFuture<'a> {
+        Box::pin(async move {
+            let payload = serde_json::to_string(event).map_err(|e| e.to_string())?;
+            let mut cmd = redis::cmd("XADD");
+            cmd.arg(&self.stream);
+            if self.max_len > 0 {
+                cmd.arg("MAXLEN").arg("~").arg(self.max_len);
+            }
+            cmd.arg("*")
+                .arg("type")
+                .arg(&event.kind)
+                .arg("session_id")
+                .arg(&event.session_id)
+                .arg("event")
+                .arg(payload);
+            cmd.query_async::<redis::Value>(&mut self.connection.clone())
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        })
+    }
+}
+
+/// Open the sink selected by `[events]`.
+pub async fn connect(config: &EventSinkConfig) -> Result<Arc<dyn EventSink>, String> {
+    match config {
+        EventSinkConfig::None => Err("no event sink configured".to_string()),
+        EventSinkConfig::File { path } => FileSink::open(path)
+            .await
+            .map(|sink| Arc::new(sink) as Arc<dyn EventSink>)
+            .map_err(|e| format!("cannot open {}: {}", path.display(), e)),
+        #[cfg(feature = "nats")]
+        EventSinkConfig::Nats { url, subject } => NatsSink::connect(url, subject)
+            .await
+            .map(|sink| Arc::new(sink) as Arc<dyn EventSink>),
+        #[cfg(feature = "redis")]
+        EventSinkConfig::Redis {
+            url,
+            stream,
+            max_len,
+        } => RedisSink::connect(url, stream, *max_len)
+            .await
+            .map(|sink| Arc::new(sink) as Arc<dyn EventSink>),
+        #[allow(unreachable_patterns)]
+        _ => Err("event sink not compiled in".to_string()),
+    }
+}
+
+// ============================================================================
+// EVENT BUS
+// ============================================================================
+
+/// Non-blocking queue in front of a sink, shared by all sessions.
+#[derive(Clone)]
+pub struct EventBus {
+    tx: mpsc::Sender<SessionEvent>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl EventBus {
+    /// Publish to `sink` through a queue of `buffer` events. Must be called
+    /// from within a Tokio runtime.
+    pub fn new(sink: Arc<dyn EventSink>, buffer: usize) -> Self {
+        Self::spawn(buffer, async move { sink })
+    }
+
+    /// Bus for the `[events]` sink, or `None` when events are disabled. The
+    /// sink is connected in the background and reconnected until it succeeds;
+    /// events emitted meanwhile queue up to the buffer size.
+    pub fn from_config(config: &EventsConfig) -> Option<Self> {
+        if config.sink == EventSinkConfig::None {
+            return None;
+        }
+        let sink_config = config.sink.clone();
+        Some(Self::spawn(config.buffer, async move {
+            loop {
+                match connect(&sink_config).await {
+                    Ok(sink) => {
+                        info!("Publishing session events to {}", sink_config);
+                        return sink;
+                    }
+                    Err(e) => {
+                        error!("Event sink unavailable, retrying: {}", e);
+                        tokio::time::sleep(CONNECT_RETRY).await;
+                    }
+                }
+            }
+        }))
+    }
+
+    fn spawn(
+        buffer: usize,
+        sink: impl Future<Output = Arc<dyn EventSink>> + Send + 'static,
+    ) -> Self {
+        let (tx, mut rx) = mpsc::channel::<SessionEvent>(buffer.max(1));
+        tokio::spawn(async move {
+            let sink = sink.await;
+            while let Some(event) = rx.recv().await {
+                if let Err(e) = sink.publish(&event).await {
+                    warn!("Failed to publish {} event: {}", event.kind, e);
+                }
+            }
+        });
+        Self {
+            tx,
+            dropped: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Queue an event without waiting; drops it if the queue is full.
+    pub fn emit(&self, event: SessionEvent) {
+        if let Err(mpsc::error::TrySendError::Full(event)) = self.tx.try_send(event) {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed);
+            if dropped.is_multiple_of(1000) {
+                warn!(
+                    "Event sink is falling behind; dropped {} event(s) so far (latest: {})",
+                    dropped + 1,
+                    event.kind
+                );
+            }
+        }
+    }
+
+    /// Event source for a new session.
+    pub fn session(&self, session_id: &str, claims: &Claims) -> SessionEvents {
+        SessionEvents {
+            bus: self.clone(),
+            session_id: session_id.to_string(),
+            tenant: claims.tenant().map(str::to_string),
+            subject: claims.sub.clone(),
+            started: Instant::now(),
+        }
+    }
+}
+
+/// Publishes the events of one proxied session.
+pub struct SessionEvents {
+    bus: EventBus,
+    session_id: String,
+    tenant: Option<String>,
+    subject: Option<String>,
+    started: Instant,
+}
+
+impl SessionEvents {
+    fn emit(&self, source: Source, kind: &str, message: Value) {
+        self.bus.emit(SessionEvent {
+            session_id: self.session_id.clone(),
+            tenant: self.tenant.clone(),
+            subject: self.subject.clone(),
+            timestamp: Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            source,
+            kind: kind.to_string(),
+            message,
+        });
+    }
+
+    /// Publish a protocol message; text that is not a typed JSON message is
+    /// skipped.
+    fn message(&self, source: Source, text: &str) {
+        let Ok(message) = serde_json::from_str::<Value>(text) else {
+            return;
+        };
+        if let Some(kind) = message.get("type").and_then(Value::as_str) {
+            let kind = kind.to_string();
+            self.emit(source, &kind, message);
+        }
+    }
+
+    /// A text frame from the agent.
+    pub fn agent_text(&self, text: &str) {
+        self.message(Source::Agent, text);
+    }
+
+    /// A text frame from the client.
+    pub fn client_text(&self, text: &str) {
+        self.message(Source::Client, text);
+    }
+
+    /// The upstream connection is established.
+    pub fn started(&self) {
+        self.emit(
+            Source::Proxy,
+            "SessionStarted",
+            json!({ "type": "SessionStarted" }),
+        );
+    }
+
+    /// The session is over.
+    pub fn ended(&self, closed_by: ClosedBy, close_code: u16, close_reason: &str) {
+        self.emit(
+            Source::Proxy,
+            "SessionEnded",
+            json!({
+                "type": "SessionEnded",
+                "closed_by": closed_by,
+                "close_code": close_code,
+                "close_reason": close_reason,
+                "duration_secs": self.started.elapsed().as_secs_f64(),
+            }),
+        );
+    }
+}
@@ -0,0 +1,375 @@
+// Static frontend serving.
+//
+// Serves the built frontend (frontend/dist) from disk or, with the
+// `embed-frontend` feature, from a copy compiled into the binary:
+//
+//   - SPA fallback: unknown extension-less paths render index.html
+//   - Base path injection into HTML, equivalent to the Caddy templates used
+//     previously ({{env "BASE_PATH"}} / {{.Env.BASE_PATH}})
+//   - Long-lived caching for hashed build assets, revalidation for HTML
+//   - Precompressed .br / .gz siblings when the client accepts them
+
+use crate::config::FrontendConfig;
+use axum::{
+    body::Body,
+    http::{HeaderMap, HeaderValue, Method, StatusCode, Uri, header},
+    response::{IntoResponse, Json, Response},
+};
+use serde_json::json;
+use std::path::PathBuf;
+
+/// Cache policy for fingerprinted build output (Vite emits these under assets/).
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+/// Cache policy for other static files.
+const SHORT_LIVED: &str = "public, max-age=3600";
+/// HTML must be revalidated so new deployments are picked up.
+const REVALIDATE: &str = "no-cache";
+
+#[cfg(feature = "embed-frontend")]
+#[derive(rust_embed::RustEmbed)]
+#[folder = "frontend/dist"]
+#[allow_missing = true]
+struct EmbeddedAssets;
+
+enum Source {
+    Disk(PathBuf),
+    #[cfg(feature = "embed-frontend")]
+    Embedded,
+}
+
+/// A file read from the asset source.
+struct Asset {
+    bytes: Vec<u8>,
+    etag: Option<String>,
+}
+
+/// Serves the frontend for requests no API route matched.
+pub struct Frontend {
+    source: Source,
+    base_path: String,
+}
+
+impl Frontend {
+    /// `base_path` is the public prefix substituted into HTML.
+    pub fn new(config: &FrontendConfig, base_path: &str) -> Self {
+        #[cfg(feature = "embed-frontend")]
+        let source = if config.embedded {
+            Source::Embedded
+        } else {
+            Source::Disk(config.dir.clone())
+        };
+        #[cfg(not(feature = "embed-frontend"))]
+        let source = Source::Disk(config.dir.clone());
+
+        Self {
+            source,
+            base_path: base_path.to_string(),
+        }
+    }
+
+    /// Human-readable description of where assets come from.
+    pub fn describe(&self) -> String {
+        match &self.source {
+            Source::Disk(dir) => dir.display().to_string(),
+            #[cfg(feature = "embed-frontend")]
+            Source::Embedded => "embedded assets".to_string(),
+        }
+    }
+
+    async fn read(&self, path: &str) -> Option<Asset> {
+        match &self.source {
+            Source::Disk(dir) => {
+                let full = dir.join(path);
+                let meta = tokio::fs::metadata(&full).await.ok()?;
+                if !meta.is_file() {
+                    return None;
+                }
+                let etag = meta
+                    .modified()
+                    .ok()
+                    .and_then(|m| m.duration_since(std::time::UNIX_EPOCH).ok())
+                    .map(|m| format!("\"{:x}-{:x}\"", meta.len(), m.as_secs()));
+                let bytes = tokio::fs::read(&full).await.ok()?;
+                Some(Asset { bytes, etag })
+            }
+            #[cfg(feature = "embed-frontend")]
+            Source::Embedded => {
+                let file = EmbeddedAssets::get(path)?;
+                let etag = Some(format!(
+                    "\"{}\"",
+                    hex::encode(&file.metadata.sha256_hash()[..8])
+                ));
+                Some(Asset {
+                    bytes: file.data.into_owned(),
+                    etag,
+                })
+            }
+        }
+    }
+
+    /// Answer a GET/HEAD request for `uri`.
+    pub async fn respond(&self, method: &Method, uri: &Uri, headers: &HeaderMap) -> Response {
+        let raw_path = uri.path();
+        if raw_path == "/api" || raw_path.starts_with("/api/") {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({"error": "NOT_FOUND", "message": "Unknown API route"})),
+            )
+                .into_response();
+        }
+        if method != Method::GET && method != Method::HEAD {
+            return StatusCode::METHOD_NOT_ALLOWED.into_response();
+        }
+
+        let Some(path) = sanitize(raw_path) else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        let path = if path.is_empty() || path.ends_with('/') {
+            format!("{}index.html", path)
+        } else {
+            path
+        };
+
+        let response = match self.read(&path).await {
+            Some(asset) if is_html(&path) => self.html(asset),
+            Some(asset) => self.file(&path, asset, headers).await,
+            // SPA fallback: client-side routes have no file extension
+            None if !has_extension(&path) => match self.read("index.html").await {
+                Some(asset) => self.html(asset),
+                None => StatusCode::NOT_FOUND.into_response(),
+            },
+            None => StatusCode::NOT_FOUND.into_response(),
+        };
+
+        not_modified(response, headers)
+    }
+
+    /// HTML goes through base path injection and is never cached long-term.
+    fn html(&self, asset: Asset) -> Response {
+        let html = String::from_utf8_lossy(&asset.bytes);
+        let rendered = render_base_path(&html, &self.base_path);
+        let mut response = rendered.into_response();
+        let h = response.headers_mut();
+        h.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/html; charset=utf-8"),
+        );
+        h.insert(header::CACHE_CONTROL, HeaderValue::from_static(REVALIDATE));
+        response
+    }
+
+    async fn file(&self, path: &str, asset: Asset, headers: &HeaderMap) -> Response {
+        let mime = mime_guess::from_path(path).first_or_octet_stream();
+
+        // Prefer a precompressed sibling the client can decode
+        let mut encoding = None;
+        let mut asset = asset;
+        for (token, ext) in [("br", "br"), ("gzip", "gz")] {
+            if accepts_encoding(headers, token)
+                && let Some(compressed) = self.read(&format!("{}.{}", path, ext)).await
+            {
+                encoding = Some(token);
+                asset = Asset {
+                    bytes: compressed.bytes,
+                    etag: asset
+                        .etag
+                        .map(|e| format!("{}-{}\"", e.trim_end_matches('"'), ext)),
+                };
+                break;
+            }
+        }
+
+        let cache = if path.starts_with("assets/") {
+            IMMUTABLE
+        } else {
+            SHORT_LIVED
+        };
+
+        let mut response = Response::new(Body::from(asset.bytes));
+        let h = response.headers_mut();
+        if let Ok(value) = HeaderValue::from_str(mime.as_ref()) {
+            h.insert(header::CONTENT_TYPE, value);
+        }
+        h.insert(header::CACHE_CONTROL, HeaderValue::from_static(cache));
+        h.insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+        if let Some(encoding) = encoding {
+            h.insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding));
+        }
+        if let Some(etag) = asset.etag.and_then(|e| HeaderValue::from_str(&e).ok()) {
+            h.insert(header::ETAG, etag);
+        }
+        response
+    }
+}
+
+/// Turn a request path into a relative asset path, refusing traversal.
+fn sanitize(path: &str) -> Option<String> {
+    let decoded = percent_decode(path.trim_start_matches('/'))?;
+    let mut parts = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => return None,
+            s if s.contains('\\') || s.contains('\0') => return None,
+            s => parts.push(s),
+        }
+    }
+    let mut clean = parts.join("/");
+    if decoded.ends_with('/') && !clean.is_empty() {
+        clean.push('/');
+    }
+    Some(clean)
+}
+
+fn percent_decode(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = input.get(i + 1..i + 3)?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+fn is_html(path: &str) -> bool {
+    path.ends_with(".html")
+}
+
+fn has_extension(path: &str) -> bool {
+    path.rsplit('/')
+        .next()
+        .is_some_and(|name| name.contains('.'))
+}
+
+fn accepts_encoding(headers: &HeaderMap, token: &str) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|item| {
+            let mut parts = item.split(';');
+            let name = parts.next().unwrap_or("").trim();
+            let refused = parts.any(|p| {
+                p.trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    == Some(0.0)
+            });
+            name.eq_ignore_ascii_case(token) && !refused
+        })
+}
+
+/// Turn a 200 response into 304 when the client's If-None-Match matches.
+fn not_modified(response: Response, headers: &HeaderMap) -> Response {
+    let etag = response.headers().get(header::ETAG).cloned();
+    let matches = match (&etag, headers.get(header::IF_NONE_MATCH)) {
+        (Some(etag), Some(inm)) => inm
+            .to_str()
+            .map(|v| {
+                v.split(',')
+                    .any(|t| t.trim() == etag.to_str().unwrap_or(""))
+            })
+            .unwrap_or(false),
+        _ => false,
+    };
+    if !matches || response.status() != StatusCode::OK {
+        return response;
+    }
+    let mut not_modified = StatusCode::NOT_MODIFIED.into_response();
+    for name in [header::ETAG, header::CACHE_CONTROL, header::VARY] {
+        if let Some(value) = response.headers().get(&name) {
+            not_modified.headers_mut().insert(name, value.clone());
+        }
+    }
+    not_modified
+}
+
+/// Replace Caddy-style base path template tags with `base_path`.
+/// Other `{{ ... }}` sequences are left untouched.
+fn render_base_path(html: &str, base_path: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start..].find("}}") else {
+            break;
+        };
+        let tag = rest[start + 2..start + len].trim();
+        out.push_str(&rest[..start]);
+        if tag == "env \"BASE_PATH\"" || tag == ".Env.BASE_PATH" {
+            out.push_str(base_path);
+        } else {
+            out.push_str(&rest[start..start + len + 2]);
+        }
+        rest = &rest[start + len + 2..];
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_normalizes_paths() {
+        assert_eq!(sanitize("/").as_deref(), Some(""));
+        assert_eq!(
+            sanitize("/assets//app.js").as_deref(),
+            Some("assets/app.js")
+        );
+        assert_eq!(sanitize("/./docs/").as_deref(), Some("docs/"));
+        assert_eq!(sanitize("/a%20b/c%2Ejs").as_deref(), Some("a b/c.js"));
+    }
+
+    #[test]
+    fn sanitize_refuses_traversal() {
+        assert_eq!(sanitize("/../etc/passwd"), None);
+        assert_eq!(sanitize("/assets/%2e%2e/%2e%2e/secret"), None);
+        assert_eq!(sanitize("/assets%2F..%2Fsecret"), None);
+        assert_eq!(sanitize("/a%5C..%5Cb"), None);
+        assert_eq!(sanitize("/a%00.html"), None);
+    }
+
+    #[test]
+    fn sanitize_refuses_malformed_escapes() {
+        assert_eq!(sanitize("/a%2"), None);
+        assert_eq!(sanitize("/a%zz"), None);
+        assert_eq!(sanitize("/a%ff"), None);
+    }
+
+    #[test]
+    fn render_base_path_replaces_only_base_path_tags() {
+        let html =
+            r#"<base href="{{ env "BASE_PATH" }}/"><a href="{{.Env.BASE_PATH}}/x">{{ other }}</a>"#;
+        assert_eq!(
+            render_base_path(html, "/voice"),
+            r#"<base href="/voice/"><a href="/voice/x">{{ other }}</a>"#
+        );
+        assert_eq!(
+            render_base_path(html, ""),
+            r#"<base href="/"><a href="/x">{{ other }}</a>"#
+        );
+    }
+
+    #[test]
+    fn render_base_path_keeps_unterminated_tags() {
+        assert_eq!(render_base_path("a {{ b", "/p"), "a {{ b");
+    }
+
+    #[test]
+    fn accepts_encoding_honours_q_zero() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT_ENCODING, "gzip, br;q=0".parse().unwrap());
+        assert!(accepts_encoding(&headers, "gzip"));
+        assert!(!accepts_encoding(&headers, "br"));
+        assert!(!accepts_encoding(&HeaderMap::new(), "gzip"));
+    }
+}
@@ -0,0 +1,491 @@
+// Content moderation guardrails.
+//
+// Every user and assistant `ConversationText` line of a proxied session is
+// checked against the blocklist rules of `[guardrails]`, in order, and when
+// none matches, sent to the configured classifier hooks. Classifiers are
+// HTTP endpoints receiving
+//
+//   {"session_id": "...", "role": "user", "content": "..."}
+//
+// and answering `{"flagged": bool, "category": "..."}`; they run in the
+// background, so a slow classifier never delays the conversation. Lines are
+// inspected after redaction.
+//
+// A violation is handed to the proxy, which takes the guardrail's action:
+// inject an agent message, update the prompt, warn the client or end the
+// session. An assistant line caught by a blocklist is also withheld from the
+// client together with the rest of that turn's audio. Every violation and
+// every classifier verdict is appended to `guardrails.audit_log`.
+
+use crate::auth::Claims;
+use crate::config::{AppConfig, ClassifierHook, GuardrailAction, GuardrailsConfig};
+use crate::protocol::{AgentMessage, Role};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{Mutex, mpsc};
+use tracing::{error, warn};
+
+// ============================================================================
+// CLASSIFIERS
+// ============================================================================
+
+/// A classifier's judgement of one conversation line.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Verdict {
+    pub flagged: bool,
+    /// Why the line was flagged, e.g. `harassment`.
+    #[serde(default)]
+    pub category: Option<String>,
+}
+
+/// Future returned by `Classifier::classify`.
+pub type ClassifyFuture<'a> = Pin<Box<dyn Future<Output = Result<Verdict, String>> + Send + 'a>>;
+
+/// Judges conversation lines. Called from a background task per line.
+pub trait Classifier: Send + Sync {
+    fn classify<'a>(
+        &'a self,
+        session_id: &'a str,
+        role: Role,
+        content: &'a str,
+    ) -> ClassifyFuture<'a>;
+}
+
+/// Classifier hook backed by an HTTP endpoint.
+pub struct HttpClassifier {
+    client: reqwest::Client,
+    url: String,
+    timeout: Duration,
+}
+
+impl HttpClassifier {
+    pub fn new(client: reqwest::Client, url: &str, timeout: Duration) -> Self {
+        Self {
+            client,
+            url: url.to_string(),
+            timeout,
+        }
+    }
+}
+
+impl Classifier for HttpClassifier {
+    fn classify<'a>(
+        &'a self,
+        session_id: &'a str,
+        role: Role,
+        content: &'a str,
+    ) -> ClassifyFuture<'a> {
+        Box::pin(async move {
+            let response = self
+                .client
+                .post(&self.url)
+                .timeout(self.timeout)
+                .json(&json!({ "session_id": session_id, "role": role, "content": content }))
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .map_err(|e| e.to_string())?;
+            response
+                .json::<Verdict>()
+                .await
+                .map_err(|e| format!("invalid classifier response: {}", e))
+        })
+    }
+}
+
+// ============================================================================
+// GUARDRAILS
+// ============================================================================
+
+/// A guardrail violation for the proxy to act on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Violation {
+    /// Name of the rule or classifier that was violated.
+    pub guardrail: String,
+    pub role: Role,
+    pub action: GuardrailAction,
+}
+
+/// Guardrail state shared by all sessions.
+#[derive(Clone, Default)]
+pub struct Guardrails {
+    client: reqwest::Client,
+    /// Serializes appends to the audit log.
+    audit: Arc<Mutex<()>>,
+}
+
+impl Guardrails {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Guardrails for a new session, or `None` when none are configured.
+    pub fn session(
+        &self,
+        config: Arc<AppConfig>,
+        session_id: &str,
+        claims: &Claims,
+    ) -> Option<SessionGuardrails> {
+        let guardrails = &config.reloadable.guardrails;
+        if guardrails.rules.is_empty() && guardrails.classifiers.is_empty() {
+            return None;
+        }
+        let classifiers = guardrails
+            .classifiers
+            .iter()
+            .map(|hook| {
+                let classifier = HttpClassifier::new(
+                    self.client.clone(),
+                    &hook.url,
+                    Duration::from_millis(hook.timeout_ms),
+                );
+                (hook.clone(), Arc::new(classifier) as Arc<dyn Classifier>)
+            })
+            .collect();
+        let (tx, rx) = mpsc::unbounded_channel();
+        Some(SessionGuardrails {
+            decisions: Arc::new(Decisions {
+                audit_lock: self.audit.clone(),
+                config: guardrails.clone(),
+                session_id: session_id.to_string(),
+                subject: claims.sub.clone(),
+                violations: tx,
+            }),
+            classifiers,
+            violations: Mutex::new(rx),
+        })
+    }
+}
+
+/// Inspects the conversation of one proxied session. Uses the configuration
+/// snapshot the session started with.
+pub struct SessionGuardrails {
+    decisions: Arc<Decisions>,
+    classifiers: Vec<(ClassifierHook, Arc<dyn Classifier>)>,
+    violations: Mutex<mpsc::UnboundedReceiver<Violation>>,
+}
+
+impl SessionGuardrails {
+    /// Consult `classifier` instead of the HTTP endpoint of the classifier
+    /// hook with the same name, or in addition to the configured hooks.
+    pub fn with_classifier(
+        mut self,
+        hook: ClassifierHook,
+        classifier: Arc<dyn Classifier>,
+    ) -> Self {
+        self.classifiers.retain(|(h, _)| h.name != hook.name);
+        self.classifiers.push((hook, classifier));
+        self
+    }
+
+    /// Check a text frame from the agent. Returns true when the frame must
+    /// be withheld from the client: an assistant line caught by a blocklist.
+    pub fn inspect(&self, text: &str) -> bool {
+        let Some(AgentMessage::ConversationText { role, content }) = AgentMessage::parse(text)
+        else {
+            return false;
+        };
+
+        let decisions = &self.decisions;
+        let rule = decisions.config.rules.iter().find(|rule| {
+            rule.applies_to.contains(&role) && rule.matchers.iter().any(|m| m.is_match(&content))
+        });
+        if let Some(rule) = rule {
+            let violation = Violation {
+                guardrail: rule.name.clone(),
+                role,
+                action: rule.action.clone(),
+            };
+            decisions.violated(violation, "rule", &content, None);
+            return role == Role::Assistant;
+        }
+
+        for (hook, classifier) in &self.classifiers {
+            if !hook.applies_to.contains(&role) {
+                continue;
+            }
+            let decisions = decisions.clone();
+            let hook = hook.clone();
+            let classifier = classifier.clone();
+            let content = content.clone();
+            tokio::spawn(async move {
+                match classifier
+                    .classify(&decisions.session_id, role, &content)
+                    .await
+                {
+                    Ok(verdict) if verdict.flagged => {
+                        let violation = Violation {
+                            guardrail: hook.name.clone(),
+                            role,
+                            action: hook.action.clone(),
+                        };
+                        decisions.violated(violation, "classifier", &content, verdict.category);
+                    }
+                    Ok(verdict) => decisions.audit(json!({
+                        "guardrail": hook.name,
+                        "source": "classifier",
+                        "role": role,
+                        "content": content,
+                        "verdict": "allow",
+                        "category": verdict.category,
+                    })),
+                    Err(e) => {
+                        warn!(
+                            "Guardrail classifier '{}' failed, allowing the line: {}",
+                            hook.name, e
+                        );
+                        decisions.audit(json!({
+                            "guardrail": hook.name,
+                            "source": "classifier",
+                            "role": role,
+                            "content": content,
+                            "verdict": "error",
+                            "error": e,
+                        }));
+                    }
+                }
+            });
+        }
+        false
+    }
+
+    /// Wait for the next violation to act on.
+    pub async fn next_violation(&self) -> Option<Violation> {
+        self.violations.lock().await.recv().await
+    }
+}
+
+/// Records a session's guardrail decisions and queues its violations.
+struct Decisions {
+    audit_lock: Arc<Mutex<()>>,
+    /// `[guardrails]` as of the session start.
+    config: GuardrailsConfig,
+    session_id: String,
+    subject: Option<String>,
+    violations: mpsc::UnboundedSender<Violation>,
+}
+
+impl Decisions {
+    fn violated(
+        &self,
+        violation: Violation,
+        source: &str,
+        content: &str,
+        category: Option<String>,
+    ) {
+        warn!(
+            "Guardrail '{}' violated by {} line in session {}, action: {}",
+            violation.guardrail,
+            if violation.role == Role::User {
+                "user"
+            } else {
+                "assistant"
+            },
+            self.session_id,
+            violation.action.name()
+        );
+        self.audit(json!({
+            "guardrail": violation.guardrail,
+            "source": source,
+            "role": violation.role,
+            "content": content,
+            "verdict": "violation",
+            "category": category,
+            "action": violation.action.name(),
+        }));
+        let _ = self.violations.send(violation);
+    }
+
+    /// Append a decision to the audit log in the background.
+    fn audit(&self, mut entry: Value) {
+        entry["timestamp"] = json!(Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true));
+        entry["session_id"] = json!(self.session_id);
+        entry["subject"] = json!(self.subject);
+        let lock = self.audit_lock.clone();
+        let path = self.config.audit_log.clone();
+        tokio::spawn(async move {
+            let _lock = lock.lock().await;
+            let written = async {
+                tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .await?
+                    .write_all(format!("{}\n", entry).as_bytes())
+                    .await
+            };
+            if let Err(e) = written.await {
+                error!("Cannot write guardrail audit log {}: {}", path.display(), e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::GuardrailRule;
+    use std::path::PathBuf;
+
+    fn rule(name: &str, pattern: &str, applies_to: &[Role]) -> GuardrailRule {
+        GuardrailRule {
+            name: name.to_string(),
+            applies_to: applies_to.to_vec(),
+            matchers: vec![regex::Regex::new(pattern).unwrap()],
+            action: GuardrailAction::Warn {
+                message: "Please keep it civil".to_string(),
+            },
+        }
+    }
+
+    fn session(rules: Vec<GuardrailRule>, audit_log: PathBuf) -> SessionGuardrails {
+        let (tx, rx) = mpsc::unbounded_channel();
+        SessionGuardrails {
+            decisions: Arc::new(Decisions {
+                audit_lock: Arc::default(),
+                config: GuardrailsConfig {
+                    rules,
+                    classifiers: Vec::new(),
+                    audit_log,
+                },
+                session_id: "sess-1".to_string(),
+                subject: Some("u1".to_string()),
+                violations: tx,
+            }),
+            classifiers: Vec::new(),
+            violations: Mutex::new(rx),
+        }
+    }
+
+    fn line(role: &str, content: &str) -> String {
+        json!({ "type": "ConversationText", "role": role, "content": content }).to_string()
+    }
+
+    fn audit_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("guardrails-{}-{}.jsonl", name, std::process::id()))
+    }
+
+    /// The audit log entries once `count` have been written.
+    async fn audit_entries(path: &PathBuf, count: usize) -> Vec<Value> {
+        for _ in 0..300 {
+            if let Ok(contents) = tokio::fs::read_to_string(path).await {
+                let entries: Vec<Value> = contents
+                    .lines()
+                    .map(|l| serde_json::from_str(l).unwrap())
+                    .collect();
+                if entries.len() >= count {
+                    return entries;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("the audit log did not get {} entries", count);
+    }
+
+    /// Flags lines containing "forbidden".
+    struct Keyword;
+
+    impl Classifier for Keyword {
+        fn classify<'a>(&'a self, _: &'a str, _: Role, content: &'a str) -> ClassifyFuture<'a> {
+            Box::pin(async move {
+                Ok(Verdict {
+                    flagged: content.contains("forbidden"),
+                    category: Some("policy".to_string()),
+                })
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn blocked_assistant_lines_are_withheld() {
+        let path = audit_path("withheld");
+        let rules = vec![rule(
+            "secrets",
+            r"(?i)\bsecret plan\b",
+            &[Role::User, Role::Assistant],
+        )];
+        let guardrails = session(rules, path.clone());
+
+        assert!(guardrails.inspect(&line("assistant", "Our Secret Plan is ready")));
+        let violation = guardrails.next_violation().await.unwrap();
+        assert_eq!(violation.guardrail, "secrets");
+        assert_eq!(violation.role, Role::Assistant);
+        assert_eq!(violation.action.name(), "warn");
+
+        // A user line is a violation, but the user has already said it
+        assert!(!guardrails.inspect(&line("user", "tell me the secret plan")));
+        assert_eq!(guardrails.next_violation().await.unwrap().role, Role::User);
+
+        let entries = audit_entries(&path, 2).await;
+        tokio::fs::remove_file(&path).await.unwrap();
+        let entry = &entries[0];
+        assert_eq!(entry["guardrail"], "secrets");
+        assert_eq!(entry["source"], "rule");
+        assert_eq!(entry["role"], "assistant");
+        assert_eq!(entry["content"], "Our Secret Plan is ready");
+        assert_eq!(entry["verdict"], "violation");
+        assert_eq!(entry["action"], "warn");
+        assert_eq!(entry["session_id"], "sess-1");
+        assert_eq!(entry["subject"], "u1");
+        assert!(entry["timestamp"].is_string());
+    }
+
+    #[tokio::test]
+    async fn allowed_lines_pass_without_a_violation() {
+        let rules = vec![
+            rule("secrets", r"(?i)\bsecret plan\b", &[Role::Assistant]),
+            rule("profanity", r"(?i)\bdarn\b", &[Role::User]),
+        ];
+        let guardrails = session(rules, audit_path("allowed"));
+
+        assert!(!guardrails.inspect(&line("assistant", "Here is the plan")));
+        assert!(!guardrails.inspect(&line("assistant", "secret planning")));
+        // Rules only check the speakers they apply to
+        assert!(!guardrails.inspect(&line("assistant", "darn it")));
+        assert!(!guardrails.inspect(&line("user", "what is the secret plan?")));
+        // Only conversation lines are inspected
+        assert!(!guardrails.inspect(r#"{"type":"AgentThinking","content":"secret plan"}"#));
+        assert!(!guardrails.inspect("secret plan"));
+
+        assert!(guardrails.violations.lock().await.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn classifier_verdicts_are_acted_on_and_audited() {
+        let path = audit_path("classifier");
+        let hook = ClassifierHook {
+            name: "moderation".to_string(),
+            url: "http://127.0.0.1:9/classify".to_string(),
+            applies_to: vec![Role::User],
+            timeout_ms: 1000,
+            action: GuardrailAction::End {
+                message: "Session ended".to_string(),
+            },
+        };
+        let guardrails = session(Vec::new(), path.clone()).with_classifier(hook, Arc::new(Keyword));
+
+        // Classified in the background, so never withheld
+        assert!(!guardrails.inspect(&line("user", "a forbidden topic")));
+        let violation = guardrails.next_violation().await.unwrap();
+        assert_eq!(violation.guardrail, "moderation");
+        assert_eq!(violation.action.name(), "end");
+
+        assert!(!guardrails.inspect(&line("user", "the weather")));
+        assert!(!guardrails.inspect(&line("assistant", "a forbidden reply")));
+        audit_entries(&path, 2).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let entries = audit_entries(&path, 2).await;
+        tokio::fs::remove_file(&path).await.unwrap();
+        assert_eq!(entries.len(), 2, "assistant lines are not classified");
+        let verdicts: Vec<_> = entries.iter().map(|e| e["verdict"].clone()).collect();
+        assert_eq!(verdicts, ["violation", "allow"]);
+        assert_eq!(entries[0]["category"], "policy");
+        assert_eq!(entries[0]["source"], "classifier");
+    }
+}
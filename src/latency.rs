@@ -0,0 +1,545 @@
+// Conversation latency analytics (`metrics` feature).
+//
+// `SessionLatency` times each conversational turn from the agent messages
+// passing through the proxy:
+//
+//   UserStartedSpeaking        turn starts
+//   ConversationText (user)    end of the user's speech, as the proxy sees it
+//   AgentThinking              the LLM started
+//   first binary frame         first TTS audio
+//   AgentStartedSpeaking       Deepgram's own total/LLM/TTS latencies
+//   AgentAudioDone             turn ends
+//
+// A turn the user barges into, or that the session ends during, is marked
+// interrupted. Each finished turn is added to the session's report, kept in
+// memory for the last `metrics.keep_sessions` sessions and served by
+// `GET /api/admin/sessions/{id}/latency`, and recorded in Prometheus
+// histograms served by `GET /metrics`:
+//
+//   voice_agent_response_latency_seconds   end of speech to first audio
+//   voice_agent_thinking_latency_seconds   end of speech to AgentThinking
+//   voice_agent_speech_duration_seconds    first audio to AgentAudioDone
+//   voice_agent_reported_latency_seconds   AgentStartedSpeaking, by `kind`
+//   voice_agent_turns_total                turns, by `interrupted`
+//
+// all labelled with the tenant. Histograms go through the `metrics` facade:
+// when the embedding application installed its own recorder first, they are
+// recorded there and `/metrics` is not served.
+
+use crate::auth::Claims;
+use crate::config::{AppConfig, Tenant};
+use crate::protocol::{AgentMessage, Role};
+use chrono::{DateTime, Utc};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Instant;
+use tracing::warn;
+
+/// Histogram buckets, in seconds.
+const BUCKETS: [f64; 12] = [
+    0.1, 0.2, 0.3, 0.5, 0.75, 1.0, 1.5, 2.0, 3.0, 5.0, 10.0, 30.0,
+];
+
+/// Turns kept per session report (most recent).
+const MAX_TURNS: usize = 500;
+
+/// The process-wide Prometheus recorder, if this crate installed it.
+static PROMETHEUS: OnceLock<Option<PrometheusHandle>> = OnceLock::new();
+
+fn prometheus() -> Option<&'static PrometheusHandle> {
+    PROMETHEUS
+        .get_or_init(|| {
+            PrometheusBuilder::new()
+                .set_buckets_for_metric(Matcher::Prefix("voice_agent_".to_string()), &BUCKETS)
+                .and_then(PrometheusBuilder::install_recorder)
+                .inspect_err(|e| {
+                    warn!(
+                        "Cannot install the Prometheus recorder, /metrics is not served: {}",
+                        e
+                    )
+                })
+                .ok()
+        })
+        .as_ref()
+}
+
+// ============================================================================
+// REPORTS
+// ============================================================================
+
+/// Latencies Deepgram reports in `AgentStartedSpeaking`, in milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ReportedLatency {
+    pub total_ms: f64,
+    /// LLM (text-to-text) part.
+    pub ttt_ms: f64,
+    pub tts_ms: f64,
+}
+
+/// Timings of one conversational turn, in milliseconds. Stages the turn
+/// never reached are `None`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TurnLatency {
+    pub turn: usize,
+    /// When the turn started, from the start of the session.
+    pub at_ms: f64,
+    /// The user barged in, or the session ended, before `AgentAudioDone`.
+    pub interrupted: bool,
+    /// UserStartedSpeaking to the user's transcript.
+    pub user_speech_ms: Option<f64>,
+    /// End of speech (user transcript) to AgentThinking.
+    pub thinking_ms: Option<f64>,
+    /// End of speech (user transcript) to the first TTS audio frame.
+    pub response_ms: Option<f64>,
+    /// First TTS audio frame to AgentAudioDone.
+    pub agent_speech_ms: Option<f64>,
+    pub reported: Option<ReportedLatency>,
+}
+
+/// Distribution of one latency over a session's turns, in milliseconds.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LatencyStats {
+    pub count: usize,
+    pub mean: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+impl LatencyStats {
+    fn of(values: impl Iterator<Item = f64>) -> Option<Self> {
+        let mut values: Vec<f64> = values.collect();
+        if values.is_empty() {
+            return None;
+        }
+        values.sort_by(f64::total_cmp);
+        let percentile = |p: f64| values[((values.len() - 1) as f64 * p).round() as usize];
+        Some(Self {
+            count: values.len(),
+            mean: values.iter().sum::<f64>() / values.len() as f64,
+            p50: percentile(0.5),
+            p90: percentile(0.9),
+            p99: percentile(0.99),
+            max: values[values.len() - 1],
+        })
+    }
+}
+
+/// Latency report of one session; live sessions report the turns so far.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SessionReport {
+    pub session_id: String,
+    pub tenant: String,
+    pub subject: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: Option<DateTime<Utc>>,
+    pub turns: Vec<TurnLatency>,
+}
+
+impl SessionReport {
+    /// The report with per-stage statistics, as served by the report route.
+    pub fn to_json(&self) -> serde_json::Value {
+        let stats = |stage: fn(&TurnLatency) -> Option<f64>| {
+            LatencyStats::of(self.turns.iter().filter_map(stage))
+        };
+        let mut report = serde_json::to_value(self).unwrap_or_default();
+        report["summary"] = serde_json::json!({
+            "turns": self.turns.len(),
+            "interrupted": self.turns.iter().filter(|t| t.interrupted).count(),
+            "response_ms": stats(|t| t.response_ms),
+            "thinking_ms": stats(|t| t.thinking_ms),
+            "agent_speech_ms": stats(|t| t.agent_speech_ms),
+            "reported_total_ms": stats(|t| t.reported.map(|r| r.total_ms)),
+        });
+        report
+    }
+}
+
+// ============================================================================
+// SESSION TRACKING
+// ============================================================================
+
+/// Latency reports of recent sessions, shared by all sessions.
+#[derive(Clone, Default)]
+pub struct Latency {
+    reports: Arc<Mutex<Reports>>,
+}
+
+#[derive(Default)]
+struct Reports {
+    by_id: HashMap<String, Arc<Mutex<SessionReport>>>,
+    /// Session IDs, oldest first.
+    order: VecDeque<String>,
+}
+
+impl Latency {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handle rendering `GET /metrics`, when the Prometheus recorder is ours.
+    pub fn prometheus(&self) -> Option<&'static PrometheusHandle> {
+        prometheus()
+    }
+
+    /// Latency tracker for a new session, or `None` when `[metrics]` is off.
+    pub fn session(
+        &self,
+        config: &AppConfig,
+        session_id: &str,
+        tenant: &Tenant,
+        claims: &Claims,
+    ) -> Option<SessionLatency> {
+        if !config.metrics.enabled {
+            return None;
+        }
+        let report = Arc::new(Mutex::new(SessionReport {
+            session_id: session_id.to_string(),
+            tenant: tenant.id.clone(),
+            subject: claims.sub.clone(),
+            started_at: Utc::now(),
+            ended_at: None,
+            turns: Vec::new(),
+        }));
+        {
+            let mut reports = self.reports.lock().unwrap_or_else(|e| e.into_inner());
+            while reports.order.len() >= config.metrics.keep_sessions.max(1) {
+                if let Some(oldest) = reports.order.pop_front() {
+                    reports.by_id.remove(&oldest);
+                }
+            }
+            reports.order.push_back(session_id.to_string());
+            reports.by_id.insert(session_id.to_string(), report.clone());
+        }
+        Some(SessionLatency {
+            tenant: tenant.id.clone(),
+            started: Instant::now(),
+            report,
+            turn: Mutex::new(None),
+        })
+    }
+
+    /// Report of a live or recent session.
+    pub fn report(&self, session_id: &str) -> Option<SessionReport> {
+        let reports = self.reports.lock().unwrap_or_else(|e| e.into_inner());
+        let report = reports.by_id.get(session_id)?;
+        let report = report.lock().unwrap_or_else(|e| e.into_inner());
+        Some(report.clone())
+    }
+}
+
+/// Event times of the turn in progress.
+struct Turn {
+    started: Instant,
+    user_started: Option<Instant>,
+    transcript: Option<Instant>,
+    thinking: Option<Instant>,
+    first_audio: Option<Instant>,
+    reported: Option<ReportedLatency>,
+}
+
+impl Turn {
+    fn new(now: Instant) -> Self {
+        Self {
+            started: now,
+            user_started: None,
+            transcript: None,
+            thinking: None,
+            first_audio: None,
+            reported: None,
+        }
+    }
+}
+
+/// Times the turns of one proxied session.
+pub struct SessionLatency {
+    tenant: String,
+    started: Instant,
+    report: Arc<Mutex<SessionReport>>,
+    turn: Mutex<Option<Turn>>,
+}
+
+fn ms(from: Option<Instant>, to: Option<Instant>) -> Option<f64> {
+    Some(to?.saturating_duration_since(from?).as_secs_f64() * 1000.0)
+}
+
+impl SessionLatency {
+    fn turn(&self) -> std::sync::MutexGuard<'_, Option<Turn>> {
+        self.turn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// A text frame from the agent.
+    pub fn agent_text(&self, text: &str) {
+        self.agent_text_at(text, Instant::now());
+    }
+
+    fn agent_text_at(&self, text: &str, now: Instant) {
+        let mut current = self.turn();
+        match AgentMessage::parse(text) {
+            Some(AgentMessage::UserStartedSpeaking) => {
+                if let Some(turn) = current.take() {
+                    self.finish(turn, None);
+                }
+                let mut turn = Turn::new(now);
+                turn.user_started = Some(now);
+                *current = Some(turn);
+            }
+            Some(AgentMessage::ConversationText {
+                role: Role::User, ..
+            }) => {
+                // A transcript after the agent answered starts a new turn
+                // (for instance injected user messages)
+                if current.as_ref().is_some_and(|t| t.first_audio.is_some())
+                    && let Some(turn) = current.take()
+                {
+                    self.finish(turn, None);
+                }
+                let turn = current.get_or_insert_with(|| Turn::new(now));
+                turn.transcript = Some(now);
+            }
+            Some(AgentMessage::AgentThinking { .. }) => {
+                if let Some(turn) = current.as_mut().filter(|t| t.thinking.is_none()) {
+                    turn.thinking = Some(now);
+                }
+            }
+            Some(AgentMessage::AgentStartedSpeaking {
+                total_latency,
+                tts_latency,
+                ttt_latency,
+            }) => {
+                if let Some(turn) = current.as_mut() {
+                    turn.reported = Some(ReportedLatency {
+                        total_ms: total_latency * 1000.0,
+                        ttt_ms: ttt_latency * 1000.0,
+                        tts_ms: tts_latency * 1000.0,
+                    });
+                }
+            }
+            Some(AgentMessage::AgentAudioDone) => {
+                if let Some(turn) = current.take() {
+                    self.finish(turn, Some(now));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// TTS audio from the agent. Audio outside a user turn (the greeting)
+    /// is not timed.
+    pub fn agent_binary(&self) {
+        self.agent_binary_at(Instant::now());
+    }
+
+    fn agent_binary_at(&self, now: Instant) {
+        if let Some(turn) = self.turn().as_mut().filter(|t| t.first_audio.is_none()) {
+            turn.first_audio = Some(now);
+        }
+    }
+
+    /// The session is over.
+    pub fn ended(&self) {
+        if let Some(turn) = self.turn().take() {
+            self.finish(turn, None);
+        }
+        let mut report = self.report.lock().unwrap_or_else(|e| e.into_inner());
+        report.ended_at = Some(Utc::now());
+    }
+
+    /// Record a turn that ended at `audio_done`, or was cut short.
+    fn finish(&self, turn: Turn, audio_done: Option<Instant>) {
+        let latency = {
+            let mut report = self.report.lock().unwrap_or_else(|e| e.into_inner());
+            let latency = TurnLatency {
+                turn: report.turns.last().map_or(1, |t| t.turn + 1),
+                at_ms: turn.started.duration_since(self.started).as_secs_f64() * 1000.0,
+                interrupted: audio_done.is_none(),
+                user_speech_ms: ms(turn.user_started, turn.transcript),
+                thinking_ms: ms(turn.transcript, turn.thinking),
+                response_ms: ms(turn.transcript, turn.first_audio),
+                agent_speech_ms: ms(turn.first_audio, audio_done),
+                reported: turn.reported,
+            };
+            if report.turns.len() == MAX_TURNS {
+                report.turns.remove(0);
+            }
+            report.turns.push(latency.clone());
+            latency
+        };
+
+        let tenant = self.tenant.clone();
+        let seconds = |ms: f64| ms / 1000.0;
+        if let Some(v) = latency.response_ms {
+            metrics::histogram!("voice_agent_response_latency_seconds", "tenant" => tenant.clone())
+                .record(seconds(v));
+        }
+        if let Some(v) = latency.thinking_ms {
+            metrics::histogram!("voice_agent_thinking_latency_seconds", "tenant" => tenant.clone())
+                .record(seconds(v));
+        }
+        if let Some(v) = latency.agent_speech_ms {
+            metrics::histogram!("voice_agent_speech_duration_seconds", "tenant" => tenant.clone())
+                .record(seconds(v));
+        }
+        if let Some(r) = latency.reported {
+            for (kind, v) in [("total", r.total_ms), ("ttt", r.ttt_ms), ("tts", r.tts_ms)] {
+                metrics::histogram!(
+                    "voice_agent_reported_latency_seconds",
+                    "tenant" => tenant.clone(),
+                    "kind" => kind
+                )
+                .record(seconds(v));
+            }
+        }
+        metrics::counter!(
+            "voice_agent_turns_total",
+            "tenant" => tenant,
+            "interrupted" => if latency.interrupted { "true" } else { "false" }
+        )
+        .increment(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// A session started at `t0`, and a clock in milliseconds from it.
+    fn session() -> (SessionLatency, impl Fn(u64) -> Instant) {
+        let t0 = Instant::now();
+        let latency = SessionLatency {
+            tenant: "acme".to_string(),
+            started: t0,
+            report: Arc::new(Mutex::new(SessionReport {
+                session_id: "sess-1".to_string(),
+                tenant: "acme".to_string(),
+                subject: None,
+                started_at: Utc::now(),
+                ended_at: None,
+                turns: Vec::new(),
+            })),
+            turn: Mutex::new(None),
+        };
+        (latency, move |ms| t0 + Duration::from_millis(ms))
+    }
+
+    fn turns(latency: &SessionLatency) -> Vec<TurnLatency> {
+        latency.report.lock().unwrap().turns.clone()
+    }
+
+    fn assert_ms(actual: Option<f64>, expected: f64) {
+        let actual = actual.expect("a timing");
+        assert!(
+            (actual - expected).abs() < 1e-6,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    const STARTED_SPEAKING: &str = r#"{"type":"UserStartedSpeaking"}"#;
+    const USER_LINE: &str = r#"{"type":"ConversationText","role":"user","content":"Hi"}"#;
+    const THINKING: &str = r#"{"type":"AgentThinking","content":"..."}"#;
+    const AUDIO_DONE: &str = r#"{"type":"AgentAudioDone"}"#;
+
+    #[test]
+    fn stats_take_the_nearest_rank_percentiles() {
+        assert_eq!(LatencyStats::of(std::iter::empty()), None);
+
+        let stats =
+            LatencyStats::of([7.0, 1.0, 10.0, 4.0, 2.0, 9.0, 3.0, 6.0, 5.0, 8.0].into_iter())
+                .unwrap();
+        assert_eq!(stats.count, 10);
+        assert_eq!(stats.mean, 5.5);
+        // Ranks round((n - 1) * p): 4.5 -> 5, 8.1 -> 8, 8.91 -> 9
+        assert_eq!((stats.p50, stats.p90, stats.p99), (6.0, 9.0, 10.0));
+        assert_eq!(stats.max, 10.0);
+
+        let single = LatencyStats::of([250.0].into_iter()).unwrap();
+        assert_eq!((single.p50, single.p99, single.max), (250.0, 250.0, 250.0));
+    }
+
+    #[test]
+    fn a_turn_is_timed_from_end_of_speech() {
+        let (latency, at) = session();
+        latency.agent_text_at(STARTED_SPEAKING, at(1000));
+        latency.agent_text_at(USER_LINE, at(1800));
+        latency.agent_text_at(THINKING, at(2000));
+        latency.agent_text_at(THINKING, at(2100));
+        latency.agent_binary_at(at(2500));
+        latency.agent_binary_at(at(2600));
+        latency.agent_text_at(
+            r#"{"type":"AgentStartedSpeaking","total_latency":0.7,"tts_latency":0.25,"ttt_latency":0.45}"#,
+            at(2500),
+        );
+        latency.agent_text_at(AUDIO_DONE, at(4500));
+
+        let turns = turns(&latency);
+        assert_eq!(turns.len(), 1);
+        let turn = &turns[0];
+        assert_eq!((turn.turn, turn.interrupted), (1, false));
+        assert_ms(Some(turn.at_ms), 1000.0);
+        assert_ms(turn.user_speech_ms, 800.0);
+        assert_ms(turn.thinking_ms, 200.0);
+        assert_ms(turn.response_ms, 700.0);
+        assert_ms(turn.agent_speech_ms, 2000.0);
+        let reported = turn.reported.unwrap();
+        assert_ms(Some(reported.total_ms), 700.0);
+        assert_ms(Some(reported.ttt_ms), 450.0);
+        assert_ms(Some(reported.tts_ms), 250.0);
+    }
+
+    #[test]
+    fn barge_ins_and_the_session_end_interrupt_turns() {
+        let (latency, at) = session();
+        latency.agent_text_at(STARTED_SPEAKING, at(0));
+        latency.agent_text_at(USER_LINE, at(500));
+        latency.agent_binary_at(at(900));
+        // The user talks over the agent
+        latency.agent_text_at(STARTED_SPEAKING, at(1500));
+        latency.agent_text_at(USER_LINE, at(2000));
+        latency.ended();
+
+        let turns = turns(&latency);
+        assert_eq!(turns.len(), 2);
+        assert!(turns.iter().all(|t| t.interrupted));
+        assert_eq!((turns[0].turn, turns[1].turn), (1, 2));
+        assert_ms(turns[0].response_ms, 400.0);
+        assert_eq!(turns[0].agent_speech_ms, None);
+        assert_eq!(turns[1].response_ms, None);
+        assert!(latency.report.lock().unwrap().ended_at.is_some());
+    }
+
+    #[test]
+    fn a_transcript_after_the_answer_starts_a_new_turn() {
+        let (latency, at) = session();
+        // The greeting is not part of a turn
+        latency.agent_binary_at(at(100));
+        latency.agent_text_at(AUDIO_DONE, at(2000));
+        assert!(turns(&latency).is_empty());
+
+        latency.agent_text_at(USER_LINE, at(3000));
+        latency.agent_binary_at(at(3600));
+        // An injected user message, with no UserStartedSpeaking
+        latency.agent_text_at(USER_LINE, at(5000));
+        latency.agent_binary_at(at(5300));
+        latency.agent_text_at(AUDIO_DONE, at(6000));
+
+        let turns = turns(&latency);
+        assert_eq!(turns.len(), 2);
+        assert!(turns[0].interrupted);
+        assert_eq!(turns[0].user_speech_ms, None);
+        assert_ms(turns[0].response_ms, 600.0);
+        assert!(!turns[1].interrupted);
+        assert_ms(turns[1].response_ms, 300.0);
+        assert_ms(turns[1].agent_speech_ms, 700.0);
+
+        let report = latency.report.lock().unwrap().to_json();
+        assert_eq!(report["summary"]["turns"], 2);
+        assert_eq!(report["summary"]["interrupted"], 1);
+        assert_eq!(report["summary"]["response_ms"]["max"], 600.0);
+    }
+}
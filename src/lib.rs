@@ -0,0 +1,88 @@
+// Rust Voice Agent Starter - Backend Library
+//
+// Simple WebSocket proxy to Deepgram's Voice Agent API using Axum.
+// Forwards all messages (JSON and binary) bidirectionally between client and Deepgram.
+//
+// `voice_agent_router` builds the complete set of routes, relative to
+// wherever the router is mounted:
+//
+//   GET  /api/session       - Issue signed session token
+//   POST /api/direct-session - Deepgram grant for direct browser mode
+//   WS   /api/voice-agent   - WebSocket proxy to Deepgram Agent API (auth required)
+//   GET  /api/metadata      - Project metadata from deepgram.toml
+//   GET  /health            - Health check
+//   POST /api/admin/reload  - Reload configuration (admin token required)
+//   GET  /api/usage         - Usage report, JSON or CSV (admin token required)
+//   GET  /api/admin/sessions/{id}/latency - Turn latencies of a session
+//   GET  /metrics           - Prometheus metrics, when [metrics] is enabled
+//   GET  /*                 - Built frontend, when [frontend] is enabled
+//
+// The router can be nested under any prefix or merged into another axum
+// app; the `rust-voice-agent` binary nests it under `server.base_path`.
+//
+// Public API: configuration (`config`), session tokens (`auth`), the proxy
+// session (`proxy`), the agent message model (`protocol`) and a client SDK
+// (`client`). Heavier subsystems sit behind cargo features, enabled by
+// default unless noted:
+//
+//   client          - client SDK for the voice agent endpoint
+//   direct          - short-lived Deepgram grants for direct browser mode
+//   frontend        - static frontend serving
+//   guardrails      - content moderation of conversation lines
+//   metrics         - turn latency analytics and Prometheus histograms
+//   embed-frontend  - compile frontend/dist into the binary (not default)
+//   events          - publish session events to a file or custom sink
+//   nats, redis     - NATS and Redis Streams event sinks (not default)
+//   otel            - OpenTelemetry trace export over OTLP (not default)
+//   recording       - session capture (`[recording]`) and two-sided replay
+//   socks           - SOCKS5 egress proxies for the Deepgram connection
+//   tls             - native HTTPS/WSS listener with certificate reload
+//   vault           - HashiCorp Vault KV secret provider
+//   webhooks        - signed webhooks for session lifecycle events
+
+pub mod auth;
+#[cfg(feature = "client")]
+pub mod client;
+pub mod config;
+#[cfg(feature = "direct")]
+pub mod direct;
+pub mod egress;
+#[cfg(feature = "events")]
+pub mod events;
+#[cfg(feature = "frontend")]
+pub mod frontend;
+#[cfg(feature = "guardrails")]
+pub mod guardrails;
+#[cfg(feature = "metrics")]
+pub mod latency;
+pub mod logging;
+pub mod metadata;
+pub mod pool;
+pub mod protocol;
+pub mod proxy;
+mod ratelimit;
+#[cfg(feature = "recording")]
+pub mod recording;
+pub mod redaction;
+#[cfg(feature = "recording")]
+pub mod replay;
+pub mod resume;
+mod router;
+pub mod secrets;
+pub mod telemetry;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod upstream;
+pub mod usage;
+#[cfg(feature = "webhooks")]
+pub mod webhooks;
+
+pub use auth::{Claims, ClientIdentity, issue_token, validate_token};
+pub use config::{AppConfig, ConfigError, SharedConfig};
+pub use protocol::{AgentMessage, ClientMessage};
+pub use proxy::ProxySession;
+pub use router::voice_agent_router;
+#[cfg(feature = "direct")]
+pub use router::voice_agent_router_with_grants;
+#[cfg(feature = "events")]
+pub use router::voice_agent_router_with_sink;
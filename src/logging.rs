@@ -0,0 +1,58 @@
+// Logging setup with a runtime-adjustable level filter.
+//
+// The console log shows events only, at the configured level. Spans are
+// for the OpenTelemetry collector (`otel` feature), which sees them at INFO
+// regardless of the log level.
+
+#[cfg(feature = "otel")]
+use crate::config::OtelConfig;
+use std::sync::OnceLock;
+use tracing_subscriber::{
+    EnvFilter, Layer, Registry,
+    filter::{FilterExt, filter_fn},
+    fmt,
+    layer::SubscriberExt,
+    reload,
+    util::SubscriberInitExt,
+};
+
+/// Level filter handle, present once `init` has installed our subscriber.
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// Install the global subscriber at the given level.
+pub fn init(level: &str) {
+    let (filter, handle) = reload::Layer::new(EnvFilter::new(level));
+    tracing_subscriber::registry().with(console(filter)).init();
+    let _ = FILTER.set(handle);
+}
+
+/// Console log layer: events passing `filter`, without span context.
+fn console(filter: reload::Layer<EnvFilter, Registry>) -> impl Layer<Registry> {
+    fmt::layer()
+        .with_target(false)
+        .with_filter(filter.and(filter_fn(|meta| meta.is_event())))
+}
+
+/// Like `init`, also exporting spans over OTLP when `otel` enables it.
+#[cfg(feature = "otel")]
+pub fn init_with_otel(level: &str, otel: &OtelConfig) {
+    let (filter, handle) = reload::Layer::new(EnvFilter::new(level));
+    tracing_subscriber::registry()
+        .with(console(filter))
+        .with(
+            crate::telemetry::layer(otel)
+                .with_filter(tracing_subscriber::filter::LevelFilter::INFO),
+        )
+        .init();
+    let _ = FILTER.set(handle);
+}
+
+/// Swap the active level filter (used by config reload). Does nothing when
+/// the embedding application installed its own subscriber.
+pub fn set_level(level: &str) {
+    if let Some(handle) = FILTER.get()
+        && let Err(e) = handle.reload(EnvFilter::new(level))
+    {
+        tracing::error!("Failed to update log level: {}", e);
+    }
+}
@@ -1,530 +1,198 @@
 // Rust Voice Agent Starter - Backend Server
 //
-// Simple WebSocket proxy to Deepgram's Voice Agent API using Axum.
-// Forwards all messages (JSON and binary) bidirectionally between client and Deepgram.
+// Thin binary around the `rust_voice_agent` library: parses the command
+// line, loads configuration, and serves `voice_agent_router` over HTTP or
+// HTTPS, nested under `server.base_path`.
 //
-// Routes:
-//
-//   GET  /api/session       - Issue signed session token
-//   WS   /api/voice-agent   - WebSocket proxy to Deepgram Agent API (auth required)
-//   GET  /api/metadata      - Project metadata from deepgram.toml
-//   GET  /health            - Health check
-
-use axum::{
-    extract::ws::{Message, WebSocket, WebSocketUpgrade},
-    extract::State,
-    http::StatusCode,
-    response::{IntoResponse, Json},
-    routing::get,
-    Router,
-};
-use chrono::Utc;
-use futures_util::{SinkExt, StreamExt};
-use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
-use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
+// Configuration is read from config.toml (or CONFIG_FILE) with environment
+// variable overrides; see config.example.toml. Send SIGHUP to reload
+// profiles, limits, CORS origins and log level without dropping sessions;
+// rotated secrets are picked up the same way every `secrets.refresh_secs`.
+// Run with --help for the command-line interface (token minting, config
+// checks, session replay, a webhook test receiver).
+
+mod cli;
+
+use axum::{Router, response::Redirect, routing::get};
+use rust_voice_agent::config::SharedConfig;
+#[cfg(feature = "frontend")]
+use rust_voice_agent::frontend::Frontend;
+#[cfg(feature = "tls")]
+use rust_voice_agent::tls;
+use rust_voice_agent::{logging, secrets, voice_agent_router};
+use std::net::SocketAddr;
+#[cfg(feature = "tls")]
 use std::sync::Arc;
 use tokio::net::TcpListener;
 use tokio::signal;
-use tokio::sync::Mutex;
-use tokio_tungstenite::{connect_async, tungstenite};
-use tower_http::cors::{Any, CorsLayer};
+use tracing::{error, info, warn};
 
 // ============================================================================
-// CONFIGURATION
+// MAIN
 // ============================================================================
 
-/// Application configuration loaded from environment variables.
-#[derive(Clone)]
-struct AppConfig {
-    deepgram_api_key: String,
-    deepgram_agent_url: String,
-    port: String,
-    host: String,
-    session_secret: Vec<u8>,
-}
-
-impl AppConfig {
-    /// Load configuration from environment variables with sensible defaults.
-    fn from_env() -> Self {
-        // Load .env file if it exists (development convenience)
-        let _ = dotenvy::dotenv();
-
-        let deepgram_api_key = std::env::var("DEEPGRAM_API_KEY").unwrap_or_else(|_| {
-            eprintln!(
-                "ERROR: DEEPGRAM_API_KEY environment variable is required\n\
-                 Please copy sample.env to .env and add your API key"
-            );
-            std::process::exit(1);
-        });
-
-        let session_secret = match std::env::var("SESSION_SECRET") {
-            Ok(s) if !s.is_empty() => s.into_bytes(),
-            _ => {
-                let mut buf = [0u8; 32];
-                use rand::RngCore;
-                rand::thread_rng().fill_bytes(&mut buf);
-                buf.to_vec()
-            }
-        };
-
-        Self {
-            deepgram_api_key,
-            deepgram_agent_url: "wss://agent.deepgram.com/v1/agent/converse".to_string(),
-            port: std::env::var("PORT").unwrap_or_else(|_| "8081".to_string()),
-            host: std::env::var("HOST").unwrap_or_else(|_| "0.0.0.0".to_string()),
-            session_secret,
+#[tokio::main]
+async fn main() {
+    let cli = <cli::Cli as clap::Parser>::parse();
+
+    match cli.command {
+        _ if cli.check_config => cli::config_check(&cli.config_arg()).await,
+        Some(cli::Command::Serve(args)) => serve(args).await,
+        Some(cli::Command::Token(command)) => cli::token(command).await,
+        Some(cli::Command::Config(cli::ConfigCommand::Check { config })) => {
+            cli::config_check(&config).await
         }
-    }
-}
-
-// ============================================================================
-// SESSION AUTH - JWT tokens for production security
-// ============================================================================
-
-/// JWT expiry duration in seconds (1 hour).
-const JWT_EXPIRY_SECS: i64 = 3600;
-
-/// JWT claims structure for session tokens.
-#[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    iat: i64,
-    exp: i64,
-}
-
-/// Creates a signed JWT with a 1-hour expiry.
-fn issue_token(secret: &[u8]) -> Result<String, jsonwebtoken::errors::Error> {
-    let now = Utc::now().timestamp();
-    let claims = Claims {
-        iat: now,
-        exp: now + JWT_EXPIRY_SECS,
-    };
-    encode(
-        &Header::new(Algorithm::HS256),
-        &claims,
-        &EncodingKey::from_secret(secret),
-    )
-}
-
-/// Verifies a JWT token string and returns an error if invalid.
-fn validate_token(token_str: &str, secret: &[u8]) -> Result<(), jsonwebtoken::errors::Error> {
-    let mut validation = Validation::new(Algorithm::HS256);
-    validation.required_spec_claims = std::collections::HashSet::new();
-    validation.validate_exp = true;
-    decode::<Claims>(token_str, &DecodingKey::from_secret(secret), &validation)?;
-    Ok(())
-}
-
-/// Extracts and validates a JWT from the `access_token.<jwt>` subprotocol.
-/// Returns the full subprotocol string if valid, None if invalid.
-fn validate_ws_token(protocols: &[String], secret: &[u8]) -> Option<String> {
-    for proto in protocols {
-        if let Some(token_str) = proto.strip_prefix("access_token.") {
-            if validate_token(token_str, secret).is_ok() {
-                return Some(proto.clone());
-            }
+        #[cfg(feature = "recording")]
+        Some(cli::Command::Replay(args)) => cli::replay(args).await,
+        #[cfg(feature = "webhooks")]
+        Some(cli::Command::Webhook(command)) => cli::webhook(command).await,
+        None => {
+            serve(cli::ServeArgs {
+                config: cli.config,
+                ..Default::default()
+            })
+            .await
         }
     }
-    None
-}
-
-// ============================================================================
-// METADATA - deepgram.toml parser
-// ============================================================================
-
-/// Represents the structure of deepgram.toml for metadata extraction.
-#[derive(serde::Deserialize)]
-struct DeepgramToml {
-    meta: Option<toml::Table>,
-}
-
-// ============================================================================
-// WEBSOCKET HELPERS
-// ============================================================================
-
-/// Reserved WebSocket close codes that cannot be set by applications (RFC 6455).
-const RESERVED_CLOSE_CODES: [u16; 4] = [1004, 1005, 1006, 1015];
-
-/// Return a valid WebSocket close code, translating reserved codes to 1000 (normal closure).
-fn get_safe_close_code(code: u16) -> u16 {
-    if (1000..=4999).contains(&code) && !RESERVED_CLOSE_CODES.contains(&code) {
-        code
-    } else {
-        1000
-    }
 }
 
-// ============================================================================
-// HTTP HANDLERS
-// ============================================================================
-
-/// GET /api/session - Issue a signed JWT session token.
-async fn handle_session(State(config): State<Arc<AppConfig>>) -> impl IntoResponse {
-    match issue_token(&config.session_secret) {
-        Ok(token) => Json(json!({ "token": token })).into_response(),
-        Err(_) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({"error": "Failed to generate session token"})),
-        )
-            .into_response(),
+/// Run the proxy server until SIGINT/SIGTERM.
+async fn serve(args: cli::ServeArgs) {
+    // Load configuration from the config file and environment variables,
+    // then apply command-line overrides
+    let mut config = cli::load_config(&args.config).await;
+    let mut pinned = Vec::new();
+    if let Some(host) = args.host {
+        config.host = host;
+        pinned.push("server.host");
     }
-}
-
-/// GET /api/metadata - Return project metadata from deepgram.toml.
-async fn handle_metadata() -> impl IntoResponse {
-    let contents = match std::fs::read_to_string("deepgram.toml") {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!("Error reading deepgram.toml: {}", e);
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({
-                    "error": "INTERNAL_SERVER_ERROR",
-                    "message": "Failed to read metadata from deepgram.toml"
-                })),
-            )
-                .into_response();
-        }
-    };
-
-    let cfg: DeepgramToml = match toml::from_str(&contents) {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!("Error parsing deepgram.toml: {}", e);
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({
-                    "error": "INTERNAL_SERVER_ERROR",
-                    "message": "Failed to parse deepgram.toml"
-                })),
-            )
-                .into_response();
-        }
-    };
-
-    match cfg.meta {
-        Some(meta) => {
-            let value: Value = toml::Value::Table(meta).try_into().unwrap_or(json!({}));
-            Json(value).into_response()
-        }
-        None => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({
-                "error": "INTERNAL_SERVER_ERROR",
-                "message": "Missing [meta] section in deepgram.toml"
-            })),
-        )
-            .into_response(),
+    if let Some(port) = args.port {
+        config.port = port;
+        pinned.push("server.port");
     }
-}
-
-/// GET /health - Health check endpoint.
-async fn handle_health() -> impl IntoResponse {
-    Json(json!({ "status": "ok" }))
-}
-
-// ============================================================================
-// WEBSOCKET PROXY HANDLER
-// ============================================================================
 
-/// WS /api/voice-agent - Proxy WebSocket connections to Deepgram's Voice Agent API.
-/// Forwards all messages (JSON and binary) bidirectionally without modification.
-async fn handle_voice_agent(
-    State(config): State<Arc<AppConfig>>,
-    headers: axum::http::HeaderMap,
-    ws: WebSocketUpgrade,
-) -> impl IntoResponse {
-    // Extract and validate JWT from access_token.<jwt> subprotocol.
-    let protocols: Vec<String> = headers
-        .get("sec-websocket-protocol")
-        .and_then(|v| v.to_str().ok())
-        .unwrap_or("")
-        .split(',')
-        .map(|s| s.trim().to_string())
-        .filter(|s| !s.is_empty())
-        .collect();
-    let valid_proto = match validate_ws_token(&protocols, &config.session_secret) {
-        Some(proto) => proto,
-        None => {
-            eprintln!("WebSocket auth failed: invalid or missing token");
-            return StatusCode::UNAUTHORIZED.into_response();
-        }
-    };
-
-    // Accept the WebSocket connection, echoing back the validated subprotocol
-    ws.protocols([valid_proto])
-        .on_upgrade(move |socket| handle_voice_agent_socket(socket, config))
-}
-
-/// Handle the upgraded WebSocket connection: connect to Deepgram and proxy messages.
-async fn handle_voice_agent_socket(client_ws: WebSocket, config: Arc<AppConfig>) {
-    println!("Client connected to /api/voice-agent");
-
-    // Connect to Deepgram Voice Agent API
-    // No query parameters needed -- config is sent via JSON after connection
-    println!("Initiating Deepgram connection...");
-
-    let url = match url::Url::parse(&config.deepgram_agent_url) {
-        Ok(u) => u,
-        Err(e) => {
-            eprintln!("Failed to parse Deepgram agent URL: {}", e);
-            return;
-        }
-    };
-
-    let request = match tungstenite::http::Request::builder()
-        .uri(config.deepgram_agent_url.as_str())
-        .header("Host", url.host_str().unwrap_or("agent.deepgram.com"))
-        .header("Authorization", format!("Token {}", config.deepgram_api_key))
-        .header("Connection", "Upgrade")
-        .header("Upgrade", "websocket")
-        .header("Sec-WebSocket-Version", "13")
-        .header(
-            "Sec-WebSocket-Key",
-            tungstenite::handshake::client::generate_key(),
-        )
-        .body(())
+    #[cfg(feature = "otel")]
+    logging::init_with_otel(&config.reloadable.log_level, &config.otel);
+    #[cfg(not(feature = "otel"))]
+    logging::init(&config.reloadable.log_level);
+    if config
+        .reloadable
+        .cors_allowed_origins
+        .iter()
+        .any(|o| o == "*")
     {
-        Ok(r) => r,
-        Err(e) => {
-            eprintln!("Failed to build Deepgram request: {}", e);
-            return;
-        }
-    };
-
-    let (deepgram_ws, _response) = match connect_async(request).await {
-        Ok(conn) => conn,
-        Err(e) => {
-            eprintln!("Failed to connect to Deepgram: {}", e);
-            // Send error message to client before closing
-            let (mut sender, _) = client_ws.split();
-            let err_msg = json!({
-                "type": "Error",
-                "description": "Failed to establish proxy connection",
-                "code": "CONNECTION_FAILED"
-            });
-            let _ = sender
-                .send(Message::Text(err_msg.to_string().into()))
-                .await;
-            let _ = sender.close().await;
-            return;
-        }
-    };
-
-    println!("Connected to Deepgram Agent API");
-
-    // Split both WebSocket connections into sender/receiver halves
-    let (client_sender, client_receiver) = client_ws.split();
-    let (deepgram_sender, deepgram_receiver) = deepgram_ws.split();
-
-    // Wrap senders in Arc<Mutex> for shared access
-    let client_sender = Arc::new(Mutex::new(client_sender));
-    let deepgram_sender = Arc::new(Mutex::new(deepgram_sender));
-
-    // Forward messages: Deepgram -> Client
-    let client_sender_clone = client_sender.clone();
-    let deepgram_to_client = {
-        let mut deepgram_receiver = deepgram_receiver;
-        async move {
-            while let Some(msg) = deepgram_receiver.next().await {
-                match msg {
-                    Ok(tungstenite::Message::Text(text)) => {
-                        let mut sender = client_sender_clone.lock().await;
-                        if sender.send(Message::Text(text.to_string().into())).await.is_err() {
-                            eprintln!("Error forwarding text to client");
-                            break;
-                        }
-                    }
-                    Ok(tungstenite::Message::Binary(data)) => {
-                        let mut sender = client_sender_clone.lock().await;
-                        if sender.send(Message::Binary(data.into())).await.is_err() {
-                            eprintln!("Error forwarding binary to client");
-                            break;
-                        }
-                    }
-                    Ok(tungstenite::Message::Close(frame)) => {
-                        let code = frame
-                            .as_ref()
-                            .map(|f| get_safe_close_code(f.code.into()))
-                            .unwrap_or(1000);
-                        let reason = frame
-                            .as_ref()
-                            .map(|f| f.reason.to_string())
-                            .unwrap_or_default();
-                        if code == 1000 || code == 1001 {
-                            println!("Deepgram connection closed normally");
-                        } else {
-                            eprintln!("Deepgram connection closed: {} {}", code, reason);
-                        }
-                        let mut sender = client_sender_clone.lock().await;
-                        let _ = sender
-                            .send(Message::Close(Some(axum::extract::ws::CloseFrame {
-                                code,
-                                reason: reason.into(),
-                            })))
-                            .await;
-                        break;
-                    }
-                    Ok(tungstenite::Message::Ping(data)) => {
-                        let mut sender = client_sender_clone.lock().await;
-                        let _ = sender.send(Message::Ping(data.into())).await;
-                    }
-                    Ok(tungstenite::Message::Pong(data)) => {
-                        let mut sender = client_sender_clone.lock().await;
-                        let _ = sender.send(Message::Pong(data.into())).await;
-                    }
-                    Ok(tungstenite::Message::Frame(_)) => {
-                        // Raw frames are not forwarded
-                    }
-                    Err(e) => {
-                        eprintln!("Deepgram read error: {}", e);
-                        let mut sender = client_sender_clone.lock().await;
-                        let _ = sender
-                            .send(Message::Close(Some(axum::extract::ws::CloseFrame {
-                                code: 1000,
-                                reason: "".into(),
-                            })))
-                            .await;
-                        break;
-                    }
-                }
-            }
-        }
-    };
-
-    // Forward messages: Client -> Deepgram
-    let deepgram_sender_clone = deepgram_sender.clone();
-    let client_to_deepgram = {
-        let mut client_receiver = client_receiver;
-        async move {
-            while let Some(msg) = client_receiver.next().await {
-                match msg {
-                    Ok(Message::Text(text)) => {
-                        let mut sender = deepgram_sender_clone.lock().await;
-                        if sender
-                            .send(tungstenite::Message::Text(text.to_string().into()))
-                            .await
-                            .is_err()
-                        {
-                            eprintln!("Error forwarding text to Deepgram");
-                            break;
-                        }
-                    }
-                    Ok(Message::Binary(data)) => {
-                        let mut sender = deepgram_sender_clone.lock().await;
-                        if sender
-                            .send(tungstenite::Message::Binary(data.into()))
-                            .await
-                            .is_err()
-                        {
-                            eprintln!("Error forwarding binary to Deepgram");
-                            break;
-                        }
-                    }
-                    Ok(Message::Close(_)) => {
-                        println!("Client disconnected normally");
-                        break;
-                    }
-                    Ok(Message::Ping(data)) => {
-                        let mut sender = deepgram_sender_clone.lock().await;
-                        let _ = sender
-                            .send(tungstenite::Message::Ping(data.into()))
-                            .await;
-                    }
-                    Ok(Message::Pong(data)) => {
-                        let mut sender = deepgram_sender_clone.lock().await;
-                        let _ = sender
-                            .send(tungstenite::Message::Pong(data.into()))
-                            .await;
-                    }
-                    Err(e) => {
-                        eprintln!("Client read error: {}", e);
-                        break;
-                    }
-                }
-            }
-        }
-    };
-
-    // Wait for either side to close, then clean up both
-    tokio::select! {
-        _ = deepgram_to_client => {
-            println!("Deepgram disconnected, closing client connection");
-            let mut sender = client_sender.lock().await;
-            let _ = sender.close().await;
-        }
-        _ = client_to_deepgram => {
-            println!("Client disconnected, closing Deepgram connection");
-            let mut sender = deepgram_sender.lock().await;
-            let _ = sender
-                .send(tungstenite::Message::Close(Some(
-                    tungstenite::protocol::CloseFrame {
-                        code: tungstenite::protocol::frame::coding::CloseCode::Normal,
-                        reason: "Client disconnected".into(),
-                    },
-                )))
-                .await;
-            let _ = sender.close().await;
-        }
+        warn!("cors.allowed_origins allows any origin; restrict it in production");
     }
-}
-
-// ============================================================================
-// MAIN
-// ============================================================================
+    if let Some(skipped) = &config.egress.skipped_env_proxy {
+        warn!("Not using the proxy from the environment: {}", skipped);
+    }
+    let config = SharedConfig::new(config).with_pinned(pinned);
 
-#[tokio::main]
-async fn main() {
-    // Load configuration from environment variables
-    let config = Arc::new(AppConfig::from_env());
+    #[cfg(unix)]
+    tokio::spawn(reload_on_sighup(config.clone()));
+    tokio::spawn(secrets::refresh(config.clone()));
 
-    // Configure CORS for development
-    let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods(Any)
-        .allow_headers(Any);
+    let startup = config.current();
+    let router = voice_agent_router(config.clone());
+    let app = if startup.base_path.is_empty() {
+        router
+    } else {
+        // Nested routers do not match the prefix with a trailing slash
+        let base = startup.base_path.clone();
+        Router::new()
+            .route(
+                &format!("{}/", base),
+                get(move || async move { Redirect::permanent(&base) }),
+            )
+            .nest(&startup.base_path, router)
+    };
 
-    // Build the Axum router
-    let app = Router::new()
-        .route("/api/session", get(handle_session))
-        .route("/api/metadata", get(handle_metadata))
-        .route("/api/voice-agent", get(handle_voice_agent))
-        .route("/health", get(handle_health))
-        .layer(cors)
-        .with_state(config.clone());
+    #[cfg(feature = "tls")]
+    let certs = startup.tls.clone().map(|tls| {
+        let store = tls::CertStore::load(tls).unwrap_or_else(|e| {
+            eprintln!("ERROR: failed to load TLS certificates: {}", e);
+            std::process::exit(1);
+        });
+        Arc::new(store)
+    });
+    #[cfg(feature = "tls")]
+    let scheme = if certs.is_some() { "https" } else { "http" };
+    #[cfg(not(feature = "tls"))]
+    let scheme = "http";
 
-    let addr = format!("{}:{}", config.host, config.port);
+    let addr = format!("{}:{}", startup.host, startup.port);
     let listener = TcpListener::bind(&addr).await.unwrap_or_else(|e| {
         eprintln!("Failed to bind to {}: {}", addr, e);
         std::process::exit(1);
     });
 
     // Print startup banner
+    let base = &startup.base_path;
     let separator = "=".repeat(70);
     println!("{}", separator);
     println!(
-        "Backend API Server running at http://localhost:{}",
-        config.port
+        "Backend API Server running at {}://localhost:{}{}",
+        scheme, startup.port, base
     );
     println!();
-    println!("GET  /api/session");
-    println!("WS   /api/voice-agent (auth required)");
-    println!("GET  /api/metadata");
-    println!("GET  /health");
+    println!("GET  {}/api/session", base);
+    println!("WS   {}/api/voice-agent (auth required)", base);
+    println!("GET  {}/api/metadata", base);
+    println!("GET  {}/health", base);
+    if startup.admin_token.is_some() {
+        println!("POST {}/api/admin/reload (admin token required)", base);
+    }
+    #[cfg(feature = "frontend")]
+    if startup.frontend.enabled {
+        let frontend = Frontend::new(&startup.frontend, base);
+        println!("GET  {}/* (frontend from {})", base, frontend.describe());
+    }
     println!("{}", separator);
 
     // Start server with graceful shutdown
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await
-        .unwrap_or_else(|e| {
-            eprintln!("Server error: {}", e);
-            std::process::exit(1);
-        });
+    #[cfg(feature = "tls")]
+    if let Some(certs) = certs {
+        tokio::spawn(certs.clone().watch());
+        tls::serve(listener, app, certs, shutdown_signal()).await;
+        #[cfg(feature = "otel")]
+        rust_voice_agent::telemetry::shutdown();
+        println!("Shutdown complete");
+        return;
+    }
+
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await
+    .unwrap_or_else(|e| {
+        eprintln!("Server error: {}", e);
+        std::process::exit(1);
+    });
 
+    #[cfg(feature = "otel")]
+    rust_voice_agent::telemetry::shutdown();
     println!("Shutdown complete");
 }
 
+/// Reload configuration whenever SIGHUP is received.
+#[cfg(unix)]
+async fn reload_on_sighup(config: SharedConfig) {
+    let mut hangup = match signal::unix::signal(signal::unix::SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to install SIGHUP handler: {}", e);
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        info!("SIGHUP received: reloading configuration");
+        let _ = config.reload();
+    }
+}
+
 /// Wait for SIGINT or SIGTERM to trigger graceful shutdown.
 async fn shutdown_signal() {
     let ctrl_c = async {
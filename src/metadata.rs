@@ -0,0 +1,42 @@
+// Project metadata from deepgram.toml.
+
+use serde_json::{Value, json};
+use std::fmt;
+use std::path::Path;
+
+/// Default location of the metadata file, relative to the working directory.
+pub const METADATA_FILE: &str = "deepgram.toml";
+
+/// Represents the structure of deepgram.toml for metadata extraction.
+#[derive(serde::Deserialize)]
+struct DeepgramToml {
+    meta: Option<toml::Table>,
+}
+
+/// Why metadata could not be produced.
+#[derive(Debug)]
+pub enum MetadataError {
+    Read(std::io::Error),
+    Parse(toml::de::Error),
+    MissingMeta,
+}
+
+impl fmt::Display for MetadataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetadataError::Read(e) => write!(f, "cannot read {}: {}", METADATA_FILE, e),
+            MetadataError::Parse(e) => write!(f, "cannot parse {}: {}", METADATA_FILE, e),
+            MetadataError::MissingMeta => write!(f, "missing [meta] section in {}", METADATA_FILE),
+        }
+    }
+}
+
+impl std::error::Error for MetadataError {}
+
+/// Read the `[meta]` table of a deepgram.toml file as JSON.
+pub fn read_metadata(path: &Path) -> Result<Value, MetadataError> {
+    let contents = std::fs::read_to_string(path).map_err(MetadataError::Read)?;
+    let cfg: DeepgramToml = toml::from_str(&contents).map_err(MetadataError::Parse)?;
+    let meta = cfg.meta.ok_or(MetadataError::MissingMeta)?;
+    Ok(toml::Value::Table(meta).try_into().unwrap_or(json!({})))
+}
@@ -0,0 +1,638 @@
+// Pre-warmed upstream connections.
+//
+// With `[pool] size` above zero, connections to Deepgram are opened ahead of
+// sessions, so a new session can forward the client's Settings at once
+// instead of waiting for the TCP, TLS and WebSocket handshakes. Connections
+// are pooled per tenant and set of endpoints (with the profile's query
+// parameters and headers): the first session of each connects on demand and
+// starts filling its pool, which is then kept at `size` in the background
+// until it has had no session for `idle_secs`, when its connections are
+// closed and the pool dropped. No more than `max_total` connections are
+// pooled in all.
+//
+// While pooled, each connection is read by a task that answers Deepgram's
+// pings and keeps the frames that arrive (its Welcome message) for the
+// session that takes it. Connections that close, or reach `max_age_secs`,
+// leave the pool and are replaced. Once a session of a tenant connects with
+// another API key (after a secrets refresh or reload) or egress settings,
+// the tenant's pools opened with the previous ones are closed.
+
+use crate::config::{EgressConfig, PoolConfig, Tenant};
+use crate::proxy::{UpstreamSocket, connect_upstream_via};
+use crate::upstream::Upstream;
+use futures_util::StreamExt;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, warn};
+
+/// Frames a pooled connection may receive before it is given up on.
+const MAX_HELD_FRAMES: usize = 16;
+
+/// How often pools are checked for having gone idle.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// An upstream connection for a session, with the frames it received while
+/// pooled.
+pub struct Connection {
+    pub socket: UpstreamSocket,
+    pub received: Vec<Message>,
+    /// Whether the connection came from the pool.
+    pub pre_warmed: bool,
+}
+
+impl Connection {
+    /// A connection opened for the session itself.
+    pub fn new(socket: UpstreamSocket) -> Self {
+        Self {
+            socket,
+            received: Vec::new(),
+            pre_warmed: false,
+        }
+    }
+}
+
+/// Sent to a pooled connection's task to have it hand the connection over.
+type Handover = oneshot::Sender<Connection>;
+
+/// A pooled connection, held by its task.
+struct Warm {
+    id: u64,
+    take: oneshot::Sender<Handover>,
+}
+
+/// What a pool's connections are opened to.
+struct Target {
+    tenant: Tenant,
+    upstream: Upstream,
+    egress: EgressConfig,
+}
+
+/// The pool of one tenant and set of endpoints.
+struct Slot {
+    /// As of the latest session.
+    target: Arc<Target>,
+    config: PoolConfig,
+    idle: VecDeque<Warm>,
+    /// Connections being opened.
+    opening: usize,
+    /// `credentials` of the tenant and egress settings the connections were
+    /// opened with.
+    credentials: u64,
+    last_used: Instant,
+}
+
+impl Slot {
+    /// Whether the pool has had no session for `idle_secs`.
+    fn is_idle(&self) -> bool {
+        self.last_used.elapsed() > Duration::from_secs(self.config.idle_secs)
+    }
+
+    /// Connections pooled or being opened.
+    fn len(&self) -> usize {
+        self.idle.len() + self.opening
+    }
+}
+
+type Slots = Mutex<HashMap<String, Slot>>;
+
+/// Pre-warmed upstream connections, shared by all sessions.
+#[derive(Clone, Default)]
+pub struct Pool {
+    slots: Arc<Slots>,
+    next_id: Arc<AtomicU64>,
+    sweeping: Arc<AtomicBool>,
+}
+
+impl Pool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn slots(&self) -> std::sync::MutexGuard<'_, HashMap<String, Slot>> {
+        lock(&self.slots)
+    }
+
+    /// A connection for a session of `tenant` to `upstream`: a pooled one
+    /// when there is one, else a new one. With `[pool]` enabled, the pool is
+    /// refilled in the background either way.
+    pub async fn connect(
+        &self,
+        pool: &PoolConfig,
+        egress: &EgressConfig,
+        tenant: &Tenant,
+        upstream: &Upstream,
+    ) -> Result<Connection, String> {
+        if pool.size == 0 {
+            // Turned off by a reload: close what is pooled
+            self.slots().clear();
+            return connect_upstream_via(tenant, upstream, egress)
+                .await
+                .map(Connection::new);
+        }
+
+        self.start_sweeping();
+        let credentials = credentials(tenant, egress);
+        let key = pool_key(tenant, upstream, credentials);
+        let taken = self.take(&key).await;
+        {
+            let mut slots = self.slots();
+            // Connections opened with a replaced API key or egress settings
+            // are never handed out
+            slots.retain(|_, slot| {
+                slot.target.tenant.id != tenant.id || slot.credentials == credentials
+            });
+            let target = Arc::new(Target {
+                tenant: tenant.clone(),
+                upstream: upstream.clone(),
+                egress: egress.clone(),
+            });
+            let slot = slots.entry(key.clone()).or_insert_with(|| Slot {
+                target: target.clone(),
+                config: pool.clone(),
+                idle: VecDeque::new(),
+                opening: 0,
+                credentials,
+                last_used: Instant::now(),
+            });
+            slot.target = target;
+            slot.config = pool.clone();
+            slot.last_used = Instant::now();
+        }
+        self.refill(&key);
+
+        match taken {
+            Some(connection) => Ok(connection),
+            None => connect_upstream_via(tenant, upstream, egress)
+                .await
+                .map(Connection::new),
+        }
+    }
+
+    /// Take a live pooled connection of `key`, if there is one.
+    async fn take(&self, key: &str) -> Option<Connection> {
+        loop {
+            let warm = {
+                let mut slots = self.slots();
+                slots.get_mut(key)?.idle.pop_front()?
+            };
+            let (handover, connection) = oneshot::channel();
+            if warm.take.send(handover).is_ok()
+                && let Ok(connection) = connection.await
+            {
+                return Some(connection);
+            }
+            // It closed or expired meanwhile; try the next one
+        }
+    }
+
+    /// Drop the pools that have gone idle, closing their connections, once
+    /// a second for as long as the pool is in use.
+    fn start_sweeping(&self) {
+        if self.sweeping.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let slots = Arc::downgrade(&self.slots);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                if !sweep(&slots) {
+                    return;
+                }
+            }
+        });
+    }
+
+    /// Open connections until the pool of `key` is full again, unless it
+    /// has gone idle or `max_total` connections are pooled.
+    fn refill(&self, key: &str) {
+        let (target, max_age, missing) = {
+            let mut slots = self.slots();
+            let total: usize = slots.values().map(Slot::len).sum();
+            let Some(slot) = slots.get_mut(key) else {
+                return;
+            };
+            if slot.is_idle() {
+                return;
+            }
+            let missing = slot
+                .config
+                .size
+                .saturating_sub(slot.len())
+                .min(slot.config.max_total.saturating_sub(total));
+            slot.opening += missing;
+            (
+                slot.target.clone(),
+                Duration::from_secs(slot.config.max_age_secs),
+                missing,
+            )
+        };
+        for _ in 0..missing {
+            let pool = self.clone();
+            let key = key.to_string();
+            let target = target.clone();
+            tokio::spawn(async move { pool.open(key, target, max_age).await });
+        }
+    }
+
+    /// Open one connection for the pool of `key` and hold it there.
+    async fn open(self, key: String, target: Arc<Target>, max_age: Duration) {
+        let result = connect_upstream_via(&target.tenant, &target.upstream, &target.egress).await;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (take, taken) = oneshot::channel();
+        {
+            let mut slots = self.slots();
+            if let Some(slot) = slots.get_mut(&key) {
+                slot.opening = slot.opening.saturating_sub(1);
+                if let Err(e) = &result {
+                    warn!("Pre-warming a Deepgram connection failed: {}", e);
+                    return;
+                }
+                slot.idle.push_back(Warm { id, take });
+            } else {
+                // The pool went idle meanwhile: `hold` closes the connection
+                drop(take);
+            }
+        }
+        if let Ok(socket) = result {
+            debug!(
+                "Pre-warmed a Deepgram connection for tenant {}",
+                target.tenant.id
+            );
+            self.hold(key, id, socket, taken, max_age).await;
+        }
+    }
+
+    /// Keep a pooled connection alive until a session takes it, it closes or
+    /// it reaches `max_age`; in the latter cases, replace it.
+    async fn hold(
+        self,
+        key: String,
+        id: u64,
+        mut socket: UpstreamSocket,
+        mut taken: oneshot::Receiver<Handover>,
+        max_age: Duration,
+    ) {
+        let mut received = Vec::new();
+        let expiry = tokio::time::sleep(max_age);
+        tokio::pin!(expiry);
+        loop {
+            tokio::select! {
+                handover = &mut taken => {
+                    match handover {
+                        Ok(handover) => {
+                            let _ = handover.send(Connection {
+                                socket,
+                                received,
+                                pre_warmed: true,
+                            });
+                        }
+                        // The pool went idle and was dropped
+                        Err(_) => {
+                            let _ = socket.close(None).await;
+                        }
+                    }
+                    return;
+                }
+                frame = socket.next() => match frame {
+                    Some(Ok(Message::Text(_) | Message::Binary(_)))
+                        if received.len() >= MAX_HELD_FRAMES => break,
+                    Some(Ok(frame @ (Message::Text(_) | Message::Binary(_)))) => received.push(frame),
+                    Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                    // Pings are answered as they are read
+                    Some(Ok(_)) => {}
+                },
+                _ = &mut expiry => {
+                    let _ = socket.close(None).await;
+                    break;
+                }
+            }
+        }
+        if let Some(slot) = self.slots().get_mut(&key) {
+            slot.idle.retain(|warm| warm.id != id);
+        }
+        self.refill(&key);
+    }
+}
+
+fn lock(slots: &Slots) -> std::sync::MutexGuard<'_, HashMap<String, Slot>> {
+    slots.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Drop the pools that have gone idle; their connections' tasks then close
+/// them. False once the pool itself is gone.
+fn sweep(slots: &Weak<Slots>) -> bool {
+    let Some(slots) = slots.upgrade() else {
+        return false;
+    };
+    lock(&slots).retain(|key, slot| {
+        let idle = slot.is_idle();
+        if idle {
+            debug!(
+                "Closing {} pooled Deepgram connection(s) of idle pool {}",
+                slot.idle.len(),
+                key
+            );
+        }
+        !idle
+    });
+    true
+}
+
+/// Sessions share pooled connections when they have the same tenant, API
+/// key, egress settings, endpoints, query parameters and headers.
+fn pool_key(tenant: &Tenant, upstream: &Upstream, credentials: u64) -> String {
+    let mut endpoints = upstream.endpoints(tenant);
+    endpoints.sort_unstable();
+    format!(
+        "{}|{:016x}|{}|{:?}|{:?}",
+        tenant.id,
+        credentials,
+        endpoints.join(" "),
+        upstream.query,
+        upstream.headers
+    )
+}
+
+/// Fingerprint of what a connection of `tenant` is opened with besides its
+/// endpoint: the API key and the egress settings. Keeps the key itself out
+/// of pool keys, which are logged.
+fn credentials(tenant: &Tenant, egress: &EgressConfig) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    tenant.api_key.expose().hash(&mut hasher);
+    if let Some(proxy) = &egress.proxy {
+        proxy.to_string().hash(&mut hasher);
+        if let Some((_, password)) = &proxy.credentials {
+            password.expose().hash(&mut hasher);
+        }
+    }
+    egress.no_proxy.hash(&mut hasher);
+    egress.ca_file.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secrets::Secret;
+    use futures_util::SinkExt;
+    use std::collections::BTreeMap;
+    use std::sync::atomic::AtomicUsize;
+    use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+
+    /// A stand-in for the agent API that greets each connection and keeps
+    /// it open; counts the connections it accepted and saw close.
+    struct FakeAgent {
+        url: String,
+        accepted: Arc<AtomicUsize>,
+        closed: Arc<AtomicUsize>,
+        keys: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl FakeAgent {
+        async fn start() -> Self {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let agent = Self {
+                url: format!("ws://{}/v1/agent/converse", listener.local_addr().unwrap()),
+                accepted: Arc::default(),
+                closed: Arc::default(),
+                keys: Arc::default(),
+            };
+            let (accepted, closed, keys) = (
+                agent.accepted.clone(),
+                agent.closed.clone(),
+                agent.keys.clone(),
+            );
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let (accepted, closed, keys) = (accepted.clone(), closed.clone(), keys.clone());
+                    tokio::spawn(async move {
+                        // The callback type is tungstenite's
+                        #[allow(clippy::result_large_err)]
+                        let record = |request: &Request, response: Response| {
+                            let key = request.headers().get("authorization").unwrap();
+                            keys.lock().unwrap().push(key.to_str().unwrap().to_string());
+                            Ok(response)
+                        };
+                        let Ok(mut ws) = tokio_tungstenite::accept_hdr_async(stream, record).await
+                        else {
+                            return;
+                        };
+                        accepted.fetch_add(1, Ordering::SeqCst);
+                        let _ = ws.send(Message::Text(r#"{"type":"Welcome"}"#.into())).await;
+                        while let Some(Ok(_)) = ws.next().await {}
+                        closed.fetch_add(1, Ordering::SeqCst);
+                    });
+                }
+            });
+            agent
+        }
+
+        fn tenant(&self, id: &str, api_key: &str) -> Tenant {
+            Tenant {
+                id: id.to_string(),
+                api_key: Secret::new(api_key),
+                agent_url: self.url.clone(),
+                profiles: BTreeMap::new(),
+                max_sessions: 0,
+                max_session_secs: None,
+                namespace: String::new(),
+                client_subjects: Vec::new(),
+            }
+        }
+
+        fn accepted(&self) -> usize {
+            self.accepted.load(Ordering::SeqCst)
+        }
+
+        fn closed(&self) -> usize {
+            self.closed.load(Ordering::SeqCst)
+        }
+    }
+
+    fn pool_config(size: usize, max_total: usize) -> PoolConfig {
+        PoolConfig {
+            size,
+            max_total,
+            max_age_secs: 60,
+            idle_secs: 600,
+        }
+    }
+
+    /// Wait up to five seconds for `condition`.
+    async fn eventually(condition: impl Fn() -> bool) {
+        for _ in 0..500 {
+            if condition() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("condition not met in time");
+    }
+
+    fn pooled(pool: &Pool) -> usize {
+        pool.slots().values().map(|slot| slot.idle.len()).sum()
+    }
+
+    #[tokio::test]
+    async fn the_first_session_connects_on_demand_and_fills_the_pool() {
+        let agent = FakeAgent::start().await;
+        let tenant = agent.tenant("acme", "acme-key");
+        let (config, egress) = (pool_config(2, 8), EgressConfig::default());
+        let pool = Pool::new();
+
+        let first = pool
+            .connect(&config, &egress, &tenant, &Upstream::default())
+            .await;
+        assert!(!first.unwrap().pre_warmed);
+        eventually(|| pooled(&pool) == 2).await;
+        assert_eq!(agent.accepted(), 3);
+
+        let second = pool
+            .connect(&config, &egress, &tenant, &Upstream::default())
+            .await;
+        let second = second.unwrap();
+        assert!(second.pre_warmed);
+        assert_eq!(
+            second.received,
+            [Message::Text(r#"{"type":"Welcome"}"#.into())]
+        );
+        // Taken connections are replaced
+        eventually(|| pooled(&pool) == 2).await;
+        assert_eq!(agent.accepted(), 4);
+    }
+
+    #[tokio::test]
+    async fn no_more_than_max_total_connections_are_pooled() {
+        let agent = FakeAgent::start().await;
+        let (config, egress) = (pool_config(2, 3), EgressConfig::default());
+        let pool = Pool::new();
+
+        let acme = agent.tenant("acme", "acme-key");
+        let _session = pool
+            .connect(&config, &egress, &acme, &Upstream::default())
+            .await;
+        eventually(|| pooled(&pool) == 2).await;
+        let globex = agent.tenant("globex", "globex-key");
+        let _session = pool
+            .connect(&config, &egress, &globex, &Upstream::default())
+            .await;
+        eventually(|| pooled(&pool) == 3).await;
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(pooled(&pool), 3);
+        // Two sessions and three pooled connections
+        assert_eq!(agent.accepted(), 5);
+        let slots = pool.slots();
+        let globex_pooled = slots
+            .values()
+            .find(|slot| slot.target.tenant.id == "globex");
+        assert_eq!(globex_pooled.unwrap().idle.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn expired_connections_are_closed_and_replaced() {
+        let agent = FakeAgent::start().await;
+        let tenant = agent.tenant("acme", "acme-key");
+        let config = PoolConfig {
+            max_age_secs: 1,
+            ..pool_config(1, 8)
+        };
+        let pool = Pool::new();
+
+        let _session = pool
+            .connect(
+                &config,
+                &EgressConfig::default(),
+                &tenant,
+                &Upstream::default(),
+            )
+            .await;
+        eventually(|| agent.accepted() == 2).await;
+        eventually(|| agent.closed() == 1 && agent.accepted() == 3).await;
+        assert_eq!(pooled(&pool), 1);
+    }
+
+    #[tokio::test]
+    async fn idle_pools_are_closed_and_dropped() {
+        let agent = FakeAgent::start().await;
+        let tenant = agent.tenant("acme", "acme-key");
+        let config = PoolConfig {
+            idle_secs: 1,
+            ..pool_config(2, 8)
+        };
+        let pool = Pool::new();
+
+        let session = pool
+            .connect(
+                &config,
+                &EgressConfig::default(),
+                &tenant,
+                &Upstream::default(),
+            )
+            .await;
+        eventually(|| pooled(&pool) == 2).await;
+        eventually(|| pool.slots().is_empty()).await;
+        eventually(|| agent.closed() == 2).await;
+        // The session's own connection is not the pool's to close
+        assert_eq!(agent.accepted(), 3);
+        drop(session);
+    }
+
+    #[tokio::test]
+    async fn a_new_api_key_replaces_the_tenants_pool() {
+        let agent = FakeAgent::start().await;
+        let (config, egress) = (pool_config(1, 8), EgressConfig::default());
+        let pool = Pool::new();
+
+        let old = agent.tenant("acme", "revoked-key");
+        let _session = pool
+            .connect(&config, &egress, &old, &Upstream::default())
+            .await;
+        eventually(|| pooled(&pool) == 1).await;
+
+        let new = agent.tenant("acme", "rotated-key");
+        let session = pool
+            .connect(&config, &egress, &new, &Upstream::default())
+            .await;
+        assert!(!session.unwrap().pre_warmed);
+        eventually(|| agent.closed() >= 1 && pooled(&pool) == 1).await;
+        assert_eq!(pool.slots().len(), 1);
+        assert!(!pool.slots().keys().next().unwrap().contains("rotated-key"));
+        assert_eq!(
+            *agent.keys.lock().unwrap(),
+            [
+                "Token revoked-key",
+                "Token revoked-key",
+                "Token rotated-key",
+                "Token rotated-key"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_disabled_pool_connects_on_demand() {
+        let agent = FakeAgent::start().await;
+        let tenant = agent.tenant("acme", "acme-key");
+        let egress = EgressConfig::default();
+        let pool = Pool::new();
+
+        let _session = pool
+            .connect(&pool_config(1, 8), &egress, &tenant, &Upstream::default())
+            .await;
+        eventually(|| pooled(&pool) == 1).await;
+        // A reload set the size to 0
+        let session = pool
+            .connect(&pool_config(0, 8), &egress, &tenant, &Upstream::default())
+            .await;
+        assert!(!session.unwrap().pre_warmed);
+        assert!(pool.slots().is_empty());
+        eventually(|| agent.closed() == 1).await;
+    }
+}
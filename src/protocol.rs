@@ -0,0 +1,166 @@
+// Voice Agent API message model.
+//
+// Typed views of the JSON messages exchanged over the agent WebSocket. The
+// proxy itself forwards frames untouched; these types are for code that
+// needs to inspect or produce messages. Unknown message types deserialize
+// to `Unknown` so newer API versions do not break parsing.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// Messages sent by the client to the agent.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[non_exhaustive]
+pub enum ClientMessage {
+    /// Session configuration; must be the first message. The body is kept
+    /// as raw JSON since it mirrors the full agent configuration schema.
+    Settings {
+        #[serde(flatten)]
+        settings: Map<String, Value>,
+    },
+    /// Replace the agent's system prompt mid-session.
+    UpdatePrompt {
+        prompt: String,
+    },
+    /// Switch the text-to-speech model mid-session.
+    UpdateSpeak {
+        speak: Value,
+    },
+    /// Make the agent say `message` immediately.
+    InjectAgentMessage {
+        message: String,
+    },
+    /// Send text as if the user had spoken it.
+    InjectUserMessage {
+        content: String,
+    },
+    /// Result of a client-side function call.
+    FunctionCallResponse {
+        id: String,
+        name: String,
+        content: String,
+    },
+    KeepAlive,
+    #[serde(other)]
+    Unknown,
+}
+
+/// Messages sent by the agent to the client.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[non_exhaustive]
+pub enum AgentMessage {
+    Welcome {
+        #[serde(default)]
+        request_id: String,
+    },
+    SettingsApplied,
+    /// A finalized line of the conversation transcript.
+    ConversationText {
+        role: Role,
+        content: String,
+    },
+    UserStartedSpeaking,
+    AgentThinking {
+        #[serde(default)]
+        content: String,
+    },
+    /// The agent wants one or more functions called.
+    FunctionCallRequest {
+        functions: Vec<FunctionCall>,
+    },
+    /// Result of a server-side function call, run by Deepgram.
+    FunctionCallResponse {
+        #[serde(default)]
+        id: String,
+        #[serde(default)]
+        name: String,
+        #[serde(default)]
+        content: String,
+    },
+    /// The agent began speaking; latencies are in seconds.
+    AgentStartedSpeaking {
+        #[serde(default)]
+        total_latency: f64,
+        #[serde(default)]
+        tts_latency: f64,
+        #[serde(default)]
+        ttt_latency: f64,
+    },
+    AgentAudioDone,
+    PromptUpdated,
+    SpeakUpdated,
+    InjectionRefused {
+        #[serde(default)]
+        message: String,
+    },
+    Error {
+        #[serde(default)]
+        description: String,
+        #[serde(default)]
+        code: String,
+    },
+    Warning {
+        #[serde(default)]
+        description: String,
+        #[serde(default)]
+        code: String,
+    },
+    /// Sent by the proxy, not Deepgram, when `[resume]` is enabled: a client
+    /// whose connection drops can reconnect with the `resume.<resume_token>`
+    /// subprotocol within `grace_secs` to continue the session.
+    SessionResumable {
+        resume_token: String,
+        grace_secs: u64,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+/// Speaker of a `ConversationText` line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Assistant,
+    #[serde(other)]
+    Other,
+}
+
+/// One function invocation requested by the agent.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FunctionCall {
+    pub id: String,
+    pub name: String,
+    /// JSON-encoded arguments, as produced by the model.
+    #[serde(default)]
+    pub arguments: String,
+    /// Whether the client (rather than Deepgram) is expected to run it.
+    #[serde(default)]
+    pub client_side: bool,
+}
+
+impl ClientMessage {
+    /// Parse a text frame; `None` if it is not a JSON message with a `type`.
+    pub fn parse(text: &str) -> Option<Self> {
+        serde_json::from_str(text).ok()
+    }
+
+    /// Encode as a text frame.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+impl AgentMessage {
+    /// Parse a text frame; `None` if it is not a JSON message with a `type`.
+    pub fn parse(text: &str) -> Option<Self> {
+        serde_json::from_str(text).ok()
+    }
+
+    /// Encode as a text frame.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
@@ -0,0 +1,1288 @@
+// Proxy sessions: a client WebSocket bridged to a Deepgram agent connection,
+// opened to the tenant's agent URL or the profile's endpoints (`upstream`).
+//
+// Frames are forwarded in both directions without modification, except that
+// a profile's settings are merged into the client's `Settings` message.
+// Frames are also handed, as received (client frames before the profile
+// merge), to whichever observers are attached: the session recorder,
+// webhooks, the event bus, usage accounting and latency analytics. When `[redaction]` is
+// enabled, observers and debug logs only ever see redacted frames; usage
+// accounting alone reads client text as forwarded, to see the audio formats
+// the agent was configured with.
+//
+// Guardrails, when attached, inspect the (redacted) conversation lines from
+// the agent; the proxy withholds what they block and acts on violations.
+//
+// Resumable sessions (`resume`) outlive a client connection that drops
+// without a close frame: the client leg keeps the agent's latest frames and
+// the session waits out the grace window for the client to reconnect.
+
+#[cfg(feature = "guardrails")]
+use crate::config::GuardrailAction;
+use crate::config::{AppConfig, EgressConfig, ResumeConfig, Tenant, merge_json};
+use crate::egress;
+#[cfg(feature = "events")]
+use crate::events::SessionEvents;
+#[cfg(feature = "guardrails")]
+use crate::guardrails::SessionGuardrails;
+#[cfg(feature = "metrics")]
+use crate::latency::SessionLatency;
+use crate::pool::{Connection, Pool};
+use crate::protocol::{AgentMessage, ClientMessage};
+#[cfg(feature = "recording")]
+use crate::recording::{Direction, Payload, SessionRecorder};
+use crate::redaction::Redactor;
+use crate::resume::{Reconnect, Resumable};
+use crate::telemetry::SessionSpans;
+use crate::upstream::Upstream;
+use crate::usage::SessionUsage;
+#[cfg(feature = "webhooks")]
+use crate::webhooks::SessionHooks;
+use axum::extract::ws::{Message, WebSocket};
+use futures_util::stream::SplitSink;
+use futures_util::{FutureExt, SinkExt, StreamExt};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::{Mutex, Notify};
+use tokio::time::Instant;
+use tokio_tungstenite::{
+    MaybeTlsStream, WebSocketStream, client_async_tls_with_config, tungstenite,
+};
+use tracing::{Instrument, debug, error, info, info_span, warn};
+use zeroize::Zeroizing;
+
+/// WebSocket connection to the upstream agent endpoint.
+pub type UpstreamSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+// ============================================================================
+// WEBSOCKET HELPERS
+// ============================================================================
+
+/// Reserved WebSocket close codes that cannot be set by applications (RFC 6455).
+const RESERVED_CLOSE_CODES: [u16; 4] = [1004, 1005, 1006, 1015];
+
+/// Connect timeout for an agent endpoint when another region remains to try.
+const FAILOVER_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Interval of the KeepAlive messages that hold the agent connection open
+/// while a resumable session's client is away and sends no audio.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Error and warning code sent to clients when a guardrail is violated.
+#[cfg(feature = "guardrails")]
+const GUARDRAIL_VIOLATION: &str = "GUARDRAIL_VIOLATION";
+
+/// Return a valid WebSocket close code, translating reserved codes to 1000 (normal closure).
+fn get_safe_close_code(code: u16) -> u16 {
+    if (1000..=4999).contains(&code) && !RESERVED_CLOSE_CODES.contains(&code) {
+        code
+    } else {
+        1000
+    }
+}
+
+// ============================================================================
+// UPSTREAM CONNECTION
+// ============================================================================
+
+/// Open a direct connection to the tenant's agent URL, authenticated with
+/// the tenant's API key.
+pub async fn connect_upstream(tenant: &Tenant) -> Result<UpstreamSocket, String> {
+    connect_upstream_via(tenant, &Upstream::default(), &EgressConfig::default()).await
+}
+
+/// Like `connect_upstream`, but to the endpoints of `upstream`, tried in
+/// order until one connects, with its query parameters and headers, and
+/// through the `egress` proxy.
+pub async fn connect_upstream_via(
+    tenant: &Tenant,
+    upstream: &Upstream,
+    egress: &EgressConfig,
+) -> Result<UpstreamSocket, String> {
+    let endpoints = upstream.endpoints(tenant);
+    let mut last_error = String::new();
+    for (i, endpoint) in endpoints.iter().enumerate() {
+        let remaining = endpoints.len() - i - 1;
+        match connect_endpoint(tenant, upstream, egress, endpoint, remaining > 0).await {
+            Ok(ws) => return Ok(ws),
+            Err(e) => {
+                upstream.failed(endpoint);
+                if remaining > 0 {
+                    warn!("{} ({}), trying the next region", e, endpoint);
+                }
+                last_error = e;
+            }
+        }
+    }
+    Err(last_error)
+}
+
+/// Connect to one agent endpoint. With `failover`, give up after
+/// `FAILOVER_CONNECT_TIMEOUT` so the next endpoint gets its turn.
+async fn connect_endpoint(
+    tenant: &Tenant,
+    upstream: &Upstream,
+    egress: &EgressConfig,
+    endpoint: &str,
+    failover: bool,
+) -> Result<UpstreamSocket, String> {
+    // Agent config is sent via JSON after connection; query parameters
+    // only carry what the profile adds
+    let url = upstream.url(endpoint)?;
+
+    let mut request = tungstenite::http::Request::builder()
+        .uri(url.as_str())
+        .header("Host", url.host_str().unwrap_or("agent.deepgram.com"))
+        .header(
+            "Authorization",
+            Zeroizing::new(format!("Token {}", tenant.api_key.expose())).as_str(),
+        )
+        .header("Connection", "Upgrade")
+        .header("Upgrade", "websocket")
+        .header("Sec-WebSocket-Version", "13")
+        .header(
+            "Sec-WebSocket-Key",
+            tungstenite::handshake::client::generate_key(),
+        );
+    for (name, value) in &upstream.headers {
+        request = request.header(name.as_str(), value.as_str());
+    }
+    let request = request
+        .body(())
+        .map_err(|e| format!("failed to build Deepgram request: {}", e))?;
+
+    let span = info_span!(
+        "deepgram.connect",
+        otel.kind = "client",
+        url.full = %url,
+        voice_agent.tenant = %tenant.id,
+        otel.status_code = tracing::field::Empty,
+    );
+    let connect = async {
+        let stream = egress::connect(egress, &url).await?;
+        let connector = egress::tls_connector(egress)?;
+        client_async_tls_with_config(request, stream, None, connector)
+            .await
+            .map_err(|e| e.to_string())
+    }
+    .instrument(span.clone());
+    let connected = if failover {
+        tokio::time::timeout(FAILOVER_CONNECT_TIMEOUT, connect)
+            .await
+            .unwrap_or_else(|_| Err("timed out".to_string()))
+    } else {
+        connect.await
+    };
+    let (deepgram_ws, _response) = connected.map_err(|e| {
+        span.record("otel.status_code", "ERROR");
+        format!("failed to connect to Deepgram: {}", e)
+    })?;
+    Ok(deepgram_ws)
+}
+
+// ============================================================================
+// SESSION OBSERVERS
+// ============================================================================
+
+/// Optional consumers of a session's frames and lifecycle: the recorder,
+/// webhooks, the event bus, usage accounting and latency analytics. Each but
+/// usage is compiled in only with its feature, and all do nothing unless
+/// attached.
+#[derive(Default)]
+struct Observers {
+    spans: SessionSpans,
+    usage: Option<SessionUsage>,
+    #[cfg(feature = "recording")]
+    recorder: Option<SessionRecorder>,
+    #[cfg(feature = "webhooks")]
+    hooks: Option<SessionHooks>,
+    #[cfg(feature = "events")]
+    events: Option<SessionEvents>,
+    #[cfg(feature = "metrics")]
+    latency: Option<SessionLatency>,
+}
+
+#[cfg_attr(
+    not(all(
+        feature = "recording",
+        feature = "webhooks",
+        feature = "events",
+        feature = "metrics"
+    )),
+    allow(unused_variables)
+)]
+impl Observers {
+    #[cfg(feature = "recording")]
+    fn record(&self, direction: Direction, payload: impl FnOnce() -> Payload) {
+        if let Some(recorder) = &self.recorder {
+            recorder.record(direction, payload());
+        }
+    }
+
+    fn agent_text(&self, text: &str) {
+        self.spans.agent_text(text);
+        if let Some(usage) = &self.usage {
+            usage.agent_text(text);
+        }
+        #[cfg(feature = "recording")]
+        self.record(Direction::AgentToClient, || Payload::Text(text.to_string()));
+        #[cfg(feature = "webhooks")]
+        if let Some(hooks) = &self.hooks {
+            hooks.agent_text(text);
+        }
+        #[cfg(feature = "events")]
+        if let Some(events) = &self.events {
+            events.agent_text(text);
+        }
+        #[cfg(feature = "metrics")]
+        if let Some(latency) = &self.latency {
+            latency.agent_text(text);
+        }
+    }
+
+    fn agent_binary(&self, data: &[u8]) {
+        if let Some(usage) = &self.usage {
+            usage.agent_binary(data.len());
+        }
+        #[cfg(feature = "metrics")]
+        if let Some(latency) = &self.latency {
+            latency.agent_binary();
+        }
+        #[cfg(feature = "recording")]
+        self.record(Direction::AgentToClient, || Payload::Binary(data.to_vec()));
+    }
+
+    fn agent_close(&self, code: u16, reason: &str) {
+        #[cfg(feature = "recording")]
+        self.record(Direction::AgentToClient, || {
+            Payload::Close(code, reason.to_string())
+        });
+    }
+
+    fn client_text(&self, text: &str) {
+        self.spans.client_text(text);
+        #[cfg(feature = "recording")]
+        self.record(Direction::ClientToAgent, || Payload::Text(text.to_string()));
+        #[cfg(feature = "webhooks")]
+        if let Some(hooks) = &self.hooks {
+            hooks.client_text(text);
+        }
+        #[cfg(feature = "events")]
+        if let Some(events) = &self.events {
+            events.client_text(text);
+        }
+    }
+
+    /// A client text frame as forwarded to the agent, unredacted.
+    fn client_text_forwarded(&self, text: &str) {
+        if let Some(usage) = &self.usage {
+            usage.client_text(text);
+        }
+    }
+
+    fn client_binary(&self, data: &[u8]) {
+        if let Some(usage) = &self.usage {
+            usage.client_binary(data.len());
+        }
+        #[cfg(feature = "recording")]
+        self.record(Direction::ClientToAgent, || Payload::Binary(data.to_vec()));
+    }
+
+    fn client_close(&self, code: u16, reason: &str) {
+        #[cfg(feature = "recording")]
+        self.record(Direction::ClientToAgent, || {
+            Payload::Close(code, reason.to_string())
+        });
+    }
+
+    fn connection_failed(&self, error: &str) {
+        #[cfg(feature = "webhooks")]
+        if let Some(hooks) = &self.hooks {
+            hooks.connection_failed(error);
+        }
+    }
+
+    fn started(&self) {
+        #[cfg(feature = "webhooks")]
+        if let Some(hooks) = &self.hooks {
+            hooks.started();
+        }
+        #[cfg(feature = "events")]
+        if let Some(events) = &self.events {
+            events.started();
+        }
+    }
+
+    fn ended(&self, closed_by: ClosedBy, code: u16, reason: &str) {
+        self.spans.ended(closed_by, code);
+        if let Some(usage) = &self.usage {
+            usage.ended(closed_by);
+        }
+        #[cfg(feature = "metrics")]
+        if let Some(latency) = &self.latency {
+            latency.ended();
+        }
+        #[cfg(feature = "webhooks")]
+        if let Some(hooks) = &self.hooks {
+            hooks.ended(closed_by, code, reason);
+        }
+        #[cfg(feature = "events")]
+        if let Some(events) = &self.events {
+            events.ended(closed_by, code, reason);
+        }
+    }
+}
+
+// ============================================================================
+// CLIENT LEG
+// ============================================================================
+
+/// Sending half of the client connection. For resumable sessions it also
+/// keeps the latest agent frames, up to `[resume] buffer_bytes`, so a client
+/// that reconnects can be sent those it missed: both the frames that reached
+/// a connection that was already dead, and those that came while the client
+/// was away.
+struct ClientLeg {
+    sender: Option<SplitSink<WebSocket, Message>>,
+    /// Whether the session outlives a dropped client connection.
+    resumable: bool,
+    /// The latest data frames for the client, oldest first.
+    history: VecDeque<Message>,
+    history_bytes: usize,
+    max_history_bytes: usize,
+    /// Data frames for the client since the session started.
+    frames: u64,
+    /// `frames` when the client connection dropped.
+    detached_at: u64,
+    /// Notified when a frame the client has not been sent leaves the
+    /// history while the client is away.
+    overflow: Arc<Notify>,
+}
+
+impl ClientLeg {
+    fn new(sender: SplitSink<WebSocket, Message>, resume: Option<&ResumeConfig>) -> Self {
+        Self {
+            sender: Some(sender),
+            resumable: resume.is_some(),
+            history: VecDeque::new(),
+            history_bytes: 0,
+            max_history_bytes: resume.map(|r| r.buffer_bytes).unwrap_or(0),
+            frames: 0,
+            detached_at: 0,
+            overflow: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Send `message` to the client, and keep it for the client to resume
+    /// with if the session is resumable. Fails only when the client is gone
+    /// and cannot come back.
+    async fn send(&mut self, message: Message) -> Result<(), axum::Error> {
+        if !self.resumable {
+            return match &mut self.sender {
+                Some(sender) => sender.send(message).await,
+                None => Ok(()),
+            };
+        }
+        let Some(len) = data_len(&message) else {
+            // Control frames mean nothing to the client's next connection
+            if let Some(sender) = &mut self.sender {
+                let _ = sender.send(message).await;
+            }
+            return Ok(());
+        };
+        if let Some(sender) = &mut self.sender
+            && sender.send(message.clone()).await.is_err()
+        {
+            self.detach();
+        }
+        self.keep(message, len);
+        Ok(())
+    }
+
+    fn keep(&mut self, message: Message, len: usize) {
+        self.history.push_back(message);
+        self.history_bytes += len;
+        self.frames += 1;
+        while self.history_bytes > self.max_history_bytes
+            && let Some(oldest) = self.history.pop_front()
+        {
+            self.history_bytes -= data_len(&oldest).unwrap_or(0);
+            let index = self.frames - self.history.len() as u64 - 1;
+            if self.sender.is_none() && index >= self.detached_at {
+                self.overflow.notify_one();
+            }
+        }
+    }
+
+    async fn close(&mut self) -> Result<(), axum::Error> {
+        match &mut self.sender {
+            Some(sender) => sender.close().await,
+            None => Ok(()),
+        }
+    }
+
+    fn is_attached(&self) -> bool {
+        self.sender.is_some()
+    }
+
+    /// The client connection dropped.
+    fn detach(&mut self) {
+        if self.sender.take().is_some() {
+            self.detached_at = self.frames;
+        }
+    }
+
+    /// Continue on a new client connection, first sending it the frames from
+    /// the `received`th on (by default, those since the previous connection
+    /// dropped). Replaces the previous connection if there still is one.
+    /// Returns whether the client is attached again; when the frames it
+    /// needs are no longer kept, the new connection is refused.
+    async fn attach(
+        &mut self,
+        mut sender: SplitSink<WebSocket, Message>,
+        received: Option<u64>,
+    ) -> bool {
+        let first = self.frames - self.history.len() as u64;
+        let from = received.unwrap_or(match self.sender {
+            Some(_) => self.frames,
+            None => self.detached_at,
+        });
+        if from < first || from > self.frames {
+            let err_msg = AgentMessage::Error {
+                description: "The agent output to resume from is no longer available".to_string(),
+                code: "RESUME_FAILED".to_string(),
+            };
+            let _ = sender.send(Message::Text(err_msg.to_json().into())).await;
+            let _ = sender.close().await;
+            return false;
+        }
+        for message in self.history.iter().skip((from - first) as usize) {
+            if sender.send(message.clone()).await.is_err() {
+                return false;
+            }
+        }
+        self.sender = Some(sender);
+        // An overflow while the client was away that the session did not
+        // act on must not end a later grace period at once
+        let _ = self.overflow.notified().now_or_never();
+        true
+    }
+}
+
+/// Payload length of a data frame; `None` for control frames.
+fn data_len(message: &Message) -> Option<usize> {
+    match message {
+        Message::Text(text) => Some(text.len()),
+        Message::Binary(data) => Some(data.len()),
+        _ => None,
+    }
+}
+
+// ============================================================================
+// PROXY SESSION
+// ============================================================================
+
+/// Which side ended a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClosedBy {
+    Client,
+    Agent,
+    /// The session's maximum duration was reached.
+    Timeout,
+    /// A guardrail with the `end` action was violated.
+    Guardrail,
+}
+
+impl ClosedBy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ClosedBy::Client => "client",
+            ClosedBy::Agent => "agent",
+            ClosedBy::Timeout => "timeout",
+            ClosedBy::Guardrail => "guardrail",
+        }
+    }
+}
+
+/// One proxied conversation. Holds the configuration snapshot the session
+/// started with, so config reloads never affect it.
+pub struct ProxySession {
+    id: String,
+    config: Arc<AppConfig>,
+    tenant: Option<Tenant>,
+    profile_settings: Option<Value>,
+    upstream: Upstream,
+    pool: Option<Pool>,
+    resume: Option<Resumable>,
+    redactor: Redactor,
+    observers: Observers,
+    #[cfg(feature = "guardrails")]
+    guardrails: Option<SessionGuardrails>,
+}
+
+impl ProxySession {
+    pub fn new(config: Arc<AppConfig>) -> Self {
+        let mut id = [0u8; 8];
+        rand::thread_rng().fill_bytes(&mut id);
+        Self {
+            id: hex::encode(id),
+            redactor: Redactor::new(&config.reloadable.redaction),
+            tenant: config.reloadable.tenants.default.clone(),
+            config,
+            profile_settings: None,
+            upstream: Upstream::default(),
+            pool: None,
+            resume: None,
+            observers: Observers::default(),
+            #[cfg(feature = "guardrails")]
+            guardrails: None,
+        }
+    }
+
+    /// Random identifier for logs, recordings, webhooks and events.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Bill the session to `tenant` instead of the default tenant.
+    pub fn with_tenant(mut self, tenant: Tenant) -> Self {
+        self.tenant = Some(tenant);
+        self
+    }
+
+    /// Settings merged over the client's `Settings` message.
+    pub fn with_profile_settings(mut self, settings: Option<Value>) -> Self {
+        self.profile_settings = settings;
+        self
+    }
+
+    /// Connect to the endpoints of `upstream` instead of the tenant's
+    /// agent URL.
+    pub fn with_upstream(mut self, upstream: Upstream) -> Self {
+        self.upstream = upstream;
+        self
+    }
+
+    /// Take the upstream connection from `pool` when it has one ready.
+    pub fn with_pool(mut self, pool: Option<Pool>) -> Self {
+        self.pool = pool;
+        self
+    }
+
+    /// Keep the session for its client to resume when its connection drops,
+    /// for `[resume] grace_secs`.
+    pub fn with_resume(mut self, resume: Option<Resumable>) -> Self {
+        self.resume = resume;
+        self
+    }
+
+    /// Capture the session's frames with `recorder`.
+    #[cfg(feature = "recording")]
+    pub fn with_recorder(mut self, recorder: Option<SessionRecorder>) -> Self {
+        self.observers.recorder = recorder;
+        self
+    }
+
+    /// Account the session's usage with `usage`.
+    pub fn with_usage(mut self, usage: Option<SessionUsage>) -> Self {
+        self.observers.usage = usage;
+        self
+    }
+
+    /// Time the session's turns with `latency`.
+    #[cfg(feature = "metrics")]
+    pub fn with_latency(mut self, latency: Option<SessionLatency>) -> Self {
+        self.observers.latency = latency;
+        self
+    }
+
+    /// Report lifecycle events through `hooks`.
+    #[cfg(feature = "webhooks")]
+    pub fn with_webhooks(mut self, hooks: Option<SessionHooks>) -> Self {
+        self.observers.hooks = hooks;
+        self
+    }
+
+    /// Publish the session's messages through `events`.
+    #[cfg(feature = "events")]
+    pub fn with_events(mut self, events: Option<SessionEvents>) -> Self {
+        self.observers.events = events;
+        self
+    }
+
+    /// Moderate the conversation with `guardrails`.
+    #[cfg(feature = "guardrails")]
+    pub fn with_guardrails(mut self, guardrails: Option<SessionGuardrails>) -> Self {
+        self.guardrails = guardrails;
+        self
+    }
+
+    /// Connect to Deepgram and proxy messages until either side closes or
+    /// the session reaches its maximum duration.
+    pub async fn run(self, client_ws: WebSocket) {
+        let ProxySession {
+            id: _,
+            config,
+            tenant,
+            profile_settings,
+            upstream,
+            pool,
+            resume,
+            redactor,
+            observers,
+            #[cfg(feature = "guardrails")]
+            guardrails,
+        } = self;
+        // Turn spans are children of the span the session runs in
+        let observers = Arc::new(Observers {
+            spans: SessionSpans::new(),
+            ..observers
+        });
+        #[cfg(feature = "guardrails")]
+        let guardrails = guardrails.map(Arc::new);
+
+        info!("Initiating Deepgram connection...");
+        let connected = match &tenant {
+            Some(tenant) => match &pool {
+                Some(pool) => {
+                    pool.connect(&config.reloadable.pool, &config.egress, tenant, &upstream)
+                        .await
+                }
+                None => connect_upstream_via(tenant, &upstream, &config.egress)
+                    .await
+                    .map(Connection::new),
+            },
+            None => Err("no Deepgram API key configured for this session".to_string()),
+        };
+        let connection = match connected {
+            Ok(connection) => connection,
+            Err(e) => {
+                error!("{}", e);
+                observers.connection_failed(&e);
+                // Send error message to client before closing
+                let (mut sender, _) = client_ws.split();
+                let err_msg = AgentMessage::Error {
+                    description: "Failed to establish proxy connection".to_string(),
+                    code: "CONNECTION_FAILED".to_string(),
+                };
+                let _ = sender.send(Message::Text(err_msg.to_json().into())).await;
+                let _ = sender.close().await;
+                return;
+            }
+        };
+
+        if connection.pre_warmed {
+            info!("Connected to Deepgram Agent API (pre-warmed connection)");
+        } else {
+            info!("Connected to Deepgram Agent API");
+        }
+        observers.started();
+
+        // Split both WebSocket connections into sender/receiver halves
+        let grace_secs = config.reloadable.resume.grace_secs;
+        let resume = resume.filter(|_| grace_secs > 0);
+        let (client_sender, client_receiver) = client_ws.split();
+        let client_sender = ClientLeg::new(
+            client_sender,
+            resume.as_ref().map(|_| &config.reloadable.resume),
+        );
+        let overflow = client_sender.overflow.clone();
+        let (deepgram_sender, deepgram_receiver) = connection.socket.split();
+        // Frames a pooled connection received before the session took it
+        // (the Welcome message) come first
+        let deepgram_receiver = futures_util::stream::iter(
+            connection
+                .received
+                .into_iter()
+                .map(Ok::<_, tungstenite::Error>),
+        )
+        .chain(deepgram_receiver);
+
+        // Wrap senders in Arc<Mutex> for shared access
+        let client_sender = Arc::new(Mutex::new(client_sender));
+        let deepgram_sender = Arc::new(Mutex::new(deepgram_sender));
+
+        if let Some(resume) = &resume {
+            let message = AgentMessage::SessionResumable {
+                resume_token: resume.token().to_string(),
+                grace_secs,
+            };
+            let mut sender = client_sender.lock().await;
+            let _ = sender.send(Message::Text(message.to_json().into())).await;
+        }
+
+        // Forward messages: Deepgram -> Client. Each direction resolves to
+        // the close code and reason that ended it.
+        let client_sender_clone = client_sender.clone();
+        let agent_observers = observers.clone();
+        let agent_redactor = redactor.clone();
+        #[cfg(feature = "guardrails")]
+        let agent_guardrails = guardrails.clone();
+        let deepgram_to_client = {
+            let mut deepgram_receiver = deepgram_receiver;
+            async move {
+                // Set when a guardrail withholds an assistant line; the rest
+                // of that turn's audio is dropped
+                #[cfg(feature = "guardrails")]
+                let mut muted = false;
+                while let Some(msg) = deepgram_receiver.next().await {
+                    match msg {
+                        Ok(tungstenite::Message::Text(text)) => {
+                            let redacted = agent_redactor.agent_message(&text);
+                            debug!("Agent message: {}", redacted);
+                            agent_observers.agent_text(&redacted);
+                            #[cfg(feature = "guardrails")]
+                            if let Some(guardrails) = &agent_guardrails {
+                                if guardrails.inspect(&redacted) {
+                                    muted = true;
+                                    continue;
+                                }
+                                if muted && ends_agent_turn(&text) {
+                                    muted = false;
+                                }
+                            }
+                            let forwarded = if agent_redactor.redacts_client() {
+                                agent_redactor.transcript(&text)
+                            } else {
+                                Cow::Borrowed(text.as_str())
+                            };
+                            let mut sender = client_sender_clone.lock().await;
+                            if sender
+                                .send(Message::Text(forwarded.into_owned().into()))
+                                .await
+                                .is_err()
+                            {
+                                error!("Error forwarding text to client");
+                                return (1006, String::new());
+                            }
+                        }
+                        Ok(tungstenite::Message::Binary(data)) => {
+                            agent_observers.agent_binary(&data);
+                            #[cfg(feature = "guardrails")]
+                            if muted {
+                                continue;
+                            }
+                            let mut sender = client_sender_clone.lock().await;
+                            if sender.send(Message::Binary(data.into())).await.is_err() {
+                                error!("Error forwarding binary to client");
+                                return (1006, String::new());
+                            }
+                        }
+                        Ok(tungstenite::Message::Close(frame)) => {
+                            let code = frame
+                                .as_ref()
+                                .map(|f| get_safe_close_code(f.code.into()))
+                                .unwrap_or(1000);
+                            let reason = frame
+                                .as_ref()
+                                .map(|f| f.reason.to_string())
+                                .unwrap_or_default();
+                            if code == 1000 || code == 1001 {
+                                info!("Deepgram connection closed normally");
+                            } else {
+                                warn!("Deepgram connection closed: {} {}", code, reason);
+                            }
+                            agent_observers.agent_close(code, &reason);
+                            let mut sender = client_sender_clone.lock().await;
+                            let _ = sender
+                                .send(Message::Close(Some(axum::extract::ws::CloseFrame {
+                                    code,
+                                    reason: reason.clone().into(),
+                                })))
+                                .await;
+                            return (code, reason);
+                        }
+                        Ok(tungstenite::Message::Ping(data)) => {
+                            let mut sender = client_sender_clone.lock().await;
+                            let _ = sender.send(Message::Ping(data.into())).await;
+                        }
+                        Ok(tungstenite::Message::Pong(data)) => {
+                            let mut sender = client_sender_clone.lock().await;
+                            let _ = sender.send(Message::Pong(data.into())).await;
+                        }
+                        Ok(tungstenite::Message::Frame(_)) => {
+                            // Raw frames are not forwarded
+                        }
+                        Err(e) => {
+                            error!("Deepgram read error: {}", e);
+                            let mut sender = client_sender_clone.lock().await;
+                            let _ = sender
+                                .send(Message::Close(Some(axum::extract::ws::CloseFrame {
+                                    code: 1000,
+                                    reason: "".into(),
+                                })))
+                                .await;
+                            return (1006, e.to_string());
+                        }
+                    }
+                }
+                (1006, String::new())
+            }
+        };
+
+        // Forward messages: Client -> Deepgram. A dropped client connection
+        // ends the session unless it is resumable and the client reconnects
+        // in time; a reconnect may also replace a connection at any time.
+        let deepgram_sender_clone = deepgram_sender.clone();
+        let client_observers = observers.clone();
+        let client_leg = client_sender.clone();
+        let client_to_deepgram = {
+            let mut client_receiver = client_receiver;
+            let mut resume = resume;
+            async move {
+                loop {
+                    let (code, reason) = loop {
+                        let msg = tokio::select! {
+                            Some(reconnect) = reconnected(&mut resume) => {
+                                let (sender, receiver) = reconnect.socket.split();
+                                let mut leg = client_leg.lock().await;
+                                if leg.attach(sender, reconnect.received).await {
+                                    info!("Client reconnected, replacing its connection");
+                                    client_receiver = receiver;
+                                } else if !leg.is_attached() {
+                                    // The previous connection is already
+                                    // dead and the output the client missed
+                                    // is gone
+                                    warn!("Client could not resume the session, ending it");
+                                    return (1006, String::new());
+                                }
+                                continue;
+                            }
+                            msg = client_receiver.next() => msg,
+                        };
+                        match msg {
+                            Some(Ok(Message::Text(text))) => {
+                                let redacted = redactor.client_message(text.as_str());
+                                debug!("Client message: {}", redacted);
+                                client_observers.client_text(&redacted);
+                                let text = match &profile_settings {
+                                    Some(overrides) => {
+                                        apply_profile_settings(text.as_str(), overrides)
+                                    }
+                                    None => text.to_string(),
+                                };
+                                client_observers.client_text_forwarded(&text);
+                                let mut sender = deepgram_sender_clone.lock().await;
+                                if sender.send(tungstenite::Message::Text(text)).await.is_err() {
+                                    error!("Error forwarding text to Deepgram");
+                                    return (1006, String::new());
+                                }
+                            }
+                            Some(Ok(Message::Binary(data))) => {
+                                client_observers.client_binary(&data);
+                                let mut sender = deepgram_sender_clone.lock().await;
+                                if sender
+                                    .send(tungstenite::Message::Binary(data.into()))
+                                    .await
+                                    .is_err()
+                                {
+                                    error!("Error forwarding binary to Deepgram");
+                                    return (1006, String::new());
+                                }
+                            }
+                            Some(Ok(Message::Close(frame))) => {
+                                let (code, reason) = frame
+                                    .map(|f| (f.code, f.reason.to_string()))
+                                    .unwrap_or((1005, String::new()));
+                                client_observers.client_close(code, &reason);
+                                info!("Client disconnected normally");
+                                return (code, reason);
+                            }
+                            Some(Ok(Message::Ping(data))) => {
+                                let mut sender = deepgram_sender_clone.lock().await;
+                                let _ = sender.send(tungstenite::Message::Ping(data.into())).await;
+                            }
+                            Some(Ok(Message::Pong(data))) => {
+                                let mut sender = deepgram_sender_clone.lock().await;
+                                let _ = sender.send(tungstenite::Message::Pong(data.into())).await;
+                            }
+                            Some(Err(e)) => {
+                                error!("Client read error: {}", e);
+                                break (1006, e.to_string());
+                            }
+                            None => break (1006, String::new()),
+                        }
+                    };
+
+                    // The connection dropped without a close frame
+                    let Some(resumable) = resume.as_mut() else {
+                        return (code, reason);
+                    };
+                    client_leg.lock().await.detach();
+                    info!(
+                        "Client connection lost, holding the session for {}s",
+                        grace_secs
+                    );
+                    let deadline = Instant::now() + Duration::from_secs(grace_secs);
+                    loop {
+                        let Some(reconnect) =
+                            await_reconnect(resumable, deadline, &overflow, &deepgram_sender_clone)
+                                .await
+                        else {
+                            return (code, reason);
+                        };
+                        let (sender, receiver) = reconnect.socket.split();
+                        if client_leg
+                            .lock()
+                            .await
+                            .attach(sender, reconnect.received)
+                            .await
+                        {
+                            info!("Client resumed the session");
+                            client_receiver = receiver;
+                            break;
+                        }
+                    }
+                }
+            }
+        };
+
+        // Optional cap on session duration
+        let max_session_secs = tenant
+            .as_ref()
+            .and_then(|t| t.max_session_secs)
+            .unwrap_or(config.reloadable.limits.max_session_secs);
+        let session_timeout = async move {
+            if max_session_secs > 0 {
+                tokio::time::sleep(Duration::from_secs(max_session_secs)).await;
+            } else {
+                std::future::pending::<()>().await;
+            }
+        };
+
+        // Act on guardrail violations; resolves when one ends the session
+        #[cfg(feature = "guardrails")]
+        let enforcement = enforce_guardrails(
+            guardrails.clone(),
+            client_sender.clone(),
+            deepgram_sender.clone(),
+        );
+        #[cfg(not(feature = "guardrails"))]
+        let enforcement = std::future::pending::<String>();
+
+        // Wait for either side to close, then clean up both
+        let (closed_by, code, reason) = tokio::select! {
+            (code, reason) = deepgram_to_client => {
+                info!("Deepgram disconnected, closing client connection");
+                let mut sender = client_sender.lock().await;
+                let _ = sender.close().await;
+                (ClosedBy::Agent, code, reason)
+            }
+            (code, reason) = client_to_deepgram => {
+                info!("Client disconnected, closing Deepgram connection");
+                let mut sender = deepgram_sender.lock().await;
+                let _ = sender
+                    .send(tungstenite::Message::Close(Some(
+                        tungstenite::protocol::CloseFrame {
+                            code: tungstenite::protocol::frame::coding::CloseCode::Normal,
+                            reason: "Client disconnected".into(),
+                        },
+                    )))
+                    .await;
+                let _ = sender.close().await;
+                (ClosedBy::Client, code, reason)
+            }
+            _ = session_timeout => {
+                info!("Session reached max duration of {}s, closing", max_session_secs);
+                let mut sender = client_sender.lock().await;
+                let _ = sender
+                    .send(Message::Close(Some(axum::extract::ws::CloseFrame {
+                        code: 1000,
+                        reason: "Session time limit reached".into(),
+                    })))
+                    .await;
+                let mut sender = deepgram_sender.lock().await;
+                let _ = sender.close().await;
+                (ClosedBy::Timeout, 1000, "Session time limit reached".to_string())
+            }
+            reason = enforcement => {
+                info!("Guardrail ended the session: {}", reason);
+                let mut sender = client_sender.lock().await;
+                let _ = sender
+                    .send(Message::Close(Some(axum::extract::ws::CloseFrame {
+                        code: 1008,
+                        reason: reason.clone().into(),
+                    })))
+                    .await;
+                let mut sender = deepgram_sender.lock().await;
+                let _ = sender.close().await;
+                (ClosedBy::Guardrail, 1008, reason)
+            }
+        };
+
+        observers.ended(closed_by, code, &reason);
+    }
+}
+
+/// The next connection of a client resuming the session; never resolves for
+/// sessions that are not resumable.
+async fn reconnected(resume: &mut Option<Resumable>) -> Option<Reconnect> {
+    match resume {
+        Some(resume) => resume.reconnected().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Wait until `deadline` for the client of a session to reconnect after its
+/// connection dropped, keeping the agent connection open meanwhile. `None`
+/// when it does not, or misses more agent output than is kept for it.
+async fn await_reconnect(
+    resume: &mut Resumable,
+    deadline: Instant,
+    overflow: &Notify,
+    deepgram_sender: &Mutex<SplitSink<UpstreamSocket, tungstenite::Message>>,
+) -> Option<Reconnect> {
+    let deadline = tokio::time::sleep_until(deadline);
+    tokio::pin!(deadline);
+    let mut keepalive = tokio::time::interval(KEEPALIVE_INTERVAL);
+    loop {
+        tokio::select! {
+            reconnect = resume.reconnected() => return reconnect,
+            _ = &mut deadline => {
+                info!("Client did not resume the session in time");
+                return None;
+            }
+            _ = overflow.notified() => {
+                warn!("Client missed more agent output than is kept for it, ending the session");
+                return None;
+            }
+            _ = keepalive.tick() => {
+                let mut sender = deepgram_sender.lock().await;
+                let _ = sender
+                    .send(tungstenite::Message::Text(ClientMessage::KeepAlive.to_json()))
+                    .await;
+            }
+        }
+    }
+}
+
+/// Take the action of each guardrail violation until one ends the session;
+/// resolves with the reason to close it with.
+#[cfg(feature = "guardrails")]
+async fn enforce_guardrails(
+    guardrails: Option<Arc<SessionGuardrails>>,
+    client_sender: Arc<Mutex<ClientLeg>>,
+    deepgram_sender: Arc<Mutex<SplitSink<UpstreamSocket, tungstenite::Message>>>,
+) -> String {
+    let Some(guardrails) = guardrails else {
+        return std::future::pending().await;
+    };
+    while let Some(violation) = guardrails.next_violation().await {
+        let to_agent = |message: ClientMessage| tungstenite::Message::Text(message.to_json());
+        let to_client = |message: AgentMessage| Message::Text(message.to_json().into());
+        match violation.action {
+            GuardrailAction::Inject { message } => {
+                let mut sender = deepgram_sender.lock().await;
+                let _ = sender
+                    .send(to_agent(ClientMessage::InjectAgentMessage { message }))
+                    .await;
+            }
+            GuardrailAction::UpdatePrompt { prompt } => {
+                let mut sender = deepgram_sender.lock().await;
+                let _ = sender
+                    .send(to_agent(ClientMessage::UpdatePrompt { prompt }))
+                    .await;
+            }
+            GuardrailAction::Warn { message } => {
+                let mut sender = client_sender.lock().await;
+                let _ = sender
+                    .send(to_client(AgentMessage::Warning {
+                        description: message,
+                        code: GUARDRAIL_VIOLATION.to_string(),
+                    }))
+                    .await;
+            }
+            GuardrailAction::End { message } => {
+                let mut sender = client_sender.lock().await;
+                let _ = sender
+                    .send(to_client(AgentMessage::Error {
+                        description: message.clone(),
+                        code: GUARDRAIL_VIOLATION.to_string(),
+                    }))
+                    .await;
+                return message;
+            }
+        }
+    }
+    std::future::pending().await
+}
+
+/// Whether an agent frame marks the end of the agent's turn.
+#[cfg(feature = "guardrails")]
+fn ends_agent_turn(text: &str) -> bool {
+    matches!(
+        AgentMessage::parse(text),
+        Some(AgentMessage::AgentAudioDone | AgentMessage::UserStartedSpeaking)
+    )
+}
+
+/// Merge profile overrides into a client `Settings` message.
+/// Any other message, or text that is not JSON, passes through unchanged.
+fn apply_profile_settings(text: &str, overrides: &Value) -> String {
+    let mut msg: Value = match serde_json::from_str(text) {
+        Ok(v) => v,
+        Err(_) => return text.to_string(),
+    };
+    if msg.get("type").and_then(Value::as_str) != Some("Settings") {
+        return text.to_string();
+    }
+    merge_json(&mut msg, overrides);
+    msg.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::Router;
+    use axum::extract::ws::WebSocketUpgrade;
+    use axum::routing::get;
+    use tokio::sync::mpsc;
+    use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+    type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    /// A client connection: the proxy's sending half, and the client end.
+    async fn connection() -> (SplitSink<WebSocket, Message>, Client) {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let app = Router::new().route(
+            "/",
+            get(move |ws: WebSocketUpgrade| async move {
+                ws.on_upgrade(move |socket| async move {
+                    let _ = tx.send(socket.split().0);
+                })
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let (client, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        (rx.recv().await.unwrap(), client)
+    }
+
+    fn resumable_leg(sender: SplitSink<WebSocket, Message>, buffer_bytes: usize) -> ClientLeg {
+        let resume = ResumeConfig {
+            grace_secs: 30,
+            buffer_bytes,
+        };
+        ClientLeg::new(sender, Some(&resume))
+    }
+
+    async fn send(leg: &mut ClientLeg, texts: &[&str]) {
+        for text in texts {
+            leg.send(Message::Text((*text).into())).await.unwrap();
+        }
+    }
+
+    /// The text frames the client was sent until its connection closed.
+    async fn texts(mut client: Client) -> Vec<String> {
+        let mut texts = Vec::new();
+        while let Some(Ok(frame)) = client.next().await {
+            match frame {
+                tungstenite::Message::Text(text) => texts.push(text),
+                tungstenite::Message::Close(_) => break,
+                _ => {}
+            }
+        }
+        texts
+    }
+
+    fn overflowed(leg: &ClientLeg) -> bool {
+        leg.overflow.notified().now_or_never().is_some()
+    }
+
+    #[tokio::test]
+    async fn a_returning_client_gets_what_it_missed_while_away() {
+        let (first, _first_client) = connection().await;
+        let mut leg = resumable_leg(first, 1024);
+        send(&mut leg, &["a", "b"]).await;
+        leg.detach();
+        send(&mut leg, &["c", "d"]).await;
+
+        let (second, client) = connection().await;
+        assert!(leg.attach(second, None).await);
+        send(&mut leg, &["e"]).await;
+        leg.close().await.unwrap();
+        assert_eq!(texts(client).await, ["c", "d", "e"]);
+    }
+
+    #[tokio::test]
+    async fn a_client_can_say_how_much_it_received() {
+        let (first, _first_client) = connection().await;
+        let mut leg = resumable_leg(first, 1024);
+        send(&mut leg, &["a", "b", "c"]).await;
+
+        // Replaces the connection, which is still attached
+        let (second, client) = connection().await;
+        assert!(leg.attach(second, Some(1)).await);
+        leg.close().await.unwrap();
+        assert_eq!(texts(client).await, ["b", "c"]);
+
+        let (third, client) = connection().await;
+        assert!(leg.attach(third, Some(3)).await);
+        leg.close().await.unwrap();
+        assert!(texts(client).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_resume_point_outside_the_history_is_refused() {
+        let (first, _first_client) = connection().await;
+        // Keeps two one-byte frames
+        let mut leg = resumable_leg(first, 2);
+        send(&mut leg, &["a", "b", "c", "d"]).await;
+
+        for received in [Some(1), Some(5)] {
+            let (sender, client) = connection().await;
+            assert!(!leg.attach(sender, received).await);
+            let texts = texts(client).await;
+            assert_eq!(texts.len(), 1);
+            assert!(texts[0].contains("RESUME_FAILED"), "{}", texts[0]);
+        }
+        // The previous connection is kept
+        assert!(leg.is_attached());
+
+        let (sender, client) = connection().await;
+        assert!(leg.attach(sender, Some(2)).await);
+        leg.close().await.unwrap();
+        assert_eq!(texts(client).await, ["c", "d"]);
+    }
+
+    #[tokio::test]
+    async fn losing_output_the_client_missed_is_an_overflow() {
+        let (first, _first_client) = connection().await;
+        let mut leg = resumable_leg(first, 2);
+        send(&mut leg, &["a", "b"]).await;
+        leg.detach();
+
+        // Only frames the client was already sent leave the history
+        send(&mut leg, &["c", "d"]).await;
+        assert!(!overflowed(&leg));
+        send(&mut leg, &["e"]).await;
+        assert!(overflowed(&leg));
+
+        let (sender, _client) = connection().await;
+        assert!(!leg.attach(sender, None).await);
+        assert!(!leg.is_attached());
+    }
+
+    #[tokio::test]
+    async fn attaching_clears_an_overflow_not_acted_on() {
+        let (first, _first_client) = connection().await;
+        let mut leg = resumable_leg(first, 1);
+        send(&mut leg, &["a"]).await;
+        leg.detach();
+        send(&mut leg, &["b", "c"]).await;
+
+        let (sender, client) = connection().await;
+        assert!(leg.attach(sender, Some(2)).await);
+        assert!(!overflowed(&leg));
+        leg.close().await.unwrap();
+        assert_eq!(texts(client).await, ["c"]);
+    }
+}
@@ -0,0 +1,354 @@
+// Built-in rate limiting.
+//
+// Token buckets keyed by client IP and, when the request carries a valid
+// session token or a verified client certificate, by its subject. Each route class has its own budget
+// (see `[rate_limit]` in config.example.toml). Limited requests get
+// 429 Too Many Requests with a Retry-After header.
+
+use crate::auth::{ClientIdentity, validate_token};
+use crate::config::{RateLimitConfig, RateRule};
+use crate::router::AppState;
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderMap, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use serde_json::json;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Route classes with separate budgets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RouteClass {
+    Session,
+    VoiceAgent,
+    Admin,
+}
+
+impl RouteClass {
+    fn rule(self, config: &RateLimitConfig) -> RateRule {
+        match self {
+            RouteClass::Session => config.session,
+            RouteClass::VoiceAgent => config.voice_agent,
+            RouteClass::Admin => config.admin,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            RouteClass::Session => "session",
+            RouteClass::VoiceAgent => "voice_agent",
+            RouteClass::Admin => "admin",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Ip(IpAddr),
+    Subject(String),
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// How often idle buckets are swept, and how long a bucket may sit idle.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+const PRUNE_IDLE: Duration = Duration::from_secs(3600);
+
+#[derive(Default)]
+struct Buckets {
+    map: HashMap<(RouteClass, Key), Bucket>,
+    pruned_at: Option<Instant>,
+}
+
+/// Set once the missing peer address has been logged.
+static WARNED_NO_PEER: AtomicBool = AtomicBool::new(false);
+
+/// In-memory token bucket store shared by all requests.
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    /// Take one token for `key`, or return how long until one is available.
+    fn take(
+        &self,
+        class: RouteClass,
+        key: Key,
+        limit: u32,
+        window: Duration,
+    ) -> Result<(), Duration> {
+        let now = Instant::now();
+        let capacity = f64::from(limit);
+        let per_sec = capacity / window.as_secs_f64();
+
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        // Drop buckets untouched for a long time; they would be full anyway
+        if buckets
+            .pruned_at
+            .is_none_or(|at| now.duration_since(at) >= PRUNE_INTERVAL)
+        {
+            buckets
+                .map
+                .retain(|_, b| now.duration_since(b.updated) < PRUNE_IDLE);
+            buckets.pruned_at = Some(now);
+        }
+
+        let bucket = buckets.map.entry((class, key)).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_sec).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_sec))
+        }
+    }
+}
+
+/// Middleware enforcing the budget of `class` on the wrapped routes.
+pub async fn limit(
+    State((state, class)): State<(AppState, RouteClass)>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let config = state.config();
+    let rules = &config.reloadable.rate_limit;
+    if !rules.enabled {
+        return next.run(req).await;
+    }
+    let rule = class.rule(rules);
+    let window = Duration::from_secs(rule.window_secs);
+
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    if peer.is_none() && rule.per_ip > 0 && !WARNED_NO_PEER.swap(true, Ordering::Relaxed) {
+        warn!(
+            "Per-IP rate limits are not enforced: the client address is unknown \
+             (serve the router with into_make_service_with_connect_info::<SocketAddr>())"
+        );
+    }
+    if let Some(peer) = peer
+        && rule.per_ip > 0
+    {
+        let ip = client_ip(peer, req.headers(), &rules.trusted_proxies);
+        if let Err(wait) = state
+            .rate_limiter
+            .take(class, Key::Ip(ip), rule.per_ip, window)
+        {
+            warn!("Rate limit exceeded for {} on {} routes", ip, class.name());
+            return too_many_requests(wait);
+        }
+    }
+
+    if rule.per_subject > 0
+        && let Some(subject) = token_subject(req.headers(), &config.session_secret).or_else(|| {
+            req.extensions()
+                .get::<ClientIdentity>()
+                .map(|id| id.subject.clone())
+        })
+        && let Err(wait) = state.rate_limiter.take(
+            class,
+            Key::Subject(subject.clone()),
+            rule.per_subject,
+            window,
+        )
+    {
+        warn!(
+            "Rate limit exceeded for subject '{}' on {} routes",
+            subject,
+            class.name()
+        );
+        return too_many_requests(wait);
+    }
+
+    next.run(req).await
+}
+
+/// Resolve the client address, walking X-Forwarded-For right to left past
+/// trusted proxies. Untrusted peers cannot spoof their address this way.
+fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted: &[ipnet::IpNet]) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|net| net.contains(ip));
+    if !is_trusted(&peer) {
+        return peer;
+    }
+
+    let forwarded: Vec<IpAddr> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|s| s.trim().parse().ok())
+        .collect();
+
+    let mut client = peer;
+    for ip in forwarded.into_iter().rev() {
+        client = ip;
+        if !is_trusted(&ip) {
+            break;
+        }
+    }
+    client
+}
+
+/// Subject of a valid session token presented as `Authorization: Bearer` or
+/// as the `access_token.<jwt>` WebSocket subprotocol.
+fn token_subject(headers: &HeaderMap, secret: &[u8]) -> Option<String> {
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    let subprotocol = headers
+        .get(header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| {
+            v.split(',')
+                .find_map(|p| p.trim().strip_prefix("access_token."))
+        });
+
+    bearer
+        .into_iter()
+        .chain(subprotocol)
+        .find_map(|token| validate_token(token.trim(), secret).ok())
+        .and_then(|claims| claims.sub)
+}
+
+fn too_many_requests(wait: Duration) -> Response {
+    let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, retry_after.to_string())],
+        Json(json!({
+            "error": "RATE_LIMITED",
+            "message": format!("Too many requests, retry after {} seconds", retry_after)
+        })),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nets(list: &[&str]) -> Vec<ipnet::IpNet> {
+        list.iter().map(|n| n.parse().unwrap()).collect()
+    }
+
+    fn forwarded(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn client_ip_ignores_forwarded_for_from_untrusted_peers() {
+        let peer: IpAddr = "203.0.113.7".parse().unwrap();
+        let headers = forwarded("198.51.100.1");
+        assert_eq!(client_ip(peer, &headers, &[]), peer);
+        assert_eq!(client_ip(peer, &headers, &nets(&["10.0.0.0/8"])), peer);
+    }
+
+    #[test]
+    fn client_ip_walks_past_trusted_proxies() {
+        let peer: IpAddr = "10.0.0.2".parse().unwrap();
+        let trusted = nets(&["10.0.0.0/8"]);
+        // The leftmost entry is client-supplied and may be spoofed
+        let headers = forwarded("192.0.2.9, 198.51.100.1, 10.0.0.1");
+        assert_eq!(
+            client_ip(peer, &headers, &trusted),
+            "198.51.100.1".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn client_ip_skips_garbage_and_falls_back_to_the_last_proxy() {
+        let peer: IpAddr = "10.0.0.2".parse().unwrap();
+        let trusted = nets(&["10.0.0.0/8"]);
+        let headers = forwarded("not-an-ip, 10.0.0.1");
+        assert_eq!(
+            client_ip(peer, &headers, &trusted),
+            "10.0.0.1".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(client_ip(peer, &HeaderMap::new(), &trusted), peer);
+    }
+
+    #[test]
+    fn buckets_allow_a_burst_then_report_the_wait() {
+        let limiter = RateLimiter::default();
+        let key = || Key::Subject("alice".to_string());
+        let window = Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(limiter.take(RouteClass::Session, key(), 3, window).is_ok());
+        }
+        let wait = limiter
+            .take(RouteClass::Session, key(), 3, window)
+            .unwrap_err();
+        assert!(wait > Duration::from_secs(19) && wait <= Duration::from_secs(20));
+    }
+
+    #[test]
+    fn buckets_are_separate_per_key_and_route_class() {
+        let limiter = RateLimiter::default();
+        let window = Duration::from_secs(60);
+        let ip = |s: &str| Key::Ip(s.parse().unwrap());
+        assert!(
+            limiter
+                .take(RouteClass::Session, ip("192.0.2.1"), 1, window)
+                .is_ok()
+        );
+        assert!(
+            limiter
+                .take(RouteClass::Session, ip("192.0.2.1"), 1, window)
+                .is_err()
+        );
+        assert!(
+            limiter
+                .take(RouteClass::Session, ip("192.0.2.2"), 1, window)
+                .is_ok()
+        );
+        assert!(
+            limiter
+                .take(RouteClass::Admin, ip("192.0.2.1"), 1, window)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn buckets_refill_over_the_window() {
+        let limiter = RateLimiter::default();
+        let window = Duration::from_millis(50);
+        let key = || Key::Subject("bob".to_string());
+        assert!(
+            limiter
+                .take(RouteClass::VoiceAgent, key(), 1, window)
+                .is_ok()
+        );
+        assert!(
+            limiter
+                .take(RouteClass::VoiceAgent, key(), 1, window)
+                .is_err()
+        );
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(
+            limiter
+                .take(RouteClass::VoiceAgent, key(), 1, window)
+                .is_ok()
+        );
+    }
+}
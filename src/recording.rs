@@ -0,0 +1,407 @@
+// Session recording file format.
+//
+// A recording is the bidirectional frame stream of one voice agent session.
+// Layout (all integers little-endian):
+//
+//   header:  b"DGREC" version:u8
+//   record:  direction:u8 kind:u8 offset_ms:u32 len:u32 payload[len]
+//
+// direction: 0 = client -> agent, 1 = agent -> client
+// kind:      0 = text (UTF-8), 1 = binary, 2 = close (code:u16 + UTF-8 reason)
+//
+// Audio dominates session traffic, so binary frames are stored raw rather
+// than base64-encoded JSON.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+const MAGIC: &[u8; 5] = b"DGREC";
+const VERSION: u8 = 1;
+
+/// Largest frame payload, as WebSocket messages are capped by default.
+/// Readers refuse larger lengths rather than allocate for a corrupt one.
+const MAX_FRAME_LEN: u32 = 64 << 20;
+
+/// Frames a live session may queue for the writer; about 20 seconds of
+/// audio in both directions at the usual 20 ms chunks.
+const RECORDER_BUFFER: usize = 2048;
+
+/// Which leg of the proxy a frame travelled on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    ClientToAgent,
+    AgentToClient,
+}
+
+/// Frame contents.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Payload {
+    Text(String),
+    Binary(Vec<u8>),
+    Close(u16, String),
+}
+
+/// One recorded frame with its offset from the start of the session.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub direction: Direction,
+    pub offset: Duration,
+    pub payload: Payload,
+}
+
+// ============================================================================
+// WRITER
+// ============================================================================
+
+/// Streams records to any `Write` in the recording format.
+pub struct RecordingWriter<W: Write> {
+    inner: W,
+}
+
+impl RecordingWriter<BufWriter<File>> {
+    /// Create (or truncate) a recording file.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write> RecordingWriter<W> {
+    /// Write the file header and return a writer for records.
+    pub fn new(mut inner: W) -> io::Result<Self> {
+        inner.write_all(MAGIC)?;
+        inner.write_all(&[VERSION])?;
+        Ok(Self { inner })
+    }
+
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        let mut bytes = Vec::new();
+        encode(record, &mut bytes)?;
+        self.inner.write_all(&bytes)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Append the encoding of `record` to `out`.
+fn encode(record: &Record, out: &mut Vec<u8>) -> io::Result<()> {
+    let direction = match record.direction {
+        Direction::ClientToAgent => 0u8,
+        Direction::AgentToClient => 1u8,
+    };
+    let kind = match &record.payload {
+        Payload::Text(_) => 0u8,
+        Payload::Binary(_) => 1,
+        Payload::Close(..) => 2,
+    };
+    let offset_ms = u32::try_from(record.offset.as_millis()).unwrap_or(u32::MAX);
+
+    let start = out.len();
+    out.extend_from_slice(&[direction, kind]);
+    out.extend_from_slice(&offset_ms.to_le_bytes());
+    // Length, filled in once the payload is written
+    out.extend_from_slice(&[0; 4]);
+    match &record.payload {
+        Payload::Text(text) => out.extend_from_slice(text.as_bytes()),
+        Payload::Binary(data) => out.extend_from_slice(data),
+        Payload::Close(code, reason) => {
+            out.extend_from_slice(&code.to_le_bytes());
+            out.extend_from_slice(reason.as_bytes());
+        }
+    }
+    let len = out.len() - start - 10;
+    match u32::try_from(len) {
+        Ok(len) if len <= MAX_FRAME_LEN => {
+            out[start + 6..start + 10].copy_from_slice(&len.to_le_bytes());
+            Ok(())
+        }
+        _ => {
+            out.truncate(start);
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "frame too large",
+            ))
+        }
+    }
+}
+
+/// Captures a live session from async code. Frames are queued to a task
+/// that writes them, so disk I/O never stalls the proxy; the file is flushed
+/// once every clone of the recorder has been dropped. When the disk cannot
+/// keep up and the queue is full, frames are dropped (and counted in the
+/// logs) rather than held in memory.
+#[derive(Clone)]
+pub struct SessionRecorder {
+    tx: mpsc::Sender<Record>,
+    started: Instant,
+    dropped: Arc<AtomicU64>,
+}
+
+impl SessionRecorder {
+    /// Create the recording file; offsets are measured from now. Call this
+    /// from within a Tokio runtime.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = tokio::fs::File::from_std(File::create(path)?);
+        let (tx, rx) = mpsc::channel(RECORDER_BUFFER);
+        let dropped = Arc::new(AtomicU64::new(0));
+        tokio::spawn(write_queued(
+            file,
+            rx,
+            path.display().to_string(),
+            dropped.clone(),
+        ));
+        Ok(Self {
+            tx,
+            started: Instant::now(),
+            dropped,
+        })
+    }
+
+    /// Queue a frame, stamped with the time since the recorder was created.
+    pub fn record(&self, direction: Direction, payload: Payload) {
+        let record = Record {
+            direction,
+            offset: self.started.elapsed(),
+            payload,
+        };
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.tx.try_send(record) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Write the records queued on `rx` to `file` until every sender is gone.
+async fn write_queued(
+    file: tokio::fs::File,
+    mut rx: mpsc::Receiver<Record>,
+    name: String,
+    dropped: Arc<AtomicU64>,
+) {
+    let mut file = tokio::io::BufWriter::new(file);
+    let mut bytes = [&MAGIC[..], &[VERSION]].concat();
+    let result: io::Result<()> = async {
+        loop {
+            file.write_all(&bytes).await?;
+            let Some(record) = rx.recv().await else {
+                return file.flush().await;
+            };
+            bytes.clear();
+            encode(&record, &mut bytes)?;
+        }
+    }
+    .await;
+    if let Err(e) = result {
+        tracing::error!("Failed to write recording {}: {}", name, e);
+    }
+    let dropped = dropped.load(Ordering::Relaxed);
+    if dropped > 0 {
+        tracing::warn!(
+            "Recording {} is missing {} frame(s): the disk fell behind the session",
+            name,
+            dropped
+        );
+    }
+}
+
+// ============================================================================
+// READER
+// ============================================================================
+
+/// Iterates over the records of a recording.
+pub struct RecordingReader<R: Read> {
+    inner: R,
+}
+
+impl<R: Read> RecordingReader<R> {
+    /// Check the file header and return a reader positioned at the first record.
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let mut header = [0u8; 6];
+        inner.read_exact(&mut header)?;
+        if &header[..5] != MAGIC {
+            return Err(invalid("not a session recording"));
+        }
+        if header[5] != VERSION {
+            return Err(invalid(&format!(
+                "unsupported recording version {}",
+                header[5]
+            )));
+        }
+        Ok(Self { inner })
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<Record>> {
+        let mut head = [0u8; 10];
+        match self.inner.read_exact(&mut head[..1]) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        self.inner.read_exact(&mut head[1..])?;
+
+        let direction = match head[0] {
+            0 => Direction::ClientToAgent,
+            1 => Direction::AgentToClient,
+            d => return Err(invalid(&format!("unknown direction {}", d))),
+        };
+        let offset_ms = u32::from_le_bytes([head[2], head[3], head[4], head[5]]);
+        let len = u32::from_le_bytes([head[6], head[7], head[8], head[9]]);
+        if len > MAX_FRAME_LEN {
+            return Err(invalid(&format!("frame of {} bytes is too large", len)));
+        }
+        let mut body = vec![0u8; len as usize];
+        self.inner.read_exact(&mut body)?;
+
+        let payload = match head[1] {
+            0 => Payload::Text(String::from_utf8(body).map_err(|e| invalid(&e.to_string()))?),
+            1 => Payload::Binary(body),
+            2 if body.len() >= 2 => {
+                let code = u16::from_le_bytes([body[0], body[1]]);
+                let reason = String::from_utf8_lossy(&body[2..]).into_owned();
+                Payload::Close(code, reason)
+            }
+            k => return Err(invalid(&format!("unknown frame kind {}", k))),
+        };
+
+        Ok(Some(Record {
+            direction,
+            offset: Duration::from_millis(offset_ms.into()),
+            payload,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for RecordingReader<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+/// Read every record of a recording file into memory.
+pub fn read_file(path: &Path) -> io::Result<Vec<Record>> {
+    RecordingReader::new(BufReader::new(File::open(path)?))?.collect()
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records() -> Vec<Record> {
+        vec![
+            Record {
+                direction: Direction::ClientToAgent,
+                offset: Duration::ZERO,
+                payload: Payload::Text(r#"{"type":"Settings"}"#.to_string()),
+            },
+            Record {
+                direction: Direction::ClientToAgent,
+                offset: Duration::from_millis(20),
+                payload: Payload::Binary(vec![0, 1, 2, 255]),
+            },
+            Record {
+                direction: Direction::AgentToClient,
+                offset: Duration::from_millis(1500),
+                payload: Payload::Binary(Vec::new()),
+            },
+            Record {
+                direction: Direction::AgentToClient,
+                offset: Duration::from_secs(90),
+                payload: Payload::Close(1000, "bye ✓".to_string()),
+            },
+        ]
+    }
+
+    fn write_all(records: &[Record]) -> Vec<u8> {
+        let mut writer = RecordingWriter::new(Vec::new()).unwrap();
+        for record in records {
+            writer.write(record).unwrap();
+        }
+        writer.inner
+    }
+
+    fn read_all(bytes: &[u8]) -> io::Result<Vec<Record>> {
+        RecordingReader::new(bytes)?.collect()
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let bytes = write_all(&records());
+        assert_eq!(&bytes[..6], b"DGREC\x01");
+        assert_eq!(read_all(&bytes).unwrap(), records());
+    }
+
+    #[test]
+    fn an_empty_recording_has_no_records() {
+        assert!(read_all(&write_all(&[])).unwrap().is_empty());
+    }
+
+    #[test]
+    fn foreign_files_are_rejected() {
+        assert!(read_all(b"RIFF\0\0\0\0WAVE").is_err());
+        assert!(read_all(b"DGREC\x02").is_err());
+        assert!(read_all(b"DGR").is_err());
+    }
+
+    #[test]
+    fn truncated_records_are_errors() {
+        let bytes = write_all(&records());
+        let result = read_all(&bytes[..bytes.len() - 3]);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn oversized_lengths_are_refused_before_allocating() {
+        let mut bytes = write_all(&[]);
+        bytes.extend_from_slice(&[0, 1, 0, 0, 0, 0]);
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        let error = read_all(&bytes).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn unknown_directions_and_kinds_are_errors() {
+        let mut bytes = write_all(&[]);
+        bytes.extend_from_slice(&[2, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(read_all(&bytes).is_err());
+        let mut bytes = write_all(&[]);
+        bytes.extend_from_slice(&[0, 7, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(read_all(&bytes).is_err());
+    }
+
+    #[tokio::test]
+    async fn session_recorder_writes_a_readable_file() {
+        let path =
+            std::env::temp_dir().join(format!("recording-test-{}.dgrec", std::process::id()));
+        let recorder = SessionRecorder::create(&path).unwrap();
+        for record in records() {
+            recorder.record(record.direction, record.payload);
+        }
+        drop(recorder);
+
+        // The writer task flushes once the last recorder is gone
+        let mut read = Vec::new();
+        for _ in 0..100 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            read = read_file(&path).unwrap_or_default();
+            if read.len() == records().len() {
+                break;
+            }
+        }
+        std::fs::remove_file(&path).unwrap();
+        let payloads: Vec<Payload> = read.into_iter().map(|r| r.payload).collect();
+        let expected: Vec<Payload> = records().into_iter().map(|r| r.payload).collect();
+        assert_eq!(payloads, expected);
+    }
+}
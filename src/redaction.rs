@@ -0,0 +1,348 @@
+// PII redaction for transcripts and function calls.
+//
+// Built-in detectors find payment card numbers (Luhn-checked), US social
+// security numbers, email addresses and phone numbers; `[redaction]` can add
+// named regexes. Matches are replaced with `[REDACTED:<NAME>]`.
+//
+// The proxy applies redaction to `ConversationText` content, function call
+// arguments and function call results before a frame reaches logs,
+// recordings, webhooks or the event bus. With `redaction.redact_client` the
+// client also receives redacted `ConversationText`; function calls are
+// always forwarded intact so client-side functions keep working.
+
+use crate::config::{PiiDetector, RedactionConfig, RedactionPattern};
+use regex::{Captures, Regex};
+use serde_json::Value;
+use std::borrow::Cow;
+use std::sync::LazyLock;
+
+/// 13 to 19 digits, optionally grouped by spaces or dashes.
+static CARD: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\b\d(?:[ -]?\d){12,18}\b").expect("valid regex"));
+
+/// 123-45-6789, 123 45 6789 or 123456789.
+static SSN: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\b(\d{3})([- ]?)(\d{2})([- ]?)(\d{4})\b").expect("valid regex"));
+
+static EMAIL: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)\b[a-z0-9._%+-]+@[a-z0-9-]+(?:\.[a-z0-9-]+)*\.[a-z]{2,}\b")
+        .expect("valid regex")
+});
+
+/// North American numbers and `+<country code>` international numbers.
+static PHONE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?:\+\d{1,3}[ .-]?)?(?:\(\d{3}\)[ .-]?|\b\d{3}[ .-]?)\d{3}[ .-]?\d{4}\b|\+\d{1,3}(?:[ .-]?\d){6,12}\b",
+    )
+    .expect("valid regex")
+});
+
+/// Replaces PII in text and agent protocol messages. Cheap to create; holds
+/// the rules of the configuration it was built from.
+#[derive(Debug, Clone, Default)]
+pub struct Redactor {
+    detectors: Vec<PiiDetector>,
+    patterns: Vec<RedactionPattern>,
+    redact_client: bool,
+}
+
+impl Redactor {
+    /// Redactor for `[redaction]`; redacts nothing when it is disabled.
+    pub fn new(config: &RedactionConfig) -> Self {
+        if !config.enabled {
+            return Self::default();
+        }
+        Self {
+            detectors: config.detectors.clone(),
+            patterns: config.patterns.clone(),
+            redact_client: config.redact_client,
+        }
+    }
+
+    /// Whether any rule is configured.
+    pub fn is_active(&self) -> bool {
+        !self.detectors.is_empty() || !self.patterns.is_empty()
+    }
+
+    /// Whether transcripts forwarded to the client are redacted too.
+    pub fn redacts_client(&self) -> bool {
+        self.redact_client && self.is_active()
+    }
+
+    /// Replace every match of the configured rules in `text`.
+    pub fn redact<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut out = Cow::Borrowed(text);
+        // Cards before SSNs and phone numbers, whose patterns match parts of them
+        for detector in PiiDetector::ALL {
+            if !self.detectors.contains(&detector) {
+                continue;
+            }
+            let replaced = match detector {
+                PiiDetector::Card => replace(&CARD, &out, "CARD", |c| luhn_valid(&c[0])),
+                PiiDetector::Ssn => replace(&SSN, &out, "SSN", valid_ssn),
+                PiiDetector::Email => replace(&EMAIL, &out, "EMAIL", |_| true),
+                PiiDetector::Phone => replace(&PHONE, &out, "PHONE", |_| true),
+            };
+            if let Some(replaced) = replaced {
+                out = Cow::Owned(replaced);
+            }
+        }
+        for pattern in &self.patterns {
+            if let Some(replaced) = replace(
+                &pattern.regex,
+                &out,
+                &pattern.name.to_ascii_uppercase(),
+                |_| true,
+            ) {
+                out = Cow::Owned(replaced);
+            }
+        }
+        out
+    }
+
+    /// Redact a frame sent by the agent: `ConversationText` content,
+    /// `FunctionCallRequest` arguments and the results of server-side
+    /// functions in `FunctionCallResponse`.
+    pub fn agent_message<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        self.message(text, |kind, message| match kind {
+            "ConversationText" | "FunctionCallResponse" => self.redact_field(message, "content"),
+            "FunctionCallRequest" => {
+                let mut changed = false;
+                if let Some(functions) = message.get_mut("functions").and_then(Value::as_array_mut)
+                {
+                    for function in functions {
+                        changed |= self.redact_field(function, "arguments");
+                    }
+                }
+                changed
+            }
+            _ => false,
+        })
+    }
+
+    /// Redact a frame sent by the client: `FunctionCallResponse` content and
+    /// injected user messages.
+    pub fn client_message<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        self.message(text, |kind, message| match kind {
+            "FunctionCallResponse" => self.redact_field(message, "content"),
+            "InjectUserMessage" => self.redact_field(message, "content"),
+            _ => false,
+        })
+    }
+
+    /// Redact only the `ConversationText` content of an agent frame, for
+    /// forwarding to the client.
+    pub fn transcript<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        self.message(text, |kind, message| {
+            kind == "ConversationText" && self.redact_field(message, "content")
+        })
+    }
+
+    /// Apply `edit` to a JSON message, re-encoding it only if it changed.
+    fn message<'a>(
+        &self,
+        text: &'a str,
+        edit: impl FnOnce(&str, &mut Value) -> bool,
+    ) -> Cow<'a, str> {
+        if !self.is_active() {
+            return Cow::Borrowed(text);
+        }
+        let Ok(mut message) = serde_json::from_str::<Value>(text) else {
+            return Cow::Borrowed(text);
+        };
+        let Some(kind) = message
+            .get("type")
+            .and_then(Value::as_str)
+            .map(str::to_string)
+        else {
+            return Cow::Borrowed(text);
+        };
+        if edit(&kind, &mut message) {
+            Cow::Owned(message.to_string())
+        } else {
+            Cow::Borrowed(text)
+        }
+    }
+
+    /// Redact the string field `key` of `object`; true if anything changed.
+    fn redact_field(&self, object: &mut Value, key: &str) -> bool {
+        let Some(Value::String(value)) = object.get_mut(key) else {
+            return false;
+        };
+        match self.redact(value) {
+            Cow::Borrowed(_) => false,
+            Cow::Owned(redacted) => {
+                *value = redacted;
+                true
+            }
+        }
+    }
+}
+
+/// Replace the matches of `re` accepted by `accept` with a `[REDACTED:<label>]`
+/// marker; `None` if nothing was replaced.
+fn replace(
+    re: &Regex,
+    text: &str,
+    label: &str,
+    accept: impl Fn(&Captures) -> bool,
+) -> Option<String> {
+    let mut replaced = false;
+    let out = re.replace_all(text, |caps: &Captures| {
+        if accept(caps) {
+            replaced = true;
+            format!("[REDACTED:{}]", label)
+        } else {
+            caps[0].to_string()
+        }
+    });
+    replaced.then(|| out.into_owned())
+}
+
+/// Luhn checksum over the digits of `number`.
+fn luhn_valid(number: &str) -> bool {
+    let digits: Vec<u32> = number.chars().filter_map(|c| c.to_digit(10)).collect();
+    if !(13..=19).contains(&digits.len()) {
+        return false;
+    }
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, &d)| match (i % 2 == 1, d * 2) {
+            (true, doubled) if doubled > 9 => doubled - 9,
+            (true, doubled) => doubled,
+            (false, _) => d,
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+/// Reject separators used inconsistently and numbers the SSA never issues
+/// (area 000, 666 or 9xx, group 00, serial 0000).
+fn valid_ssn(caps: &Captures) -> bool {
+    caps[2] == caps[4]
+        && !matches!(&caps[1], "000" | "666")
+        && !caps[1].starts_with('9')
+        && &caps[3] != "00"
+        && &caps[5] != "0000"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redactor() -> Redactor {
+        Redactor::new(&RedactionConfig {
+            enabled: true,
+            detectors: PiiDetector::ALL.to_vec(),
+            patterns: vec![RedactionPattern {
+                name: "order".to_string(),
+                regex: Regex::new(r"\bORD-\d{6}\b").unwrap(),
+            }],
+            redact_client: false,
+        })
+    }
+
+    fn ssn(text: &str) -> bool {
+        SSN.captures(text).is_some_and(|caps| valid_ssn(&caps))
+    }
+
+    #[test]
+    fn luhn_accepts_valid_card_numbers() {
+        assert!(luhn_valid("4111111111111111"));
+        assert!(luhn_valid("4111 1111 1111 1111"));
+        assert!(luhn_valid("5500-0000-0000-0004"));
+        assert!(luhn_valid("378282246310005"));
+    }
+
+    #[test]
+    fn luhn_rejects_bad_checksums_and_lengths() {
+        assert!(!luhn_valid("4111111111111112"));
+        assert!(!luhn_valid("411111111111"));
+        assert!(!luhn_valid("41111111111111111111"));
+    }
+
+    #[test]
+    fn ssns_need_consistent_separators() {
+        assert!(ssn("123-45-6789"));
+        assert!(ssn("123 45 6789"));
+        assert!(ssn("123456789"));
+        assert!(!ssn("123-45 6789"));
+        assert!(!ssn("12345-6789"));
+    }
+
+    #[test]
+    fn ssns_never_issued_are_ignored() {
+        for number in [
+            "000-12-3456",
+            "666-12-3456",
+            "912-34-5678",
+            "123-00-4567",
+            "123-45-0000",
+        ] {
+            assert!(!ssn(number), "{}", number);
+        }
+    }
+
+    #[test]
+    fn redact_replaces_every_detector_and_pattern() {
+        let text = "card 4111 1111 1111 1111, ssn 123-45-6789, mail a.b@example.com, \
+                    call (555) 123-4567 about ORD-123456";
+        assert_eq!(
+            redactor().redact(text),
+            "card [REDACTED:CARD], ssn [REDACTED:SSN], mail [REDACTED:EMAIL], \
+             call [REDACTED:PHONE] about [REDACTED:ORDER]"
+        );
+    }
+
+    #[test]
+    fn redact_leaves_clean_text_borrowed() {
+        assert!(matches!(
+            redactor().redact("order 4111111111111112 is late"),
+            Cow::Borrowed(_)
+        ));
+    }
+
+    #[test]
+    fn disabled_redaction_changes_nothing() {
+        let redactor = Redactor::new(&RedactionConfig {
+            enabled: false,
+            detectors: PiiDetector::ALL.to_vec(),
+            patterns: Vec::new(),
+            redact_client: true,
+        });
+        assert!(!redactor.is_active());
+        assert_eq!(redactor.redact("a.b@example.com"), "a.b@example.com");
+    }
+
+    #[test]
+    fn agent_messages_redact_transcripts_and_function_calls() {
+        let redactor = redactor();
+        let redacted = redactor.agent_message(
+            r#"{"type":"FunctionCallRequest","functions":[{"id":"f1","arguments":"{\"email\":\"a@example.com\"}"}]}"#,
+        );
+        assert!(redacted.contains("[REDACTED:EMAIL]"));
+        assert!(!redacted.contains("a@example.com"));
+
+        let redacted = redactor.agent_message(
+            r#"{"type":"FunctionCallResponse","id":"f1","name":"lookup","content":"ssn 123-45-6789"}"#,
+        );
+        let message: Value = serde_json::from_str(&redacted).unwrap();
+        assert_eq!(message["content"], "ssn [REDACTED:SSN]");
+        assert_eq!(message["name"], "lookup");
+    }
+
+    #[test]
+    fn client_messages_redact_function_results_and_injected_text() {
+        let redactor = redactor();
+        let redacted = redactor
+            .client_message(r#"{"type":"InjectUserMessage","content":"I'm a@example.com"}"#);
+        assert!(redacted.contains("[REDACTED:EMAIL]"));
+        let settings = r#"{"type":"Settings","greeting":"a@example.com"}"#;
+        assert!(matches!(
+            redactor.client_message(settings),
+            Cow::Borrowed(_)
+        ));
+    }
+}
@@ -0,0 +1,276 @@
+// Replay a recorded session from either side.
+//
+// Client-side replay re-sends the client half of a recording (Settings,
+// audio, injected messages) to a Deepgram-compatible endpoint. Agent-side
+// replay listens for one connection and plays the recorded Deepgram half to
+// it, so a proxy pointed at the listener can be debugged without Deepgram.
+// Either way frames keep their original timing, the text the other end
+// sends is handed to the caller, and everything can be captured to a new
+// recording.
+
+use crate::recording::{Direction, Payload, Record, RecordingWriter};
+use futures_util::{SinkExt, StreamExt};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio_tungstenite::{WebSocketStream, accept_async, connect_async, tungstenite};
+
+/// How to authenticate against the replay target.
+pub enum ReplayAuth {
+    /// Deepgram API key, sent as `Authorization: Token <key>`.
+    ApiKey(String),
+    /// Proxy session token, sent as the `access_token.<jwt>` subprotocol.
+    SessionToken(String),
+}
+
+/// Options for a client-side replay.
+pub struct ReplayOptions {
+    pub url: String,
+    pub auth: ReplayAuth,
+    /// Playback speed multiplier; 0 sends frames as fast as possible.
+    pub speed: f64,
+    /// How long to keep reading responses after the last client frame.
+    pub linger: Duration,
+    /// Where to write the replayed session, if anywhere.
+    pub output: Option<PathBuf>,
+}
+
+/// Options for an agent-side replay.
+pub struct AgentReplayOptions {
+    /// Playback speed multiplier; 0 sends frames as fast as possible.
+    pub speed: f64,
+    /// How long to keep reading client frames after the last agent frame.
+    pub linger: Duration,
+    /// Where to write the replayed session, if anywhere.
+    pub output: Option<PathBuf>,
+}
+
+/// Counts reported when a replay finishes.
+#[derive(Debug, Default)]
+pub struct ReplaySummary {
+    pub frames_sent: usize,
+    pub text_received: usize,
+    pub binary_bytes_received: usize,
+    pub close: Option<(u16, String)>,
+}
+
+/// Feed the client side of `records` into a new session at `opts.url`,
+/// calling `on_text` with each text message the endpoint sends.
+pub async fn replay_client_side(
+    records: &[Record],
+    opts: ReplayOptions,
+    on_text: impl FnMut(&str),
+) -> Result<ReplaySummary, String> {
+    let url = url::Url::parse(&opts.url).map_err(|e| format!("invalid URL: {}", e))?;
+    let mut builder = tungstenite::http::Request::builder()
+        .uri(opts.url.as_str())
+        .header("Host", url.host_str().unwrap_or_default())
+        .header("Connection", "Upgrade")
+        .header("Upgrade", "websocket")
+        .header("Sec-WebSocket-Version", "13")
+        .header(
+            "Sec-WebSocket-Key",
+            tungstenite::handshake::client::generate_key(),
+        );
+    builder = match &opts.auth {
+        ReplayAuth::ApiKey(key) => builder.header("Authorization", format!("Token {}", key)),
+        ReplayAuth::SessionToken(token) => {
+            builder.header("Sec-WebSocket-Protocol", format!("access_token.{}", token))
+        }
+    };
+    let request = builder
+        .body(())
+        .map_err(|e| format!("failed to build request: {}", e))?;
+
+    let (ws, _) = connect_async(request)
+        .await
+        .map_err(|e| format!("failed to connect to {}: {}", opts.url, e))?;
+    play(
+        ws,
+        records,
+        Direction::ClientToAgent,
+        opts.speed,
+        opts.linger,
+        opts.output,
+        on_text,
+    )
+    .await
+}
+
+/// Accept one connection on `listener` and play the agent side of `records`
+/// to it, as if it were the Deepgram endpoint, calling `on_text` with each
+/// text message the client sends.
+pub async fn replay_agent_side(
+    records: &[Record],
+    listener: TcpListener,
+    opts: AgentReplayOptions,
+    on_text: impl FnMut(&str),
+) -> Result<ReplaySummary, String> {
+    let (tcp, _) = listener
+        .accept()
+        .await
+        .map_err(|e| format!("accept failed: {}", e))?;
+    let ws = accept_async(tcp)
+        .await
+        .map_err(|e| format!("WebSocket handshake failed: {}", e))?;
+    play(
+        ws,
+        records,
+        Direction::AgentToClient,
+        opts.speed,
+        opts.linger,
+        opts.output,
+        on_text,
+    )
+    .await
+}
+
+/// Send the `side` frames of `records` over `ws` on their original schedule
+/// while reading whatever the other end sends.
+async fn play<S>(
+    ws: WebSocketStream<S>,
+    records: &[Record],
+    side: Direction,
+    speed: f64,
+    linger: Duration,
+    output: Option<PathBuf>,
+    mut on_text: impl FnMut(&str),
+) -> Result<ReplaySummary, String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let peer = match side {
+        Direction::ClientToAgent => Direction::AgentToClient,
+        Direction::AgentToClient => Direction::ClientToAgent,
+    };
+    let (mut sender, mut receiver) = ws.split();
+
+    let mut writer = match &output {
+        Some(path) => Some(
+            RecordingWriter::create(path)
+                .map_err(|e| format!("cannot create {}: {}", path.display(), e))?,
+        ),
+        None => None,
+    };
+
+    let started = Instant::now();
+    let mut summary = ReplaySummary::default();
+    // Frames up to the point where the recorded side hung up
+    let mut outgoing = records
+        .iter()
+        .filter(|r| r.direction == side)
+        .take_while(|r| !matches!(r.payload, Payload::Close(..)))
+        .peekable();
+    let recorded_close = records.iter().find_map(|r| match &r.payload {
+        Payload::Close(code, reason) if r.direction == side => Some((*code, reason.clone())),
+        _ => None,
+    });
+    let mut deadline: Option<Instant> = None;
+    let mut peer_closed = false;
+
+    loop {
+        // Time until the next frame is due (or until linger expires)
+        let wait = match outgoing.peek() {
+            Some(next) if speed > 0.0 => {
+                next.offset.div_f64(speed).saturating_sub(started.elapsed())
+            }
+            Some(_) => Duration::ZERO,
+            None => {
+                let until = *deadline.get_or_insert_with(|| Instant::now() + linger);
+                until.saturating_duration_since(Instant::now())
+            }
+        };
+
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {
+                let Some(record) = outgoing.next() else { break };
+                let msg = match &record.payload {
+                    Payload::Text(text) => tungstenite::Message::Text(text.clone()),
+                    Payload::Binary(data) => tungstenite::Message::Binary(data.clone()),
+                    Payload::Close(..) => unreachable!("close frames are filtered out"),
+                };
+                if let Some(w) = writer.as_mut() {
+                    record_frame(w, side, started, &record.payload)?;
+                }
+                sender
+                    .send(msg)
+                    .await
+                    .map_err(|e| format!("send failed: {}", e))?;
+                summary.frames_sent += 1;
+            }
+            msg = receiver.next() => {
+                let payload = match msg {
+                    Some(Ok(tungstenite::Message::Text(text))) => {
+                        on_text(&text);
+                        summary.text_received += 1;
+                        Payload::Text(text)
+                    }
+                    Some(Ok(tungstenite::Message::Binary(data))) => {
+                        summary.binary_bytes_received += data.len();
+                        Payload::Binary(data)
+                    }
+                    Some(Ok(tungstenite::Message::Close(frame))) => {
+                        let (code, reason) = frame
+                            .map(|f| (u16::from(f.code), f.reason.to_string()))
+                            .unwrap_or((1005, String::new()));
+                        summary.close = Some((code, reason.clone()));
+                        Payload::Close(code, reason)
+                    }
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => return Err(format!("read failed: {}", e)),
+                    None => break,
+                };
+                let closed = matches!(payload, Payload::Close(..));
+                if let Some(w) = writer.as_mut() {
+                    record_frame(w, peer, started, &payload)?;
+                }
+                if closed {
+                    peer_closed = true;
+                    break;
+                }
+            }
+        }
+    }
+
+    // End the session the way the recorded side did
+    match recorded_close {
+        Some((code, reason)) if !peer_closed && code != 1005 => {
+            if let Some(w) = writer.as_mut() {
+                record_frame(w, side, started, &Payload::Close(code, reason.clone()))?;
+            }
+            let _ = sender
+                .send(tungstenite::Message::Close(Some(
+                    tungstenite::protocol::CloseFrame {
+                        code: code.into(),
+                        reason: reason.into(),
+                    },
+                )))
+                .await;
+        }
+        _ => {
+            let _ = sender.close().await;
+        }
+    }
+    if let Some(mut w) = writer {
+        w.flush()
+            .map_err(|e| format!("failed to write recording: {}", e))?;
+    }
+    Ok(summary)
+}
+
+fn record_frame<W: std::io::Write>(
+    writer: &mut RecordingWriter<W>,
+    direction: Direction,
+    started: Instant,
+    payload: &Payload,
+) -> Result<(), String> {
+    let record = Record {
+        direction,
+        offset: started.elapsed(),
+        payload: payload.clone(),
+    };
+    writer
+        .write(&record)
+        .map_err(|e| format!("failed to write recording: {}", e))
+}
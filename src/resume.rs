@@ -0,0 +1,116 @@
+// Session resumption on the client leg.
+//
+// With `[resume] grace_secs` above zero, every session is registered here
+// under a random resume token, which the proxy sends the client in a
+// `SessionResumable` message when the session starts. A client that
+// reconnects with the `resume.<token>` subprotocol, alongside an access
+// token for the same tenant and subject, has its new connection handed to
+// the running session instead of starting another one. Clients may append
+// the number of text and binary messages they have received on the session
+// (`resume.<token>.<received>`, counting `SessionResumable`) to be sent
+// everything after those, including messages that reached a connection
+// which had already failed.
+//
+// What the session does meanwhile is up to the proxy: when the client's
+// connection drops without a close frame, it keeps the Deepgram connection
+// open for the grace window and keeps the agent's output for the client.
+// The token is valid for as long as the session runs, so a client can
+// resume more than once, and a reconnect also replaces a connection whose
+// loss the proxy has not noticed yet.
+
+use axum::extract::ws::WebSocket;
+use rand::RngCore;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+/// A client connection resuming a session.
+pub struct Reconnect {
+    pub socket: WebSocket,
+    /// Messages the client has received on the session, if it said.
+    pub received: Option<u64>,
+}
+
+/// A session clients may resume.
+struct Entry {
+    tenant: String,
+    subject: Option<String>,
+    attach: mpsc::Sender<Reconnect>,
+}
+
+/// Resumable sessions by resume token, shared by all handlers.
+#[derive(Clone, Default)]
+pub struct Resumptions {
+    sessions: Arc<Mutex<HashMap<String, Entry>>>,
+}
+
+impl Resumptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn sessions(&self) -> std::sync::MutexGuard<'_, HashMap<String, Entry>> {
+        self.sessions.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Make a new session of `subject` in `tenant` resumable, for as long as
+    /// the returned handle lives.
+    pub fn register(&self, tenant: &str, subject: Option<&str>) -> Resumable {
+        let mut token = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut token);
+        let token = hex::encode(token);
+        let (attach, attached) = mpsc::channel(1);
+        self.sessions().insert(
+            token.clone(),
+            Entry {
+                tenant: tenant.to_string(),
+                subject: subject.map(str::to_string),
+                attach,
+            },
+        );
+        Resumable {
+            token,
+            attached,
+            sessions: self.clone(),
+        }
+    }
+
+    /// Where to hand a reconnect with `token` over to, if it names a running
+    /// session of `subject` in `tenant`.
+    pub fn find(
+        &self,
+        token: &str,
+        tenant: &str,
+        subject: Option<&str>,
+    ) -> Option<mpsc::Sender<Reconnect>> {
+        let sessions = self.sessions();
+        let entry = sessions.get(token)?;
+        (entry.tenant == tenant && entry.subject.as_deref() == subject)
+            .then(|| entry.attach.clone())
+    }
+}
+
+/// A session's registration; the token stops working when it is dropped.
+pub struct Resumable {
+    token: String,
+    attached: mpsc::Receiver<Reconnect>,
+    sessions: Resumptions,
+}
+
+impl Resumable {
+    /// The token clients resume the session with.
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// The next connection of a client resuming the session.
+    pub async fn reconnected(&mut self) -> Option<Reconnect> {
+        self.attached.recv().await
+    }
+}
+
+impl Drop for Resumable {
+    fn drop(&mut self) {
+        self.sessions.sessions().remove(&self.token);
+    }
+}
@@ -0,0 +1,874 @@
+// HTTP routes: session tokens, the voice agent WebSocket, metadata,
+// health, admin API and the optional frontend fallback.
+
+#[cfg(feature = "direct")]
+use crate::auth::validate_token;
+use crate::auth::{Claims, ClientIdentity, constant_time_eq, issue_token, validate_ws_token};
+use crate::config::{AppConfig, Profile, SharedConfig, Tenant};
+#[cfg(feature = "direct")]
+use crate::direct::{DeepgramGrants, GrantIssuer};
+#[cfg(feature = "events")]
+use crate::events::{EventBus, EventSink};
+#[cfg(feature = "guardrails")]
+use crate::guardrails::Guardrails;
+#[cfg(feature = "metrics")]
+use crate::latency::Latency;
+use crate::metadata::{METADATA_FILE, MetadataError, read_metadata};
+use crate::pool::Pool;
+use crate::protocol::AgentMessage;
+use crate::proxy::ProxySession;
+use crate::ratelimit::{self, RateLimiter, RouteClass};
+use crate::resume::{Reconnect, Resumptions};
+use crate::telemetry;
+use crate::upstream::Regions;
+use crate::usage::{self, Usage, UsageQuery};
+#[cfg(feature = "webhooks")]
+use crate::webhooks::Webhooks;
+use axum::{
+    Extension, Router,
+    extract::ws::{Message, WebSocketUpgrade},
+    extract::{Query, State},
+    http::{HeaderName, Method, StatusCode},
+    middleware,
+    response::{IntoResponse, Json, Response},
+    routing::{get, post},
+};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+use tracing::{Instrument, error, info, warn};
+#[cfg(feature = "recording")]
+use {crate::config::RecordingMode, crate::recording::SessionRecorder, serde_json::Value};
+#[cfg(feature = "frontend")]
+use {
+    crate::frontend::Frontend,
+    axum::http::{HeaderMap, Uri},
+};
+
+// ============================================================================
+// ROUTER
+// ============================================================================
+
+/// Build the voice agent routes for `config`.
+///
+/// Pass an `AppConfig` for a fixed configuration, or a `SharedConfig` to keep
+/// a handle for reloading it later. Routes are relative, so the router can be
+/// mounted with `Router::nest("/voice", ...)` or merged into another app.
+/// When the frontend is enabled it is installed as the router's fallback.
+/// With `[events]` configured, call this from within a Tokio runtime.
+///
+/// Serve it with `into_make_service_with_connect_info::<SocketAddr>()`:
+/// per-IP rate limits need the client address, and are skipped (with a
+/// warning) without it.
+pub fn voice_agent_router(config: impl Into<SharedConfig>) -> Router {
+    let config = config.into();
+    #[cfg(feature = "events")]
+    let events = EventBus::from_config(&config.current().events);
+    #[cfg(feature = "direct")]
+    let grants = Arc::new(DeepgramGrants::with_egress(&config.current().egress));
+    build_router(
+        config,
+        #[cfg(feature = "events")]
+        events,
+        #[cfg(feature = "direct")]
+        grants,
+    )
+}
+
+/// Like `voice_agent_router`, but publishes session events to `sink`
+/// instead of the `[events]` sink.
+#[cfg(feature = "events")]
+pub fn voice_agent_router_with_sink(
+    config: impl Into<SharedConfig>,
+    sink: Arc<dyn EventSink>,
+) -> Router {
+    let config = config.into();
+    let events = EventBus::new(sink, config.current().events.buffer);
+    #[cfg(feature = "direct")]
+    let grants = Arc::new(DeepgramGrants::with_egress(&config.current().egress));
+    build_router(
+        config,
+        Some(events),
+        #[cfg(feature = "direct")]
+        grants,
+    )
+}
+
+/// Like `voice_agent_router`, but mints direct mode credentials with
+/// `grants` instead of the Deepgram auth API.
+#[cfg(feature = "direct")]
+pub fn voice_agent_router_with_grants(
+    config: impl Into<SharedConfig>,
+    grants: Arc<dyn GrantIssuer>,
+) -> Router {
+    let config = config.into();
+    #[cfg(feature = "events")]
+    let events = EventBus::from_config(&config.current().events);
+    build_router(
+        config,
+        #[cfg(feature = "events")]
+        events,
+        grants,
+    )
+}
+
+fn build_router(
+    config: SharedConfig,
+    #[cfg(feature = "events")] events: Option<EventBus>,
+    #[cfg(feature = "direct")] grants: Arc<dyn GrantIssuer>,
+) -> Router {
+    let startup = config.current();
+    let state = AppState {
+        config: config.clone(),
+        sessions: Arc::new(Mutex::new(SessionCounts::default())),
+        rate_limiter: Arc::new(RateLimiter::default()),
+        usage: Usage::new(),
+        regions: Regions::new(),
+        pool: Pool::new(),
+        resumptions: Resumptions::new(),
+        #[cfg(feature = "metrics")]
+        latency: Latency::new(),
+        #[cfg(feature = "webhooks")]
+        webhooks: Webhooks::new(),
+        #[cfg(feature = "guardrails")]
+        guardrails: Guardrails::new(),
+        #[cfg(feature = "events")]
+        events,
+        #[cfg(feature = "direct")]
+        grants,
+    };
+
+    let limited = |class: RouteClass| {
+        middleware::from_fn_with_state((state.clone(), class), ratelimit::limit)
+    };
+
+    // Admin routes exist only when an admin token is configured
+    let admin = if startup.admin_token.is_some() {
+        let admin = Router::new()
+            .route("/api/admin/reload", post(handle_admin_reload))
+            .route("/api/usage", get(handle_usage));
+        #[cfg(feature = "metrics")]
+        let admin = admin.route(
+            "/api/admin/sessions/{id}/latency",
+            get(handle_session_latency),
+        );
+        admin
+            .route_layer(middleware::from_fn_with_state(state.clone(), require_admin))
+            .route_layer(limited(RouteClass::Admin))
+    } else {
+        Router::new()
+    };
+
+    let router = Router::new().route(
+        "/api/session",
+        get(handle_session).route_layer(limited(RouteClass::Session)),
+    );
+    #[cfg(feature = "direct")]
+    let router = router.route(
+        "/api/direct-session",
+        post(handle_direct_session).route_layer(limited(RouteClass::Session)),
+    );
+    let router = router
+        .route("/api/metadata", get(handle_metadata))
+        .route(
+            "/api/voice-agent",
+            get(handle_voice_agent).route_layer(limited(RouteClass::VoiceAgent)),
+        )
+        .route("/health", get(handle_health))
+        .merge(admin);
+
+    // Prometheus scrape endpoint, unless another recorder was installed first
+    #[cfg(feature = "metrics")]
+    let router = match startup.metrics.enabled {
+        true if state.latency.prometheus().is_some() => {
+            router.route("/metrics", get(handle_metrics))
+        }
+        _ => router,
+    };
+
+    // Serve the frontend for anything the API does not handle
+    #[cfg(feature = "frontend")]
+    let router = if startup.frontend.enabled {
+        let frontend = Arc::new(Frontend::new(&startup.frontend, &startup.base_path));
+        router.fallback(
+            move |method: Method, uri: Uri, headers: HeaderMap| async move {
+                frontend.respond(&method, &uri, &headers).await
+            },
+        )
+    } else {
+        router
+    };
+
+    router.layer(cors_layer(&config)).with_state(state)
+}
+
+// ============================================================================
+// APPLICATION STATE
+// ============================================================================
+
+/// State shared by all handlers.
+#[derive(Clone)]
+pub(crate) struct AppState {
+    pub(crate) config: SharedConfig,
+    sessions: Arc<Mutex<SessionCounts>>,
+    pub(crate) rate_limiter: Arc<RateLimiter>,
+    usage: Usage,
+    regions: Regions,
+    pool: Pool,
+    resumptions: Resumptions,
+    #[cfg(feature = "metrics")]
+    latency: Latency,
+    #[cfg(feature = "webhooks")]
+    webhooks: Webhooks,
+    #[cfg(feature = "events")]
+    events: Option<EventBus>,
+    #[cfg(feature = "guardrails")]
+    guardrails: Guardrails,
+    #[cfg(feature = "direct")]
+    grants: Arc<dyn GrantIssuer>,
+}
+
+impl AppState {
+    /// Snapshot of the current configuration.
+    pub(crate) fn config(&self) -> Arc<AppConfig> {
+        self.config.current()
+    }
+}
+
+/// Active sessions, overall and per tenant.
+#[derive(Default)]
+struct SessionCounts {
+    total: usize,
+    per_tenant: HashMap<String, usize>,
+}
+
+/// Decrements the active session counts when a session ends.
+struct SessionGuard {
+    counts: Arc<Mutex<SessionCounts>>,
+    tenant: String,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        counts.total -= 1;
+        if let Some(active) = counts.per_tenant.get_mut(&self.tenant) {
+            *active -= 1;
+            if *active == 0 {
+                counts.per_tenant.remove(&self.tenant);
+            }
+        }
+    }
+}
+
+// ============================================================================
+// HTTP HANDLERS
+// ============================================================================
+
+/// GET /api/session - Issue a signed JWT session token.
+/// With mutual TLS, the verified client certificate subject becomes the token subject.
+/// A tenant listing the client's certificate subject becomes the token's
+/// `tenant`; headers such as Origin are never trusted for this, since any
+/// client can set them.
+async fn handle_session(
+    State(state): State<AppState>,
+    #[cfg_attr(not(feature = "otel"), allow(unused_variables))] headers: axum::http::HeaderMap,
+    identity: Option<Extension<ClientIdentity>>,
+) -> impl IntoResponse {
+    let config = state.config();
+    let mut claims = Claims::new(config.reloadable.limits.token_ttl_secs);
+    claims.sub = identity.map(|Extension(id)| id.subject);
+    let span = tracing::info_span!(
+        "GET /api/session",
+        otel.kind = "server",
+        enduser.id = claims.sub.as_deref()
+    );
+    #[cfg(feature = "otel")]
+    telemetry::set_parent(&span, &headers);
+    let _entered = span.enter();
+
+    let tenants = &config.reloadable.tenants;
+    match claims
+        .sub
+        .as_deref()
+        .and_then(|sub| tenants.for_client(sub))
+    {
+        Some(tenant) => {
+            claims.extra.insert("tenant".to_string(), json!(tenant.id));
+        }
+        None if tenants.default.is_none() => {
+            warn!("Session token refused: no tenant for this client");
+            return (
+                StatusCode::FORBIDDEN,
+                Json(json!({
+                    "error": "FORBIDDEN",
+                    "message": "No tenant is configured for this client"
+                })),
+            )
+                .into_response();
+        }
+        None => {}
+    }
+
+    match issue_token(&config.session_secret, &claims) {
+        Ok(token) => Json(json!({ "token": token })).into_response(),
+        Err(_) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": "Failed to generate session token"})),
+        )
+            .into_response(),
+    }
+}
+
+/// POST /api/direct-session - Exchange a session token (`Authorization:
+/// Bearer <jwt>`) for a short-lived Deepgram access token, the agent URL and
+/// the Settings profile, so the browser can connect to Deepgram directly.
+#[cfg(feature = "direct")]
+async fn handle_direct_session(
+    State(state): State<AppState>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    let config = state.config();
+    let direct = &config.reloadable.direct;
+    if !direct.enabled {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "NOT_FOUND", "message": "Direct mode is disabled"})),
+        )
+            .into_response();
+    }
+
+    let claims = headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .and_then(|token| validate_token(token.trim(), &config.session_secret).ok());
+    let Some(claims) = claims else {
+        warn!("Direct session rejected: invalid or missing token");
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "UNAUTHORIZED", "message": "Session token required"})),
+        )
+            .into_response();
+    };
+    let (tenant, profile) = match resolve_session(&config, &claims) {
+        Ok(resolved) => resolved,
+        Err(reason) => {
+            warn!("Direct session rejected: {}", reason);
+            return (
+                StatusCode::FORBIDDEN,
+                Json(json!({"error": "FORBIDDEN", "message": reason})),
+            )
+                .into_response();
+        }
+    };
+
+    // The browser cannot set handshake headers, only the query parameters
+    let upstream = state.regions.upstream(&profile, &config.egress).await;
+    let endpoint = upstream
+        .endpoints(&tenant)
+        .first()
+        .copied()
+        .unwrap_or(tenant.agent_url.as_str());
+    let agent_url = match upstream.url(endpoint) {
+        Ok(url) => url.to_string(),
+        Err(_) => tenant.agent_url.clone(),
+    };
+
+    match state.grants.grant(direct, &tenant).await {
+        Ok(grant) => {
+            info!(
+                "Issued direct session grant for tenant {} ({}s)",
+                tenant.id, grant.expires_in
+            );
+            Json(json!({
+                "access_token": grant.access_token.expose(),
+                "expires_in": grant.expires_in,
+                "agent_url": agent_url,
+                "profile": claims.profile.as_deref().unwrap_or("default"),
+                "settings": profile.settings,
+            }))
+            .into_response()
+        }
+        Err(e) => {
+            error!("Deepgram grant for tenant {} failed: {}", tenant.id, e);
+            (
+                StatusCode::BAD_GATEWAY,
+                Json(json!({
+                    "error": "GRANT_FAILED",
+                    "message": "Failed to obtain Deepgram credentials"
+                })),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// GET /api/metadata - Return project metadata from deepgram.toml.
+async fn handle_metadata() -> impl IntoResponse {
+    match read_metadata(Path::new(METADATA_FILE)) {
+        Ok(meta) => Json(meta).into_response(),
+        Err(e) => {
+            error!("Error loading metadata: {}", e);
+            let message = match e {
+                MetadataError::Read(_) => "Failed to read metadata from deepgram.toml",
+                MetadataError::Parse(_) => "Failed to parse deepgram.toml",
+                MetadataError::MissingMeta => "Missing [meta] section in deepgram.toml",
+            };
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "INTERNAL_SERVER_ERROR",
+                    "message": message
+                })),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// GET /health - Health check endpoint.
+async fn handle_health() -> impl IntoResponse {
+    Json(json!({ "status": "ok" }))
+}
+
+// ============================================================================
+// ADMIN API
+// ============================================================================
+
+/// Middleware requiring `Authorization: Bearer <admin.token>`.
+async fn require_admin(
+    State(state): State<AppState>,
+    req: axum::extract::Request,
+    next: middleware::Next,
+) -> Response {
+    let config = state.config();
+    let presented = req
+        .headers()
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match (&config.admin_token, presented) {
+        (Some(expected), Some(token))
+            if constant_time_eq(expected.as_bytes(), token.as_bytes()) =>
+        {
+            next.run(req).await
+        }
+        _ => {
+            warn!("Admin request rejected: invalid or missing admin token");
+            (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({"error": "UNAUTHORIZED", "message": "Admin token required"})),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// POST /api/admin/reload - Reload configuration, same as SIGHUP.
+async fn handle_admin_reload(State(state): State<AppState>) -> impl IntoResponse {
+    match state.config.reload() {
+        Ok(restart_required) => Json(json!({
+            "status": "reloaded",
+            "restart_required": restart_required
+        }))
+        .into_response(),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": "INVALID_CONFIGURATION",
+                "message": "Configuration reload failed, previous config kept",
+                "details": e.errors
+            })),
+        )
+            .into_response(),
+    }
+}
+
+/// GET /api/usage - Session usage from `usage.file`, per session or totalled
+/// by `group_by` (subject, tenant, profile, day), between the `from` and `to`
+/// dates; `format=csv` for a CSV download.
+async fn handle_usage(State(state): State<AppState>, Query(query): Query<UsageQuery>) -> Response {
+    let config = state.config();
+    let bad_request = |message: String| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "INVALID_QUERY", "message": message})),
+        )
+            .into_response()
+    };
+    let csv = match query.csv() {
+        Ok(csv) => csv,
+        Err(e) => return bad_request(e),
+    };
+    match usage::report(&config.reloadable.usage.file, &query).await {
+        Ok(report) if csv => (
+            [
+                (axum::http::header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+                (
+                    axum::http::header::CONTENT_DISPOSITION,
+                    "attachment; filename=\"usage.csv\"",
+                ),
+            ],
+            report.to_csv(),
+        )
+            .into_response(),
+        Ok(report) => Json(report.to_json()).into_response(),
+        Err(usage::ReportError::Query(e)) => bad_request(e),
+        Err(e) => {
+            error!("Usage report failed: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "INTERNAL_SERVER_ERROR",
+                    "message": "Failed to read usage records"
+                })),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// GET /api/admin/sessions/{id}/latency - Per-turn latencies of a live or
+/// recent session, with a summary of each stage.
+#[cfg(feature = "metrics")]
+async fn handle_session_latency(
+    State(state): State<AppState>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+) -> Response {
+    match state.latency.report(&id) {
+        Some(report) => Json(report.to_json()).into_response(),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "error": "NOT_FOUND",
+                "message": "No latency report for this session"
+            })),
+        )
+            .into_response(),
+    }
+}
+
+/// GET /metrics - Prometheus text exposition of the latency histograms.
+#[cfg(feature = "metrics")]
+async fn handle_metrics(State(state): State<AppState>) -> Response {
+    let Some(prometheus) = state.latency.prometheus() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    prometheus.run_upkeep();
+    (
+        [(
+            axum::http::header::CONTENT_TYPE,
+            "text/plain; version=0.0.4; charset=utf-8",
+        )],
+        prometheus.render(),
+    )
+        .into_response()
+}
+
+// ============================================================================
+// WEBSOCKET PROXY HANDLER
+// ============================================================================
+
+/// WS /api/voice-agent - Proxy WebSocket connections to Deepgram's Voice Agent API.
+/// Forwards all messages (JSON and binary) bidirectionally without modification.
+async fn handle_voice_agent(
+    State(state): State<AppState>,
+    headers: axum::http::HeaderMap,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    let config = state.config();
+
+    // Browsers cannot be stopped from opening cross-site WebSockets by CORS,
+    // so check the Origin header against the allowlist ourselves.
+    if let Err(reason) = check_ws_origin(&config, &headers) {
+        warn!("WebSocket rejected: {}", reason);
+        return (StatusCode::FORBIDDEN, reason).into_response();
+    }
+
+    // Extract and validate JWT from access_token.<jwt> subprotocol.
+    let protocols: Vec<String> = headers
+        .get("sec-websocket-protocol")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect();
+    let (valid_proto, claims) = match validate_ws_token(&protocols, &config.session_secret) {
+        Some(valid) => valid,
+        None => {
+            warn!("WebSocket auth failed: invalid or missing token");
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+    };
+
+    let (tenant, profile) = match resolve_session(&config, &claims) {
+        Ok(resolved) => resolved,
+        Err(reason) => {
+            warn!("WebSocket rejected: {}", reason);
+            return StatusCode::FORBIDDEN.into_response();
+        }
+    };
+
+    // A reconnect resuming a running session joins it rather than starting
+    // one, so it does not count against the session limits
+    if let Some(resume) = protocols.iter().find_map(|p| p.strip_prefix("resume.")) {
+        let (token, received) = match resume.split_once('.') {
+            Some((token, received)) => match received.parse::<u64>() {
+                Ok(received) => (token, Some(received)),
+                Err(_) => {
+                    warn!("WebSocket rejected: malformed resume subprotocol");
+                    return StatusCode::BAD_REQUEST.into_response();
+                }
+            },
+            None => (resume, None),
+        };
+        let Some(attach) = state
+            .resumptions
+            .find(token, &tenant.id, claims.sub.as_deref())
+        else {
+            warn!("WebSocket rejected: unknown or expired resume token");
+            return StatusCode::GONE.into_response();
+        };
+        return ws
+            .protocols([valid_proto])
+            .on_upgrade(move |socket| async move {
+                let reconnect = Reconnect { socket, received };
+                if let Err(mpsc::error::SendError(Reconnect { socket, .. })) =
+                    attach.send(reconnect).await
+                {
+                    // The session ended since the upgrade was accepted
+                    let (mut sender, _) = socket.split();
+                    let err_msg = AgentMessage::Error {
+                        description: "The session to resume has ended".to_string(),
+                        code: "SESSION_ENDED".to_string(),
+                    };
+                    let _ = sender.send(Message::Text(err_msg.to_json().into())).await;
+                    let _ = sender.close().await;
+                }
+            });
+    }
+
+    // Enforce the concurrent session limits before accepting the upgrade
+    let guard = {
+        let mut counts = state.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        let max_sessions = config.reloadable.limits.max_sessions;
+        if max_sessions > 0 && counts.total >= max_sessions {
+            warn!(
+                "Rejecting voice agent session: {} active sessions (limit {})",
+                counts.total, max_sessions
+            );
+            return StatusCode::SERVICE_UNAVAILABLE.into_response();
+        }
+        let tenant_active = counts.per_tenant.get(&tenant.id).copied().unwrap_or(0);
+        if tenant.max_sessions > 0 && tenant_active >= tenant.max_sessions {
+            warn!(
+                "Rejecting voice agent session: tenant '{}' has {} active sessions (limit {})",
+                tenant.id, tenant_active, tenant.max_sessions
+            );
+            return StatusCode::SERVICE_UNAVAILABLE.into_response();
+        }
+        counts.total += 1;
+        *counts.per_tenant.entry(tenant.id.clone()).or_default() += 1;
+        SessionGuard {
+            counts: state.sessions.clone(),
+            tenant: tenant.id.clone(),
+        }
+    };
+
+    // The session span continues the trace of the upgrade request, if any
+    let span = telemetry::session_span(&claims, &tenant);
+    #[cfg(feature = "otel")]
+    telemetry::set_parent(&span, &headers);
+
+    // Accept the WebSocket connection, echoing back the validated subprotocol
+    ws.protocols([valid_proto]).on_upgrade(move |socket| {
+        async move {
+            let _guard = guard;
+            let upstream = state.regions.upstream(&profile, &config.egress).await;
+            let session = ProxySession::new(config.clone())
+                .with_profile_settings(profile.settings)
+                .with_upstream(upstream)
+                .with_pool(Some(state.pool.clone()))
+                .with_resume((config.reloadable.resume.grace_secs > 0).then(|| {
+                    state
+                        .resumptions
+                        .register(&tenant.id, claims.sub.as_deref())
+                }))
+                .with_tenant(tenant.clone());
+            tracing::Span::current().record("session.id", session.id());
+            let usage = state
+                .usage
+                .session(config.clone(), session.id(), &tenant, &claims);
+            let session = session.with_usage(usage);
+            #[cfg(feature = "metrics")]
+            let session = {
+                let latency = state
+                    .latency
+                    .session(&config, session.id(), &tenant, &claims);
+                session.with_latency(latency)
+            };
+            info!(
+                "Client connected to /api/voice-agent (session {}, tenant {})",
+                session.id(),
+                tenant.id
+            );
+            #[cfg(feature = "recording")]
+            let session = {
+                let recorder = start_recording(&config, &tenant, &claims, session.id());
+                session.with_recorder(recorder)
+            };
+            #[cfg(feature = "webhooks")]
+            let session = {
+                let hooks = state
+                    .webhooks
+                    .session(config.clone(), session.id(), &claims);
+                session.with_webhooks(hooks)
+            };
+            #[cfg(feature = "events")]
+            let session = {
+                let events = state
+                    .events
+                    .as_ref()
+                    .map(|bus| bus.session(session.id(), &claims));
+                session.with_events(events)
+            };
+            #[cfg(feature = "guardrails")]
+            let session = {
+                let guardrails = state
+                    .guardrails
+                    .session(config.clone(), session.id(), &claims);
+                session.with_guardrails(guardrails)
+            };
+            session.run(socket).await
+        }
+        .instrument(span)
+    })
+}
+
+/// The tenant and Settings profile named by a session token, falling back to
+/// the default tenant and the "default" profile (or none). Errors describe
+/// why the token cannot start a session.
+fn resolve_session(config: &AppConfig, claims: &Claims) -> Result<(Tenant, Profile), String> {
+    let Some(tenant) = config.reloadable.tenants.get(claims.tenant()) else {
+        return Err(match claims.tenant() {
+            Some(id) => format!("token names unknown tenant '{}'", id),
+            None => "token names no tenant and deepgram.api_key is unset".to_string(),
+        });
+    };
+    let profile = match claims.profile.as_deref() {
+        Some(name) => match config.tenant_profile(tenant, name) {
+            Some(profile) => profile.clone(),
+            None => return Err(format!("token names unknown profile '{}'", name)),
+        },
+        None => config
+            .tenant_profile(tenant, "default")
+            .cloned()
+            .unwrap_or_default(),
+    };
+    Ok((tenant.clone(), profile))
+}
+
+/// Open a recording for a new session if `[recording]` selects it, in the
+/// tenant's namespace under `recording.dir`. Failures are logged and the
+/// session continues unrecorded.
+#[cfg(feature = "recording")]
+fn start_recording(
+    config: &AppConfig,
+    tenant: &Tenant,
+    claims: &Claims,
+    session_id: &str,
+) -> Option<SessionRecorder> {
+    let recording = &config.reloadable.recording;
+    let wanted = match recording.mode {
+        RecordingMode::Off => false,
+        RecordingMode::All => true,
+        RecordingMode::Claim => claims.extra.get("record") == Some(&Value::Bool(true)),
+    };
+    if !wanted {
+        return None;
+    }
+
+    let dir = recording.dir.join(&tenant.namespace);
+    let path = dir.join(format!(
+        "{}-{}.dgrec",
+        chrono::Utc::now().format("%Y%m%dT%H%M%SZ"),
+        session_id
+    ));
+    let started = std::fs::create_dir_all(&dir).and_then(|_| SessionRecorder::create(&path));
+    match started {
+        Ok(recorder) => {
+            info!("Recording session to {}", path.display());
+            Some(recorder)
+        }
+        Err(e) => {
+            error!("Cannot record session to {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Check a WebSocket upgrade's Origin header against the CORS origin allowlist.
+fn check_ws_origin(config: &AppConfig, headers: &axum::http::HeaderMap) -> Result<(), String> {
+    match headers.get(axum::http::header::ORIGIN) {
+        Some(value) => {
+            let origin = value
+                .to_str()
+                .map_err(|_| "Origin header is not valid ASCII".to_string())?;
+            if config.origin_allowed(origin) {
+                Ok(())
+            } else {
+                Err(format!(
+                    "origin '{}' is not in cors.allowed_origins",
+                    origin
+                ))
+            }
+        }
+        None if config.reloadable.cors_require_origin => {
+            Err("missing Origin header (cors.require_origin is set)".to_string())
+        }
+        None => Ok(()),
+    }
+}
+
+/// Build the CORS layer. The origin allowlist is read per request so config
+/// reloads apply immediately; methods and headers are fixed at startup.
+fn cors_layer(config: &SharedConfig) -> CorsLayer {
+    let startup = config.current();
+    let origins = config.clone();
+    let layer = CorsLayer::new().allow_origin(AllowOrigin::predicate(move |origin, _| {
+        origin
+            .to_str()
+            .map(|o| origins.current().origin_allowed(o))
+            .unwrap_or(false)
+    }));
+
+    let layer = if startup.cors_allowed_methods.iter().any(|m| m == "*") {
+        layer.allow_methods(Any)
+    } else {
+        layer.allow_methods(
+            startup
+                .cors_allowed_methods
+                .iter()
+                .filter_map(|m| Method::from_bytes(m.as_bytes()).ok())
+                .collect::<Vec<_>>(),
+        )
+    };
+
+    if startup.cors_allowed_headers.iter().any(|h| h == "*") {
+        layer.allow_headers(Any)
+    } else {
+        layer.allow_headers(
+            startup
+                .cors_allowed_headers
+                .iter()
+                .filter_map(|h| HeaderName::from_bytes(h.as_bytes()).ok())
+                .collect::<Vec<_>>(),
+        )
+    }
+}
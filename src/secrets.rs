@@ -0,0 +1,387 @@
+// Secret material: where keys come from, how they rotate, how they are held.
+//
+// The Deepgram API key and the session secret are taken, in order, from an
+// environment variable (`DEEPGRAM_API_KEY`), the file named by its `_FILE`
+// variant (`DEEPGRAM_API_KEY_FILE`, for Docker and Kubernetes secrets), the
+// `[secrets]` provider and the config file. Tenants can name a provider
+// secret as their key with `api_key_secret`.
+//
+// A provider is any `SecretProvider`: a store of named secrets fetched as a
+// whole. HashiCorp Vault's KV version 2 engine is built in (`vault` feature).
+// With `secrets.refresh_secs` set, `refresh` re-fetches the provider and
+// re-reads the `_FILE` secrets periodically and reloads the configuration
+// when any changed, so rotated Deepgram keys apply to new sessions without a
+// restart.
+//
+// Key material is held in `Secret` values, which are wiped from memory when
+// dropped and never printed.
+
+use crate::config::{SecretProviderConfig, SharedConfig};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+use zeroize::Zeroizing;
+
+/// Environment variables that may instead name a file (`<VAR>_FILE`).
+pub const FILE_VARIABLES: [&str; 3] = ["DEEPGRAM_API_KEY", "SESSION_SECRET", "VAULT_TOKEN"];
+
+// ============================================================================
+// SECRET VALUES
+// ============================================================================
+
+/// Key material. Wiped from memory when dropped; masked in debug output.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Secret(Zeroizing<String>);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Self(Zeroizing::new(value.into()))
+    }
+
+    /// The secret itself. Avoid copying it anywhere long-lived.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Self::new(value)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(****)")
+    }
+}
+
+/// Secrets fetched from a provider, by name.
+pub type SecretValues = BTreeMap<String, Secret>;
+
+/// Read a secret file, dropping the trailing newline most tools write.
+pub fn read_secret_file(path: &Path) -> std::io::Result<Secret> {
+    let contents = Zeroizing::new(std::fs::read_to_string(path)?);
+    Ok(Secret::new(contents.trim_end_matches(['\r', '\n'])))
+}
+
+// ============================================================================
+// PROVIDERS
+// ============================================================================
+
+/// Future returned by `SecretProvider::fetch`.
+pub type FetchFuture<'a> = Pin<Box<dyn Future<Output = Result<SecretValues, String>> + Send + 'a>>;
+
+/// External store of named secrets. `fetch` returns all of them; it is
+/// called at startup and on every refresh.
+pub trait SecretProvider: Send + Sync {
+    fn fetch(&self) -> FetchFuture<'_>;
+}
+
+/// A HashiCorp Vault KV version 2 secret; each string field is a secret.
+#[cfg(feature = "vault")]
+pub struct VaultKv {
+    client: reqwest::Client,
+    url: String,
+    token: Secret,
+    token_file: Option<std::path::PathBuf>,
+}
+
+#[cfg(feature = "vault")]
+impl VaultKv {
+    /// Reads `<address>/v1/<mount>/data/<path>` with `token`, or with the
+    /// contents of `token_file`, re-read on every fetch so the token can
+    /// rotate too.
+    pub fn new(
+        address: &str,
+        mount: &str,
+        path: &str,
+        token: Secret,
+        token_file: Option<std::path::PathBuf>,
+    ) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: format!(
+                "{}/v1/{}/data/{}",
+                address.trim_end_matches('/'),
+                mount.trim_matches('/'),
+                path.trim_matches('/')
+            ),
+            token,
+            token_file,
+        }
+    }
+}
+
+#[cfg(feature = "vault")]
+impl SecretProvider for VaultKv {
+    fn fetch(&self) -> FetchFuture<'_> {
+        Box::pin(async move {
+            let token = match &self.token_file {
+                Some(path) => read_secret_file(path).map_err(|e| {
+                    format!("cannot read Vault token file {}: {}", path.display(), e)
+                })?,
+                None => self.token.clone(),
+            };
+            let response = self
+                .client
+                .get(&self.url)
+                .header("X-Vault-Token", token.expose())
+                .timeout(Duration::from_secs(10))
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .map_err(|e| e.to_string())?;
+            let body: serde_json::Value = response
+                .json()
+                .await
+                .map_err(|e| format!("invalid Vault response: {}", e))?;
+            let Some(data) = body.pointer("/data/data").and_then(|d| d.as_object()) else {
+                return Err(
+                    "Vault response has no data.data object (is the mount KV version 2?)"
+                        .to_string(),
+                );
+            };
+            Ok(data
+                .iter()
+                .filter_map(|(name, value)| Some((name.clone(), Secret::new(value.as_str()?))))
+                .collect())
+        })
+    }
+}
+
+/// The provider selected by `[secrets]`, if any.
+pub fn provider(config: &SecretProviderConfig) -> Option<Arc<dyn SecretProvider>> {
+    match config {
+        SecretProviderConfig::None => None,
+        #[cfg(feature = "vault")]
+        SecretProviderConfig::Vault {
+            address,
+            mount,
+            path,
+            token,
+            token_file,
+        } => Some(Arc::new(VaultKv::new(
+            address,
+            mount,
+            path,
+            token.clone(),
+            token_file.clone(),
+        ))),
+        #[allow(unreachable_patterns)]
+        _ => None,
+    }
+}
+
+// ============================================================================
+// ROTATION
+// ============================================================================
+
+/// Every `secrets.refresh_secs`, re-fetch the provider and re-read the
+/// `_FILE` secrets, reloading `config` when any of them changed. Returns at
+/// once when refreshing is disabled or there is nothing to refresh.
+pub async fn refresh(config: SharedConfig) {
+    let startup = config.current();
+    let provider = provider(&startup.secrets.provider);
+    let mut files = read_secret_files();
+    if startup.secrets.refresh_secs == 0
+        || (provider.is_none() && files.iter().all(Option::is_none))
+    {
+        return;
+    }
+    let mut interval = tokio::time::interval(Duration::from_secs(startup.secrets.refresh_secs));
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        let mut values = config.current().secret_values.clone().unwrap_or_default();
+        let mut changed = false;
+        if let Some(provider) = &provider {
+            match provider.fetch().await {
+                Ok(fetched) if fetched != values => {
+                    values = fetched;
+                    changed = true;
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Cannot refresh secrets, keeping the current ones: {}", e),
+            }
+        }
+        let current = read_secret_files();
+        if current != files {
+            files = current;
+            changed = true;
+        }
+        if changed {
+            info!("Secrets changed: reloading configuration");
+            let _ = config.reload_with_secrets(values);
+        }
+    }
+}
+
+/// Contents of the `_FILE` secrets named in the environment.
+fn read_secret_files() -> Vec<Option<Secret>> {
+    FILE_VARIABLES
+        .iter()
+        .map(|var| {
+            let path = std::env::var(format!("{}_FILE", var)).ok()?;
+            read_secret_file(Path::new(&path)).ok()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secrets_are_masked_in_debug_output() {
+        assert_eq!(format!("{:?}", Secret::new("hunter2")), "Secret(****)");
+    }
+
+    #[test]
+    fn secret_files_lose_their_trailing_newline() {
+        let path = std::env::temp_dir().join(format!("secrets-test-{}", std::process::id()));
+        std::fs::write(&path, "s3cret\r\n").unwrap();
+        let secret = read_secret_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(secret.unwrap().expose(), "s3cret");
+    }
+
+    #[cfg(feature = "vault")]
+    mod vault {
+        use super::*;
+        use axum::Router;
+        use axum::http::{HeaderMap, StatusCode};
+        use axum::response::{IntoResponse, Json, Response};
+        use axum::routing::get;
+        use serde_json::json;
+        use std::sync::Mutex;
+
+        /// A Vault dev server stand-in serving one KV version 2 secret to
+        /// `token`; records the tokens it was sent.
+        async fn mock_vault(
+            token: &'static str,
+            body: serde_json::Value,
+        ) -> (String, Arc<Mutex<Vec<String>>>) {
+            let seen = Arc::new(Mutex::new(Vec::new()));
+            let record = seen.clone();
+            let app = Router::new().route(
+                "/v1/secret/data/voice/agent",
+                get(move |headers: HeaderMap| async move {
+                    let given = headers
+                        .get("x-vault-token")
+                        .and_then(|v| v.to_str().ok())
+                        .unwrap_or_default()
+                        .to_string();
+                    record.lock().unwrap().push(given.clone());
+                    if given == token {
+                        Json(body).into_response()
+                    } else {
+                        let denied: Response = (
+                            StatusCode::FORBIDDEN,
+                            Json(json!({ "errors": ["permission denied"] })),
+                        )
+                            .into_response();
+                        denied
+                    }
+                }),
+            );
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = format!("http://{}", listener.local_addr().unwrap());
+            tokio::spawn(async move { axum::serve(listener, app).await });
+            (address, seen)
+        }
+
+        fn kv2(data: serde_json::Value) -> serde_json::Value {
+            json!({
+                "request_id": "5d7c0b1e",
+                "data": { "data": data, "metadata": { "version": 3 } }
+            })
+        }
+
+        #[test]
+        fn the_url_names_the_kv2_data_endpoint() {
+            let kv = VaultKv::new(
+                "http://vault:8200/",
+                "/secret/",
+                "/voice/agent/",
+                Secret::default(),
+                None,
+            );
+            assert_eq!(kv.url, "http://vault:8200/v1/secret/data/voice/agent");
+        }
+
+        #[tokio::test]
+        async fn fetch_returns_the_string_fields() {
+            let body =
+                kv2(json!({ "DEEPGRAM_API_KEY": "dg-key", "ACME_KEY": "acme", "retries": 3 }));
+            let (address, seen) = mock_vault("root", body).await;
+            let kv = VaultKv::new(&address, "secret", "voice/agent", Secret::new("root"), None);
+            let values = kv.fetch().await.unwrap();
+            assert_eq!(values.len(), 2);
+            assert_eq!(values["DEEPGRAM_API_KEY"].expose(), "dg-key");
+            assert_eq!(values["ACME_KEY"].expose(), "acme");
+            assert_eq!(*seen.lock().unwrap(), ["root"]);
+        }
+
+        #[tokio::test]
+        async fn fetch_rejects_responses_that_are_not_kv2() {
+            let (address, _) =
+                mock_vault("root", json!({ "data": { "DEEPGRAM_API_KEY": "dg-key" } })).await;
+            let kv = VaultKv::new(&address, "secret", "voice/agent", Secret::new("root"), None);
+            let error = kv.fetch().await.unwrap_err();
+            assert!(error.contains("KV version 2"), "{}", error);
+        }
+
+        #[tokio::test]
+        async fn the_token_file_is_reread_on_every_fetch() {
+            let (address, seen) = mock_vault("rotated", kv2(json!({ "K": "v" }))).await;
+            let path =
+                std::env::temp_dir().join(format!("vault-token-test-{}", std::process::id()));
+            std::fs::write(&path, "expired\n").unwrap();
+            let kv = VaultKv::new(
+                &address,
+                "secret",
+                "voice/agent",
+                Secret::new("ignored"),
+                Some(path.clone()),
+            );
+
+            let denied = kv.fetch().await;
+            std::fs::write(&path, "rotated\n").unwrap();
+            let fetched = kv.fetch().await;
+            std::fs::remove_file(&path).unwrap();
+
+            assert!(denied.unwrap_err().contains("403"));
+            assert_eq!(fetched.unwrap()["K"].expose(), "v");
+            assert_eq!(*seen.lock().unwrap(), ["expired", "rotated"]);
+        }
+
+        #[tokio::test]
+        async fn a_missing_token_file_is_an_error() {
+            let path = std::env::temp_dir().join("vault-token-test-missing");
+            let kv = VaultKv::new(
+                "http://127.0.0.1:9",
+                "secret",
+                "voice/agent",
+                Secret::default(),
+                Some(path),
+            );
+            let error = kv.fetch().await.unwrap_err();
+            assert!(
+                error.starts_with("cannot read Vault token file"),
+                "{}",
+                error
+            );
+        }
+    }
+}
@@ -0,0 +1,244 @@
+// Tracing spans for voice sessions, and their export over OTLP.
+//
+// Sessions are instrumented with ordinary `tracing` spans, so they cost
+// nothing without a subscriber interested in them:
+//
+//   GET /api/session           token issued (enduser.id)
+//   voice_agent.session        one proxied session, from upgrade to close
+//                              (session.id, enduser.id, profile, tenant,
+//                              close code, which side closed)
+//     deepgram.connect         the upstream WebSocket handshake
+//     voice_agent.turn         UserStartedSpeaking to AgentAudioDone
+//       voice_agent.function_call  FunctionCallRequest to its response
+//
+// With the `otel` feature and `[otel] enabled`, `layer` exports them to an
+// OpenTelemetry collector over OTLP/HTTP, and `set_parent` continues the
+// W3C trace context (`traceparent`) of the client's request, so a session
+// shows up inside the trace of the application that opened it.
+
+use crate::auth::Claims;
+use crate::config::Tenant;
+use crate::protocol::{AgentMessage, ClientMessage, Role};
+use crate::proxy::ClosedBy;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tracing::{Span, field::Empty, info_span};
+#[cfg(feature = "otel")]
+use {
+    crate::config::OtelConfig,
+    opentelemetry::trace::TracerProvider as _,
+    opentelemetry_otlp::WithExportConfig,
+    opentelemetry_sdk::trace::{Sampler, SdkTracer, SdkTracerProvider},
+    std::sync::OnceLock,
+    tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt},
+};
+
+// ============================================================================
+// SESSION SPANS
+// ============================================================================
+
+/// Span covering one proxied session. `session.id`, `voice_agent.close_code`
+/// and `voice_agent.closed_by` are recorded as the session goes.
+pub fn session_span(claims: &Claims, tenant: &Tenant) -> Span {
+    info_span!(
+        "voice_agent.session",
+        otel.kind = "server",
+        session.id = Empty,
+        enduser.id = claims.sub.as_deref(),
+        voice_agent.profile = claims.profile.as_deref().unwrap_or("default"),
+        voice_agent.tenant = %tenant.id,
+        voice_agent.close_code = Empty,
+        voice_agent.closed_by = Empty,
+    )
+}
+
+#[derive(Default)]
+struct OpenSpans {
+    turns: u64,
+    turn: Option<Span>,
+    /// Function calls awaiting their response, by call ID.
+    calls: HashMap<String, Span>,
+}
+
+/// Turn and function call spans of one session, children of the session
+/// span current when it was created.
+pub struct SessionSpans {
+    session: Span,
+    open: Mutex<OpenSpans>,
+}
+
+impl Default for SessionSpans {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SessionSpans {
+    pub fn new() -> Self {
+        Self {
+            session: Span::current(),
+            open: Mutex::new(OpenSpans::default()),
+        }
+    }
+
+    fn open(&self) -> std::sync::MutexGuard<'_, OpenSpans> {
+        self.open.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn start_turn(&self, open: &mut OpenSpans) {
+        open.turns += 1;
+        open.turn = Some(info_span!(
+            parent: &self.session,
+            "voice_agent.turn",
+            voice_agent.turn = open.turns,
+            voice_agent.interrupted = Empty,
+            voice_agent.function_calls = 0u64,
+        ));
+    }
+
+    fn end_turn(open: &mut OpenSpans, interrupted: bool) {
+        if let Some(turn) = open.turn.take() {
+            turn.record("voice_agent.interrupted", interrupted);
+        }
+    }
+
+    /// A text frame from the agent.
+    pub fn agent_text(&self, text: &str) {
+        if self.session.is_disabled() {
+            return;
+        }
+        let mut open = self.open();
+        match AgentMessage::parse(text) {
+            Some(AgentMessage::UserStartedSpeaking) => {
+                Self::end_turn(&mut open, true);
+                self.start_turn(&mut open);
+            }
+            Some(AgentMessage::ConversationText {
+                role: Role::User, ..
+            }) if open.turn.is_none() => self.start_turn(&mut open),
+            Some(AgentMessage::AgentAudioDone) => Self::end_turn(&mut open, false),
+            Some(AgentMessage::FunctionCallRequest { functions }) => {
+                let parent = open.turn.clone().unwrap_or_else(|| self.session.clone());
+                if let Some(turn) = &open.turn {
+                    let calls = open.calls.len() + functions.len();
+                    turn.record("voice_agent.function_calls", calls as u64);
+                }
+                for call in functions {
+                    let span = info_span!(
+                        parent: &parent,
+                        "voice_agent.function_call",
+                        voice_agent.function.name = %call.name,
+                        voice_agent.function.id = %call.id,
+                        voice_agent.function.client_side = call.client_side,
+                    );
+                    open.calls.insert(call.id, span);
+                }
+            }
+            Some(AgentMessage::FunctionCallResponse { id, .. }) => {
+                open.calls.remove(&id);
+            }
+            _ => {}
+        }
+    }
+
+    /// A text frame from the client.
+    pub fn client_text(&self, text: &str) {
+        if self.session.is_disabled() {
+            return;
+        }
+        if let Some(ClientMessage::FunctionCallResponse { id, .. }) = ClientMessage::parse(text) {
+            self.open().calls.remove(&id);
+        }
+    }
+
+    /// The session is over; close whatever is still open.
+    pub fn ended(&self, closed_by: ClosedBy, code: u16) {
+        let mut open = self.open();
+        open.calls.clear();
+        Self::end_turn(&mut open, true);
+        self.session.record("voice_agent.close_code", code);
+        self.session
+            .record("voice_agent.closed_by", closed_by.as_str());
+    }
+}
+
+// ============================================================================
+// OTLP EXPORT
+// ============================================================================
+
+/// Provider flushed by `shutdown`.
+#[cfg(feature = "otel")]
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+/// Layer exporting spans to the collector in `config`, or `None` when export
+/// is disabled or the exporter cannot be built. Also installs the W3C trace
+/// context propagator used by `set_parent`.
+#[cfg(feature = "otel")]
+pub fn layer<S>(config: &OtelConfig) -> Option<OpenTelemetryLayer<S, SdkTracer>>
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    if !config.enabled {
+        return None;
+    }
+    let exporter = match opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(&config.endpoint)
+        .build()
+    {
+        Ok(exporter) => exporter,
+        Err(e) => {
+            eprintln!("WARNING: OpenTelemetry export disabled: {}", e);
+            return None;
+        }
+    };
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            config.sample_ratio,
+        ))))
+        .with_resource(
+            opentelemetry_sdk::Resource::builder()
+                .with_service_name(config.service_name.clone())
+                .build(),
+        )
+        .build();
+    opentelemetry::global::set_text_map_propagator(
+        opentelemetry_sdk::propagation::TraceContextPropagator::new(),
+    );
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    let _ = PROVIDER.set(provider);
+    Some(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// Export the spans still buffered; call before the process exits.
+#[cfg(feature = "otel")]
+pub fn shutdown() {
+    if let Some(provider) = PROVIDER.get()
+        && let Err(e) = provider.shutdown()
+    {
+        tracing::warn!("OpenTelemetry shutdown failed: {}", e);
+    }
+}
+
+/// Make `span` a child of the trace context in `headers` (`traceparent`,
+/// `tracestate`), if the request carries one.
+#[cfg(feature = "otel")]
+pub fn set_parent(span: &Span, headers: &axum::http::HeaderMap) {
+    struct Headers<'a>(&'a axum::http::HeaderMap);
+
+    impl opentelemetry::propagation::Extractor for Headers<'_> {
+        fn get(&self, key: &str) -> Option<&str> {
+            self.0.get(key).and_then(|v| v.to_str().ok())
+        }
+
+        fn keys(&self) -> Vec<&str> {
+            self.0.keys().map(|k| k.as_str()).collect()
+        }
+    }
+
+    let context = opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.extract(&Headers(headers))
+    });
+    let _ = span.set_parent(context);
+}
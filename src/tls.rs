@@ -0,0 +1,242 @@
+// Native HTTPS/WSS termination with rustls.
+//
+// Certificates are re-read whenever the cert, key or client CA file changes
+// on disk (polled every `tls.reload_interval_secs`), so renewed certificates
+// are picked up without a restart; existing connections keep the certificate
+// they negotiated with. With `tls.client_ca_file` set, client certificates
+// are verified and the verified subject is attached to each request as a
+// `ClientIdentity`.
+
+use crate::auth::ClientIdentity;
+use crate::config::{ClientAuth, TlsConfig};
+use axum::{Router, extract::ConnectInfo};
+use hyper::{Request, body::Incoming};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::server::graceful::GracefulShutdown;
+use rustls::ServerConfig;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use std::future::Future;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tower::ServiceExt;
+use tracing::{debug, error, info, warn};
+
+/// Time allowed for a client to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time allowed for in-flight requests to finish after shutdown is requested.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
+
+// ============================================================================
+// CERTIFICATE STORE
+// ============================================================================
+
+/// Holds the active rustls configuration and swaps it when files change.
+pub struct CertStore {
+    config: TlsConfig,
+    current: RwLock<Arc<ServerConfig>>,
+}
+
+impl CertStore {
+    /// Load certificates; fails if any file is missing or invalid.
+    pub fn load(config: TlsConfig) -> Result<Self, String> {
+        let server_config = build_server_config(&config)?;
+        Ok(Self {
+            config,
+            current: RwLock::new(Arc::new(server_config)),
+        })
+    }
+
+    fn current(&self) -> Arc<ServerConfig> {
+        self.current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Poll the certificate files and reload when any of them changes.
+    /// A bad replacement is logged and the previous certificate stays active.
+    pub async fn watch(self: Arc<Self>) {
+        let mut last = self.fingerprint();
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(self.config.reload_interval_secs));
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let now = self.fingerprint();
+            if now == last {
+                continue;
+            }
+            last = now;
+            match build_server_config(&self.config) {
+                Ok(server_config) => {
+                    *self.current.write().unwrap_or_else(|e| e.into_inner()) =
+                        Arc::new(server_config);
+                    info!("TLS certificates reloaded");
+                }
+                Err(e) => error!("TLS certificate reload failed, keeping previous: {}", e),
+            }
+        }
+    }
+
+    /// Modification time and size of every watched file.
+    fn fingerprint(&self) -> Vec<Option<(SystemTime, u64)>> {
+        [
+            Some(&self.config.cert_file),
+            Some(&self.config.key_file),
+            self.config.client_ca_file.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|path| {
+            std::fs::metadata(path)
+                .ok()
+                .and_then(|m| Some((m.modified().ok()?, m.len())))
+        })
+        .collect()
+    }
+}
+
+fn build_server_config(config: &TlsConfig) -> Result<ServerConfig, String> {
+    let certs = read_certs(&config.cert_file)?;
+    let key = read_key(&config.key_file)?;
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| format!("TLS setup failed: {}", e))?;
+
+    let builder = match &config.client_ca_file {
+        Some(ca_file) => {
+            let mut roots = rustls::RootCertStore::empty();
+            for cert in read_certs(ca_file)? {
+                roots
+                    .add(cert)
+                    .map_err(|e| format!("{}: invalid CA certificate: {}", ca_file.display(), e))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = match config.client_auth {
+                ClientAuth::Optional => verifier.allow_unauthenticated(),
+                ClientAuth::Required => verifier,
+            };
+            let verifier = verifier
+                .build()
+                .map_err(|e| format!("client certificate verifier: {}", e))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut server_config = builder
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("{}: {}", config.cert_file.display(), e))?;
+    // WebSocket upgrades need HTTP/1.1
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(server_config)
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = std::fs::File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("{}: no certificates found", path.display()));
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &Path) -> Result<PrivateKeyDer<'static>, String> {
+    let file = std::fs::File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| format!("{}: {}", path.display(), e))?
+        .ok_or_else(|| format!("{}: no private key found", path.display()))
+}
+
+/// Extract the subject of the client's leaf certificate, if one was verified.
+fn client_identity(conn: &rustls::ServerConnection) -> Option<ClientIdentity> {
+    let leaf = conn.peer_certificates()?.first()?;
+    let (_, cert) = x509_parser::parse_x509_certificate(leaf.as_ref()).ok()?;
+    let subject = cert
+        .subject()
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+        .map(str::to_string)
+        .unwrap_or_else(|| cert.subject().to_string());
+    Some(ClientIdentity { subject })
+}
+
+// ============================================================================
+// SERVER
+// ============================================================================
+
+/// Accept TLS connections and serve `app` until `shutdown` resolves.
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    certs: Arc<CertStore>,
+    shutdown: impl Future<Output = ()>,
+) {
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(shutdown);
+
+    loop {
+        let (tcp, addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+
+        let acceptor = TlsAcceptor::from(certs.current());
+        let app = app.clone();
+        let builder = builder.clone();
+        let watcher = graceful.watcher();
+        tokio::spawn(async move {
+            let stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(tcp)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    debug!("TLS handshake with {} failed: {}", addr, e);
+                    return;
+                }
+                Err(_) => {
+                    debug!("TLS handshake with {} timed out", addr);
+                    return;
+                }
+            };
+            let identity = client_identity(stream.get_ref().1);
+
+            let service = hyper::service::service_fn(move |mut req: Request<Incoming>| {
+                req.extensions_mut().insert(ConnectInfo(addr));
+                if let Some(identity) = &identity {
+                    req.extensions_mut().insert(identity.clone());
+                }
+                app.clone().oneshot(req)
+            });
+            let conn = builder
+                .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                .into_owned();
+            if let Err(e) = watcher.watch(conn).await {
+                debug!("Connection from {} ended with error: {}", addr, e);
+            }
+        });
+    }
+
+    tokio::select! {
+        _ = graceful.shutdown() => {}
+        _ = tokio::time::sleep(SHUTDOWN_GRACE) => {
+            warn!("Timed out waiting for connections to close");
+        }
+    }
+}
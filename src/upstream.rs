@@ -0,0 +1,310 @@
+// Upstream endpoints: where a session's agent connection goes.
+//
+// Sessions connect to their tenant's `agent_url` unless their Settings
+// profile names its own endpoint (`agent_url`) or a list of regional ones
+// (`regions`). The profile's `query` parameters and `headers` are added to
+// the handshake either way.
+//
+// For regional endpoints, `Regions` keeps the TCP connect time of each,
+// through the egress proxy if there is one: it is measured the first time a
+// region is needed, then again in the background once the measurement is a
+// minute old. Sessions try the regions fastest
+// first; a region that fails to connect goes last until it is measured again.
+
+use crate::config::{EgressConfig, Profile, Tenant};
+use crate::egress;
+use futures_util::future::join_all;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// Age after which a region's latency is measured again.
+const PROBE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Regions that take longer than this to accept a connection are unreachable.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+// ============================================================================
+// SESSION ENDPOINTS
+// ============================================================================
+
+/// The agent endpoints a session may connect to, and what to add to the
+/// handshake.
+#[derive(Debug, Clone, Default)]
+pub struct Upstream {
+    /// Agent URLs to try, in order (empty = the tenant's `agent_url`).
+    pub urls: Vec<String>,
+    /// Query parameters added to the agent URL.
+    pub query: BTreeMap<String, String>,
+    /// Extra handshake headers.
+    pub headers: BTreeMap<String, String>,
+    /// Told about regions that fail to connect.
+    regions: Option<Regions>,
+}
+
+impl Upstream {
+    /// The endpoints of `profile`, in configured order.
+    pub fn from_profile(profile: &Profile) -> Self {
+        Self {
+            urls: profile.agent_urls.clone(),
+            query: profile.query.clone(),
+            headers: profile.headers.clone(),
+            regions: None,
+        }
+    }
+
+    /// Agent URLs to try for a session of `tenant`, in order.
+    pub fn endpoints<'a>(&'a self, tenant: &'a Tenant) -> Vec<&'a str> {
+        if self.urls.is_empty() {
+            vec![tenant.agent_url.as_str()]
+        } else {
+            self.urls.iter().map(String::as_str).collect()
+        }
+    }
+
+    /// `endpoint` with the query parameters added.
+    pub fn url(&self, endpoint: &str) -> Result<url::Url, String> {
+        let mut url = url::Url::parse(endpoint)
+            .map_err(|e| format!("failed to parse Deepgram agent URL: {}", e))?;
+        if !self.query.is_empty() {
+            url.query_pairs_mut().extend_pairs(&self.query);
+        }
+        Ok(url)
+    }
+
+    /// Connecting to `endpoint` failed.
+    pub fn failed(&self, endpoint: &str) {
+        if let Some(regions) = &self.regions {
+            regions.failed(endpoint);
+        }
+    }
+}
+
+// ============================================================================
+// REGION LATENCY
+// ============================================================================
+
+#[derive(Debug, Clone, Copy)]
+struct Measurement {
+    /// Connect time; `None` when unreachable.
+    rtt: Option<Duration>,
+    at: Instant,
+}
+
+/// Measured latency of regional endpoints, shared by all sessions.
+#[derive(Debug, Clone, Default)]
+pub struct Regions {
+    measured: Arc<Mutex<HashMap<String, Measurement>>>,
+}
+
+impl Regions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn measured(&self) -> std::sync::MutexGuard<'_, HashMap<String, Measurement>> {
+        self.measured.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Endpoints for a session with `profile`, its regions fastest first
+    /// when reached through `egress`.
+    pub async fn upstream(&self, profile: &Profile, egress: &EgressConfig) -> Upstream {
+        let mut upstream = Upstream::from_profile(profile);
+        if upstream.urls.len() > 1 {
+            self.rank(&mut upstream.urls, egress).await;
+            upstream.regions = Some(self.clone());
+        }
+        upstream
+    }
+
+    /// Sort `urls` by latency, measuring those never measured first.
+    async fn rank(&self, urls: &mut [String], egress: &EgressConfig) {
+        let (unmeasured, stale) = {
+            let mut measured = self.measured();
+            let now = Instant::now();
+            let mut unmeasured = Vec::new();
+            let mut stale = Vec::new();
+            for url in urls.iter() {
+                match measured.get_mut(url) {
+                    None => unmeasured.push(url.clone()),
+                    Some(m) if now.duration_since(m.at) >= PROBE_INTERVAL => {
+                        // Claimed, so concurrent sessions don't measure it too
+                        m.at = now;
+                        stale.push(url.clone());
+                    }
+                    Some(_) => {}
+                }
+            }
+            (unmeasured, stale)
+        };
+        if !unmeasured.is_empty() {
+            self.probe(unmeasured, egress).await;
+        }
+        if !stale.is_empty() {
+            let regions = self.clone();
+            let egress = egress.clone();
+            tokio::spawn(async move { regions.probe(stale, &egress).await });
+        }
+        let measured = self.measured();
+        // Stable, so unreachable regions keep their configured order
+        urls.sort_by_key(|url| {
+            measured
+                .get(url)
+                .and_then(|m| m.rtt)
+                .unwrap_or(Duration::MAX)
+        });
+    }
+
+    /// Measure the connect time of each of `urls`, concurrently.
+    async fn probe(&self, urls: Vec<String>, egress: &EgressConfig) {
+        let results = join_all(urls.into_iter().map(|url| async move {
+            let rtt = connect_time(&url, egress).await;
+            (url, rtt)
+        }))
+        .await;
+        let at = Instant::now();
+        let mut measured = self.measured();
+        for (url, rtt) in results {
+            match rtt {
+                Some(rtt) => debug!("Region {}: {} ms", url, rtt.as_millis()),
+                None => debug!("Region {}: unreachable", url),
+            }
+            measured.insert(url, Measurement { rtt, at });
+        }
+    }
+
+    /// `endpoint` failed to connect; rank it last until measured again.
+    fn failed(&self, endpoint: &str) {
+        self.measured().insert(
+            endpoint.to_string(),
+            Measurement {
+                rtt: None,
+                at: Instant::now(),
+            },
+        );
+    }
+}
+
+/// Time to open a TCP connection to the host of `url` through `egress`.
+async fn connect_time(url: &str, egress: &EgressConfig) -> Option<Duration> {
+    let url = url::Url::parse(url).ok()?;
+    let start = Instant::now();
+    match tokio::time::timeout(PROBE_TIMEOUT, egress::connect(egress, &url)).await {
+        Ok(Ok(_)) => Some(start.elapsed()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn urls(urls: &[&str]) -> Vec<String> {
+        urls.iter().map(|u| u.to_string()).collect()
+    }
+
+    fn measure(regions: &Regions, url: &str, rtt_ms: Option<u64>, age: Duration) {
+        regions.measured().insert(
+            url.to_string(),
+            Measurement {
+                rtt: rtt_ms.map(Duration::from_millis),
+                at: Instant::now() - age,
+            },
+        );
+    }
+
+    /// An endpoint that accepts connections, and one that refuses them.
+    async fn endpoints() -> (String, String, tokio::net::TcpListener) {
+        let open = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed_url = format!("ws://{}/agent", closed.local_addr().unwrap());
+        drop(closed);
+        (
+            format!("ws://{}/agent", open.local_addr().unwrap()),
+            closed_url,
+            open,
+        )
+    }
+
+    #[tokio::test]
+    async fn regions_are_ranked_by_measured_latency() {
+        let regions = Regions::new();
+        measure(&regions, "wss://us", Some(30), Duration::ZERO);
+        measure(&regions, "wss://eu", Some(10), Duration::ZERO);
+        measure(&regions, "wss://ap", None, Duration::ZERO);
+        measure(&regions, "wss://sa", None, Duration::ZERO);
+        let mut ranked = urls(&["wss://ap", "wss://us", "wss://sa", "wss://eu"]);
+        regions.rank(&mut ranked, &EgressConfig::default()).await;
+        // Unreachable regions keep their configured order
+        assert_eq!(ranked, ["wss://eu", "wss://us", "wss://ap", "wss://sa"]);
+    }
+
+    #[tokio::test]
+    async fn unmeasured_regions_are_probed_first() {
+        let (open, closed, _listener) = endpoints().await;
+        let regions = Regions::new();
+        let mut ranked = urls(&[&closed, &open]);
+        regions.rank(&mut ranked, &EgressConfig::default()).await;
+        assert_eq!(ranked, [open.as_str(), closed.as_str()]);
+        let measured = regions.measured();
+        assert!(measured[&open].rtt.is_some());
+        assert!(measured[&closed].rtt.is_none());
+    }
+
+    #[tokio::test]
+    async fn stale_measurements_are_probed_again_in_the_background() {
+        let (open, closed, _listener) = endpoints().await;
+        let regions = Regions::new();
+        measure(
+            &regions,
+            &open,
+            None,
+            PROBE_INTERVAL + Duration::from_secs(1),
+        );
+        measure(&regions, &closed, Some(5), Duration::ZERO);
+
+        let mut ranked = urls(&[&open, &closed]);
+        regions.rank(&mut ranked, &EgressConfig::default()).await;
+        // This session goes by the old measurement
+        assert_eq!(ranked, [closed.as_str(), open.as_str()]);
+
+        for _ in 0..300 {
+            if regions.measured()[&open].rtt.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        regions.rank(&mut ranked, &EgressConfig::default()).await;
+        assert_eq!(ranked, [open.as_str(), closed.as_str()]);
+    }
+
+    #[tokio::test]
+    async fn failed_regions_go_last_until_measured_again() {
+        let regions = Regions::new();
+        measure(&regions, "wss://eu", Some(10), Duration::ZERO);
+        measure(&regions, "wss://us", Some(30), Duration::ZERO);
+        let profile = Profile {
+            agent_urls: urls(&["wss://us", "wss://eu"]),
+            ..Profile::default()
+        };
+        let upstream = regions.upstream(&profile, &EgressConfig::default()).await;
+        assert_eq!(upstream.urls, ["wss://eu", "wss://us"]);
+
+        upstream.failed("wss://eu");
+        let upstream = regions.upstream(&profile, &EgressConfig::default()).await;
+        assert_eq!(upstream.urls, ["wss://us", "wss://eu"]);
+    }
+
+    #[tokio::test]
+    async fn a_single_endpoint_is_not_ranked() {
+        let regions = Regions::new();
+        let profile = Profile {
+            agent_urls: urls(&["wss://eu"]),
+            ..Profile::default()
+        };
+        let upstream = regions.upstream(&profile, &EgressConfig::default()).await;
+        assert!(upstream.regions.is_none());
+        assert!(regions.measured().is_empty());
+    }
+}
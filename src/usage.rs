@@ -0,0 +1,698 @@
+// Usage accounting: what each proxied session consumed, for cost reporting.
+//
+// While a session runs, `SessionUsage` counts the user audio sent to the
+// agent and the TTS audio received from it, the agent's LLM turns (assistant
+// lines) and the function calls it requested. Audio is converted to seconds
+// with the encoding and sample rate of the session's `Settings`
+// (`audio.input` and `audio.output`, Deepgram's linear16 at 24 kHz when
+// absent); compressed encodings are counted in bytes only. When the session
+// ends, one `UsageRecord` is appended to `usage.file` as a JSON line.
+//
+// `GET /api/usage` (admin token) reads the records back, filtered by date
+// range, tenant and subject, and aggregated by subject, tenant, profile
+// and/or day, as JSON or CSV.
+
+use crate::auth::Claims;
+use crate::config::{AppConfig, Tenant};
+use crate::protocol::{AgentMessage, ClientMessage, Role};
+use crate::proxy::ClosedBy;
+use chrono::{DateTime, NaiveDate, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
+use tracing::{error, warn};
+
+/// Deepgram's audio format when `Settings` names none.
+const DEFAULT_ENCODING: &str = "linear16";
+const DEFAULT_SAMPLE_RATE: u64 = 24000;
+
+/// Fields records can be grouped by in `GET /api/usage`.
+pub const GROUP_FIELDS: [&str; 4] = ["subject", "tenant", "profile", "day"];
+
+// ============================================================================
+// RECORDS
+// ============================================================================
+
+/// Usage of one finished session, as stored in `usage.file`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UsageRecord {
+    pub session_id: String,
+    pub subject: Option<String>,
+    pub tenant: String,
+    pub profile: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: DateTime<Utc>,
+    pub duration_secs: f64,
+    pub closed_by: ClosedBy,
+    pub user_audio_bytes: u64,
+    pub user_audio_secs: f64,
+    pub agent_audio_bytes: u64,
+    pub agent_audio_secs: f64,
+    pub llm_turns: u64,
+    pub function_calls: u64,
+}
+
+/// Bytes per second of mono audio, for encodings whose size follows from
+/// the sample rate.
+fn bytes_per_second(encoding: &str, sample_rate: u64) -> Option<u64> {
+    let sample_bytes = match encoding {
+        "linear16" => 2,
+        "linear32" => 4,
+        "mulaw" | "alaw" => 1,
+        _ => return None,
+    };
+    Some(sample_bytes * sample_rate).filter(|&rate| rate > 0)
+}
+
+/// Bytes per second of the `audio.<direction>` format in a `Settings` body.
+fn settings_rate(settings: &serde_json::Map<String, Value>, direction: &str) -> Option<u64> {
+    let audio = settings.get("audio").and_then(|a| a.get(direction));
+    let encoding = audio
+        .and_then(|a| a.get("encoding"))
+        .and_then(Value::as_str)
+        .unwrap_or(DEFAULT_ENCODING);
+    let sample_rate = audio
+        .and_then(|a| a.get("sample_rate"))
+        .and_then(Value::as_u64)
+        .unwrap_or(DEFAULT_SAMPLE_RATE);
+    bytes_per_second(encoding, sample_rate)
+}
+
+// ============================================================================
+// SESSION TRACKING
+// ============================================================================
+
+/// Usage ledger shared by all sessions.
+#[derive(Clone, Default)]
+pub struct Usage {
+    /// Serializes appends to the usage file.
+    write: Arc<tokio::sync::Mutex<()>>,
+}
+
+#[derive(Default)]
+struct Counters {
+    /// Bytes per second of the user and agent audio; `None` when the
+    /// encoding is compressed.
+    input_rate: Option<u64>,
+    output_rate: Option<u64>,
+    user_audio_bytes: u64,
+    agent_audio_bytes: u64,
+    llm_turns: u64,
+    function_calls: u64,
+}
+
+/// Counts the usage of one proxied session and records it when it ends.
+pub struct SessionUsage {
+    usage: Usage,
+    /// `usage.file` as of the session start.
+    file: PathBuf,
+    session_id: String,
+    subject: Option<String>,
+    tenant: String,
+    profile: Option<String>,
+    started_at: DateTime<Utc>,
+    counters: Mutex<Counters>,
+}
+
+impl Usage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Usage tracker for a new session, or `None` when `[usage]` is off.
+    pub fn session(
+        &self,
+        config: Arc<AppConfig>,
+        session_id: &str,
+        tenant: &Tenant,
+        claims: &Claims,
+    ) -> Option<SessionUsage> {
+        if !config.reloadable.usage.enabled {
+            return None;
+        }
+        Some(SessionUsage {
+            usage: self.clone(),
+            file: config.reloadable.usage.file.clone(),
+            session_id: session_id.to_string(),
+            subject: claims.sub.clone(),
+            tenant: tenant.id.clone(),
+            profile: claims.profile.clone(),
+            started_at: Utc::now(),
+            counters: Mutex::new(Counters {
+                input_rate: bytes_per_second(DEFAULT_ENCODING, DEFAULT_SAMPLE_RATE),
+                output_rate: bytes_per_second(DEFAULT_ENCODING, DEFAULT_SAMPLE_RATE),
+                ..Counters::default()
+            }),
+        })
+    }
+
+    async fn append(&self, path: &Path, record: &UsageRecord) -> std::io::Result<()> {
+        let line = serde_json::to_string(record).map_err(std::io::Error::other)?;
+        let _lock = self.write.lock().await;
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        file.write_all(format!("{}\n", line).as_bytes()).await
+    }
+}
+
+impl SessionUsage {
+    fn counters(&self) -> std::sync::MutexGuard<'_, Counters> {
+        self.counters.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// A text frame as forwarded to the agent (after the profile merge), so
+    /// the audio formats are those the agent actually uses.
+    pub fn client_text(&self, text: &str) {
+        if let Some(ClientMessage::Settings { settings }) = ClientMessage::parse(text) {
+            let mut counters = self.counters();
+            counters.input_rate = settings_rate(&settings, "input");
+            counters.output_rate = settings_rate(&settings, "output");
+        }
+    }
+
+    /// User audio sent to the agent.
+    pub fn client_binary(&self, len: usize) {
+        self.counters().user_audio_bytes += len as u64;
+    }
+
+    /// A text frame from the agent.
+    pub fn agent_text(&self, text: &str) {
+        match AgentMessage::parse(text) {
+            Some(AgentMessage::ConversationText {
+                role: Role::Assistant,
+                ..
+            }) => self.counters().llm_turns += 1,
+            Some(AgentMessage::FunctionCallRequest { functions }) => {
+                self.counters().function_calls += functions.len() as u64
+            }
+            _ => {}
+        }
+    }
+
+    /// TTS audio from the agent.
+    pub fn agent_binary(&self, len: usize) {
+        self.counters().agent_audio_bytes += len as u64;
+    }
+
+    /// The session is over: append its record in the background.
+    pub fn ended(&self, closed_by: ClosedBy) {
+        let record = self.record(closed_by);
+        let usage = self.usage.clone();
+        let path = self.file.clone();
+        tokio::spawn(async move {
+            if let Err(e) = usage.append(&path, &record).await {
+                error!(
+                    "Cannot write usage of session {} to {}: {}",
+                    record.session_id,
+                    path.display(),
+                    e
+                );
+            }
+        });
+    }
+
+    /// The session's usage so far, as if it ended now.
+    fn record(&self, closed_by: ClosedBy) -> UsageRecord {
+        let ended_at = Utc::now();
+        let counters = self.counters();
+        let secs = |bytes: u64, rate: Option<u64>| rate.map_or(0.0, |r| bytes as f64 / r as f64);
+        UsageRecord {
+            session_id: self.session_id.clone(),
+            subject: self.subject.clone(),
+            tenant: self.tenant.clone(),
+            profile: self.profile.clone(),
+            started_at: self.started_at,
+            ended_at,
+            duration_secs: (ended_at - self.started_at).num_milliseconds() as f64 / 1000.0,
+            closed_by,
+            user_audio_bytes: counters.user_audio_bytes,
+            user_audio_secs: secs(counters.user_audio_bytes, counters.input_rate),
+            agent_audio_bytes: counters.agent_audio_bytes,
+            agent_audio_secs: secs(counters.agent_audio_bytes, counters.output_rate),
+            llm_turns: counters.llm_turns,
+            function_calls: counters.function_calls,
+        }
+    }
+}
+
+// ============================================================================
+// REPORTS
+// ============================================================================
+
+/// A usage report request. Dates are `YYYY-MM-DD` (UTC, inclusive) or
+/// RFC 3339 instants and apply to session start times.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct UsageQuery {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub tenant: Option<String>,
+    pub subject: Option<String>,
+    /// Comma-separated `GROUP_FIELDS`; empty lists every session.
+    pub group_by: Option<String>,
+    /// `json` (default) or `csv`.
+    pub format: Option<String>,
+}
+
+/// Totals of the sessions sharing a group key.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct UsageTotals {
+    pub sessions: u64,
+    pub duration_secs: f64,
+    pub user_audio_secs: f64,
+    pub agent_audio_secs: f64,
+    pub llm_turns: u64,
+    pub function_calls: u64,
+}
+
+impl UsageTotals {
+    fn add(&mut self, record: &UsageRecord) {
+        self.sessions += 1;
+        self.duration_secs += record.duration_secs;
+        self.user_audio_secs += record.user_audio_secs;
+        self.agent_audio_secs += record.agent_audio_secs;
+        self.llm_turns += record.llm_turns;
+        self.function_calls += record.function_calls;
+    }
+}
+
+/// A usage report: per-session records, or totals per group.
+#[derive(Debug, Clone, PartialEq)]
+pub enum UsageReport {
+    Sessions(Vec<UsageRecord>),
+    Groups {
+        fields: Vec<String>,
+        groups: Vec<(Vec<String>, UsageTotals)>,
+    },
+}
+
+impl UsageQuery {
+    /// Start of the range, inclusive.
+    fn start(&self) -> Result<Option<DateTime<Utc>>, String> {
+        self.from
+            .as_deref()
+            .map(|s| parse_bound("from", s, false))
+            .transpose()
+    }
+
+    /// End of the range, exclusive; a date covers that whole day.
+    fn end(&self) -> Result<Option<DateTime<Utc>>, String> {
+        self.to
+            .as_deref()
+            .map(|s| parse_bound("to", s, true))
+            .transpose()
+    }
+
+    fn group_fields(&self) -> Result<Vec<String>, String> {
+        let mut fields = Vec::new();
+        for field in self.group_by.as_deref().unwrap_or("").split(',') {
+            let field = field.trim();
+            if field.is_empty() {
+                continue;
+            }
+            if !GROUP_FIELDS.contains(&field) {
+                return Err(format!(
+                    "group_by '{}' is invalid (expected {})",
+                    field,
+                    GROUP_FIELDS.join(", ")
+                ));
+            }
+            if !fields.iter().any(|f| f == field) {
+                fields.push(field.to_string());
+            }
+        }
+        Ok(fields)
+    }
+
+    /// Whether the report is requested as CSV.
+    pub fn csv(&self) -> Result<bool, String> {
+        match self.format.as_deref().unwrap_or("json") {
+            "json" => Ok(false),
+            "csv" => Ok(true),
+            other => Err(format!(
+                "format '{}' is invalid (expected json or csv)",
+                other
+            )),
+        }
+    }
+}
+
+fn parse_bound(name: &str, value: &str, end: bool) -> Result<DateTime<Utc>, String> {
+    if let Ok(instant) = DateTime::parse_from_rfc3339(value) {
+        return Ok(instant.with_timezone(&Utc));
+    }
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d").map_err(|_| {
+        format!(
+            "{} '{}' is invalid (expected YYYY-MM-DD or an RFC 3339 time)",
+            name, value
+        )
+    })?;
+    let date = if end {
+        date.succ_opt().unwrap_or(date)
+    } else {
+        date
+    };
+    Ok(date.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc())
+}
+
+/// Value of a group field for a record.
+fn group_value(record: &UsageRecord, field: &str) -> String {
+    match field {
+        "subject" => record.subject.clone().unwrap_or_default(),
+        "tenant" => record.tenant.clone(),
+        "profile" => record
+            .profile
+            .clone()
+            .unwrap_or_else(|| "default".to_string()),
+        _ => record.started_at.format("%Y-%m-%d").to_string(),
+    }
+}
+
+/// Build the report for `query` from the records in `path`. A missing file
+/// holds no records; unreadable lines are skipped.
+pub async fn report(path: &Path, query: &UsageQuery) -> Result<UsageReport, ReportError> {
+    let start = query.start().map_err(ReportError::Query)?;
+    let end = query.end().map_err(ReportError::Query)?;
+    let fields = query.group_fields().map_err(ReportError::Query)?;
+
+    let contents = match tokio::fs::read_to_string(path).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(ReportError::Read(e)),
+    };
+    let mut skipped = 0;
+    let records = contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| {
+            serde_json::from_str::<UsageRecord>(line)
+                .inspect_err(|_| skipped += 1)
+                .ok()
+        })
+        .filter(|r| start.is_none_or(|s| r.started_at >= s))
+        .filter(|r| end.is_none_or(|e| r.started_at < e))
+        .filter(|r| query.tenant.as_ref().is_none_or(|t| &r.tenant == t))
+        .filter(|r| {
+            query
+                .subject
+                .as_ref()
+                .is_none_or(|s| r.subject.as_ref() == Some(s))
+        });
+
+    let report = if fields.is_empty() {
+        UsageReport::Sessions(records.collect())
+    } else {
+        let mut groups: BTreeMap<Vec<String>, UsageTotals> = BTreeMap::new();
+        for record in records {
+            let key = fields.iter().map(|f| group_value(&record, f)).collect();
+            groups.entry(key).or_default().add(&record);
+        }
+        UsageReport::Groups {
+            fields,
+            groups: groups.into_iter().collect(),
+        }
+    };
+    if skipped > 0 {
+        warn!(
+            "Skipped {} unreadable line(s) in {}",
+            skipped,
+            path.display()
+        );
+    }
+    Ok(report)
+}
+
+/// Why a usage report could not be built.
+#[derive(Debug)]
+pub enum ReportError {
+    /// The request's parameters are invalid.
+    Query(String),
+    Read(std::io::Error),
+}
+
+impl std::fmt::Display for ReportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReportError::Query(e) => f.write_str(e),
+            ReportError::Read(e) => write!(f, "cannot read usage records: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ReportError {}
+
+impl UsageReport {
+    /// The report as JSON: `{"sessions": [...]}` or `{"group_by": [...],
+    /// "groups": [{"subject": ..., "sessions": ..., ...}]}`.
+    pub fn to_json(&self) -> Value {
+        match self {
+            UsageReport::Sessions(records) => serde_json::json!({ "sessions": records }),
+            UsageReport::Groups { fields, groups } => {
+                let groups: Vec<Value> = groups
+                    .iter()
+                    .map(|(key, totals)| {
+                        let mut row = serde_json::to_value(totals).unwrap_or_default();
+                        if let Some(row) = row.as_object_mut() {
+                            for (field, value) in fields.iter().zip(key) {
+                                row.insert(field.clone(), Value::String(value.clone()));
+                            }
+                        }
+                        row
+                    })
+                    .collect();
+                serde_json::json!({ "group_by": fields, "groups": groups })
+            }
+        }
+    }
+
+    /// The report as CSV with a header row.
+    pub fn to_csv(&self) -> String {
+        let mut out = String::new();
+        let mut row = |cells: Vec<String>| {
+            let cells: Vec<String> = cells.iter().map(|c| csv_cell(c)).collect();
+            out.push_str(&cells.join(","));
+            out.push_str("\r\n");
+        };
+        match self {
+            UsageReport::Sessions(records) => {
+                row([
+                    "session_id",
+                    "subject",
+                    "tenant",
+                    "profile",
+                    "started_at",
+                    "ended_at",
+                    "duration_secs",
+                    "closed_by",
+                    "user_audio_bytes",
+                    "user_audio_secs",
+                    "agent_audio_bytes",
+                    "agent_audio_secs",
+                    "llm_turns",
+                    "function_calls",
+                ]
+                .map(String::from)
+                .to_vec());
+                for r in records {
+                    row(vec![
+                        r.session_id.clone(),
+                        r.subject.clone().unwrap_or_default(),
+                        r.tenant.clone(),
+                        r.profile.clone().unwrap_or_default(),
+                        r.started_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+                        r.ended_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+                        format!("{:.3}", r.duration_secs),
+                        r.closed_by.as_str().to_string(),
+                        r.user_audio_bytes.to_string(),
+                        format!("{:.3}", r.user_audio_secs),
+                        r.agent_audio_bytes.to_string(),
+                        format!("{:.3}", r.agent_audio_secs),
+                        r.llm_turns.to_string(),
+                        r.function_calls.to_string(),
+                    ]);
+                }
+            }
+            UsageReport::Groups { fields, groups } => {
+                let mut header = fields.clone();
+                header.extend(
+                    [
+                        "sessions",
+                        "duration_secs",
+                        "user_audio_secs",
+                        "agent_audio_secs",
+                        "llm_turns",
+                        "function_calls",
+                    ]
+                    .map(String::from),
+                );
+                row(header);
+                for (key, t) in groups {
+                    let mut cells = key.clone();
+                    cells.extend([
+                        t.sessions.to_string(),
+                        format!("{:.3}", t.duration_secs),
+                        format!("{:.3}", t.user_audio_secs),
+                        format!("{:.3}", t.agent_audio_secs),
+                        t.llm_turns.to_string(),
+                        t.function_calls.to_string(),
+                    ]);
+                    row(cells);
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Quote a CSV cell when it holds a separator, quote or line break.
+fn csv_cell(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(file: PathBuf, tenant: &str, subject: &str) -> SessionUsage {
+        SessionUsage {
+            usage: Usage::new(),
+            file,
+            session_id: format!("sess-{}", subject),
+            subject: Some(subject.to_string()),
+            tenant: tenant.to_string(),
+            profile: None,
+            started_at: Utc::now() - chrono::Duration::seconds(90),
+            counters: Mutex::new(Counters {
+                input_rate: bytes_per_second(DEFAULT_ENCODING, DEFAULT_SAMPLE_RATE),
+                output_rate: bytes_per_second(DEFAULT_ENCODING, DEFAULT_SAMPLE_RATE),
+                ..Counters::default()
+            }),
+        }
+    }
+
+    /// Frames of a short conversation: two assistant turns, one user turn
+    /// and two requested function calls.
+    fn converse(usage: &SessionUsage) {
+        usage.agent_text(r#"{"type":"Welcome","request_id":"r1"}"#);
+        usage.agent_text(r#"{"type":"ConversationText","role":"user","content":"Hi"}"#);
+        usage.agent_text(r#"{"type":"ConversationText","role":"assistant","content":"Hello"}"#);
+        usage.agent_text(
+            r#"{"type":"FunctionCallRequest","functions":[
+                {"id":"f1","name":"get_time","arguments":"{}","client_side":true},
+                {"id":"f2","name":"lookup","arguments":"{}","client_side":false}
+            ]}"#,
+        );
+        usage.agent_text(r#"{"type":"ConversationText","role":"assistant","content":"Done"}"#);
+    }
+
+    #[test]
+    fn audio_is_timed_with_the_settings_formats() {
+        let usage = session(PathBuf::new(), "acme", "u1");
+        usage.client_text(
+            r#"{"type":"Settings","audio":{
+                "input":{"encoding":"linear16","sample_rate":16000},
+                "output":{"encoding":"mulaw","sample_rate":8000}
+            }}"#,
+        );
+        for _ in 0..4 {
+            usage.client_binary(8000);
+            usage.agent_binary(1000);
+        }
+        converse(&usage);
+
+        let record = usage.record(ClosedBy::Client);
+        assert_eq!(record.user_audio_bytes, 32000);
+        assert_eq!(record.user_audio_secs, 1.0);
+        assert_eq!(record.agent_audio_bytes, 4000);
+        assert_eq!(record.agent_audio_secs, 0.5);
+        assert_eq!(record.llm_turns, 2);
+        assert_eq!(record.function_calls, 2);
+        assert!((90.0..91.0).contains(&record.duration_secs));
+    }
+
+    #[test]
+    fn audio_defaults_to_linear16_at_24_khz() {
+        let usage = session(PathBuf::new(), "acme", "u1");
+        usage.client_text(r#"{"type":"Settings","agent":{"listen":{}}}"#);
+        usage.client_binary(48000 * 3);
+        usage.agent_binary(24000);
+        let record = usage.record(ClosedBy::Agent);
+        assert_eq!(record.user_audio_secs, 3.0);
+        assert_eq!(record.agent_audio_secs, 0.5);
+    }
+
+    #[test]
+    fn compressed_audio_is_counted_in_bytes_only() {
+        let usage = session(PathBuf::new(), "acme", "u1");
+        usage.client_text(
+            r#"{"type":"Settings","audio":{
+                "input":{"encoding":"opus","sample_rate":48000},
+                "output":{"encoding":"linear16","sample_rate":0}
+            }}"#,
+        );
+        usage.client_binary(5000);
+        usage.agent_binary(5000);
+        let record = usage.record(ClosedBy::Client);
+        assert_eq!(
+            (record.user_audio_bytes, record.user_audio_secs),
+            (5000, 0.0)
+        );
+        assert_eq!(
+            (record.agent_audio_bytes, record.agent_audio_secs),
+            (5000, 0.0)
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_total_the_recorded_sessions() {
+        let path = std::env::temp_dir().join(format!("usage-test-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        for (tenant, subject) in [("acme", "u1"), ("acme", "u2"), ("globex", "u3")] {
+            let usage = session(path.clone(), tenant, subject);
+            usage.client_binary(48000);
+            converse(&usage);
+            let record = usage.record(ClosedBy::Client);
+            usage.usage.append(&path, &record).await.unwrap();
+        }
+
+        let query = UsageQuery {
+            group_by: Some("tenant".to_string()),
+            ..UsageQuery::default()
+        };
+        let grouped = report(&path, &query).await.unwrap();
+        let UsageReport::Groups { fields, groups } = grouped else {
+            panic!("expected groups");
+        };
+        assert_eq!(fields, ["tenant"]);
+        assert_eq!(groups.len(), 2);
+        let (key, acme) = &groups[0];
+        assert_eq!(key, &["acme"]);
+        assert_eq!(acme.sessions, 2);
+        assert_eq!(acme.user_audio_secs, 2.0);
+        assert_eq!(acme.llm_turns, 4);
+        assert_eq!(acme.function_calls, 4);
+        assert!((180.0..182.0).contains(&acme.duration_secs));
+
+        let query = UsageQuery {
+            tenant: Some("globex".to_string()),
+            ..UsageQuery::default()
+        };
+        let listed = report(&path, &query).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let UsageReport::Sessions(records) = listed else {
+            panic!("expected sessions");
+        };
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].subject.as_deref(), Some("u3"));
+    }
+}
@@ -0,0 +1,475 @@
+// Outbound webhooks for session lifecycle events.
+//
+// Every endpoint in `[webhooks]` subscribed to an event type receives a JSON
+// POST per event:
+//
+//   {"id": "evt_...", "type": "session.ended", "created_at": "...",
+//    "session_id": "...", "data": {...}}
+//
+// signed with the endpoint's secret so receivers can authenticate it:
+//
+//   X-Webhook-Id:        evt_...
+//   X-Webhook-Timestamp: <unix seconds>
+//   X-Webhook-Signature: sha256=<hex HMAC-SHA256 of "<timestamp>.<body>">
+//
+// Network errors, timeouts, 429 and 5xx responses are retried with
+// exponential backoff. Deliveries that exhaust `webhooks.max_attempts`, or
+// that the receiver rejects outright, are appended to the dead-letter file.
+// Delivery runs on background tasks and never delays the proxied session.
+
+use crate::auth::{Claims, constant_time_eq};
+use crate::config::{AppConfig, WebhookConfig, WebhookEndpoint};
+use crate::protocol::{AgentMessage, ClientMessage, FunctionCall, Role};
+use crate::proxy::ClosedBy;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use rand::{Rng, RngCore};
+use serde::Serialize;
+use serde_json::{Value, json};
+use sha2::Sha256;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, error, warn};
+
+/// Longest delay between two delivery attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Transcript lines kept for the `session.ended` summary (most recent).
+const MAX_TRANSCRIPT_LINES: usize = 200;
+
+// ============================================================================
+// SIGNATURES
+// ============================================================================
+
+/// `X-Webhook-Signature` value for a payload sent at `timestamp`.
+pub fn sign(secret: &[u8], timestamp: &str, body: &[u8]) -> String {
+    format!("sha256={}", hex::encode(mac(secret, timestamp, body)))
+}
+
+/// Check an `X-Webhook-Signature` header against the payload. Receivers
+/// should also reject timestamps too far from the current time.
+pub fn verify_signature(secret: &[u8], timestamp: &str, body: &[u8], signature: &str) -> bool {
+    let Some(Ok(given)) = signature.strip_prefix("sha256=").map(hex::decode) else {
+        return false;
+    };
+    constant_time_eq(&given, &mac(secret, timestamp, body))
+}
+
+fn mac(secret: &[u8], timestamp: &str, body: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    mac.finalize().into_bytes().to_vec()
+}
+
+// ============================================================================
+// DELIVERY
+// ============================================================================
+
+/// Webhook dispatcher shared by all sessions.
+#[derive(Clone, Default)]
+pub struct Webhooks {
+    client: reqwest::Client,
+    /// Serializes appends to the dead-letter file.
+    dead_letter: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl Webhooks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send `kind` to every endpoint subscribed to it, in the background.
+    pub fn emit(&self, config: &WebhookConfig, session_id: &str, kind: &str, data: Value) {
+        let mut id = [0u8; 8];
+        rand::thread_rng().fill_bytes(&mut id);
+        let event = json!({
+            "id": format!("evt_{}", hex::encode(id)),
+            "type": kind,
+            "created_at": Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            "session_id": session_id,
+            "data": data,
+        });
+
+        for endpoint in &config.endpoints {
+            if !endpoint.events.iter().any(|e| e == kind) {
+                continue;
+            }
+            let webhooks = self.clone();
+            let endpoint = endpoint.clone();
+            let config = config.clone();
+            let event = event.clone();
+            tokio::spawn(async move { webhooks.deliver(&endpoint, &config, event).await });
+        }
+    }
+
+    /// POST `event` to `endpoint`, retrying until it is accepted or the
+    /// attempts run out.
+    async fn deliver(&self, endpoint: &WebhookEndpoint, config: &WebhookConfig, event: Value) {
+        let id = event["id"].as_str().unwrap_or_default().to_string();
+        let body = event.to_string();
+        let mut backoff = Duration::from_millis(config.initial_backoff_ms);
+        let mut attempts = 0;
+
+        let failure = loop {
+            attempts += 1;
+            let timestamp = Utc::now().timestamp().to_string();
+            let result = self
+                .client
+                .post(&endpoint.url)
+                .timeout(Duration::from_secs(config.timeout_secs))
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header("X-Webhook-Id", &id)
+                .header("X-Webhook-Timestamp", &timestamp)
+                .header(
+                    "X-Webhook-Signature",
+                    sign(endpoint.secret.as_bytes(), &timestamp, body.as_bytes()),
+                )
+                .body(body.clone())
+                .send()
+                .await;
+
+            let (failure, retryable) = match result {
+                Ok(response) if response.status().is_success() => {
+                    debug!("Webhook {} delivered to {}", id, endpoint.url);
+                    return;
+                }
+                Ok(response) => {
+                    let status = response.status();
+                    let retryable = status.is_server_error()
+                        || status == reqwest::StatusCode::TOO_MANY_REQUESTS;
+                    (format!("HTTP {}", status), retryable)
+                }
+                Err(e) => (e.to_string(), true),
+            };
+            if !retryable || attempts >= config.max_attempts {
+                break failure;
+            }
+
+            // Jitter keeps retries from many sessions from arriving in lockstep
+            let jitter = rand::thread_rng().gen_range(0..=backoff.as_millis() as u64 / 4);
+            let delay = backoff + Duration::from_millis(jitter);
+            warn!(
+                "Webhook {} to {} failed ({}), retrying in {:.1}s",
+                id,
+                endpoint.url,
+                failure,
+                delay.as_secs_f64()
+            );
+            tokio::time::sleep(delay).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        };
+
+        error!(
+            "Webhook {} to {} failed after {} attempt(s): {}",
+            id, endpoint.url, attempts, failure
+        );
+        let entry = json!({
+            "failed_at": Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            "url": endpoint.url,
+            "attempts": attempts,
+            "error": failure,
+            "event": event,
+        });
+        if let Err(e) = self.dead_letter(config, &entry).await {
+            error!(
+                "Cannot write webhook dead letter to {}: {}",
+                config.dead_letter_file.display(),
+                e
+            );
+        }
+    }
+
+    async fn dead_letter(&self, config: &WebhookConfig, entry: &Value) -> std::io::Result<()> {
+        let _lock = self.dead_letter.lock().await;
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.dead_letter_file)
+            .await?;
+        file.write_all(format!("{}\n", entry).as_bytes()).await
+    }
+
+    /// Lifecycle tracker for a new session, or `None` when no endpoints are
+    /// configured.
+    pub fn session(
+        &self,
+        config: Arc<AppConfig>,
+        session_id: &str,
+        claims: &Claims,
+    ) -> Option<SessionHooks> {
+        if config.reloadable.webhooks.endpoints.is_empty() {
+            return None;
+        }
+        Some(SessionHooks {
+            webhooks: self.clone(),
+            config,
+            session_id: session_id.to_string(),
+            subject: claims.sub.clone(),
+            profile: claims.profile.clone(),
+            started: Instant::now(),
+            conversation: Mutex::new(Conversation::default()),
+        })
+    }
+}
+
+// ============================================================================
+// SESSION TRACKING
+// ============================================================================
+
+#[derive(Serialize)]
+struct TranscriptLine {
+    role: Role,
+    content: String,
+}
+
+#[derive(Default)]
+struct Conversation {
+    user_turns: usize,
+    agent_turns: usize,
+    lines_seen: usize,
+    transcript: VecDeque<TranscriptLine>,
+    /// Function calls requested by the agent and not yet answered.
+    pending_calls: HashMap<String, FunctionCall>,
+}
+
+impl Conversation {
+    /// Count a turn and keep its line, dropping the oldest beyond
+    /// `MAX_TRANSCRIPT_LINES`.
+    fn add_line(&mut self, role: Role, content: String) {
+        match role {
+            Role::User => self.user_turns += 1,
+            Role::Assistant => self.agent_turns += 1,
+            _ => {}
+        }
+        self.lines_seen += 1;
+        if self.transcript.len() == MAX_TRANSCRIPT_LINES {
+            self.transcript.pop_front();
+        }
+        self.transcript.push_back(TranscriptLine { role, content });
+    }
+
+    /// The `function_call.executed` payload of an answered call, which is
+    /// no longer pending.
+    fn call_executed(&mut self, id: String, name: String, content: String) -> Value {
+        let call = self.pending_calls.remove(&id);
+        json!({
+            "id": id,
+            "name": name,
+            "arguments": call.map(|c| c.arguments),
+            "result": content,
+        })
+    }
+}
+
+/// Follows the frames of one proxied session and emits its lifecycle
+/// events. Uses the configuration snapshot the session started with.
+pub struct SessionHooks {
+    webhooks: Webhooks,
+    config: Arc<AppConfig>,
+    session_id: String,
+    subject: Option<String>,
+    profile: Option<String>,
+    started: Instant,
+    conversation: Mutex<Conversation>,
+}
+
+impl SessionHooks {
+    fn emit(&self, kind: &str, data: Value) {
+        self.webhooks.emit(
+            &self.config.reloadable.webhooks,
+            &self.session_id,
+            kind,
+            data,
+        );
+    }
+
+    fn conversation(&self) -> std::sync::MutexGuard<'_, Conversation> {
+        self.conversation.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The upstream connection is established.
+    pub fn started(&self) {
+        self.emit(
+            "session.started",
+            json!({ "subject": self.subject, "profile": self.profile }),
+        );
+    }
+
+    /// The proxy could not reach the agent.
+    pub fn connection_failed(&self, description: &str) {
+        self.emit(
+            "agent.error",
+            json!({ "code": "CONNECTION_FAILED", "description": description }),
+        );
+    }
+
+    /// A text frame from the agent.
+    pub fn agent_text(&self, text: &str) {
+        match AgentMessage::parse(text) {
+            Some(AgentMessage::ConversationText { role, content }) => {
+                self.conversation().add_line(role, content);
+            }
+            Some(AgentMessage::FunctionCallRequest { functions }) => {
+                let mut conversation = self.conversation();
+                for call in functions {
+                    conversation.pending_calls.insert(call.id.clone(), call);
+                }
+            }
+            // Run by Deepgram, for calls that are not client-side
+            Some(AgentMessage::FunctionCallResponse { id, name, content }) => {
+                let data = self.conversation().call_executed(id, name, content);
+                self.emit("function_call.executed", data);
+            }
+            Some(AgentMessage::Error { description, code }) => {
+                self.emit(
+                    "agent.error",
+                    json!({ "code": code, "description": description }),
+                );
+            }
+            _ => {}
+        }
+    }
+
+    /// A text frame from the client.
+    pub fn client_text(&self, text: &str) {
+        if let Some(ClientMessage::FunctionCallResponse { id, name, content }) =
+            ClientMessage::parse(text)
+        {
+            let data = self.conversation().call_executed(id, name, content);
+            self.emit("function_call.executed", data);
+        }
+    }
+
+    /// The session is over.
+    pub fn ended(&self, closed_by: ClosedBy, close_code: u16, close_reason: &str) {
+        let conversation = self.conversation();
+        self.emit(
+            "session.ended",
+            json!({
+                "subject": self.subject,
+                "profile": self.profile,
+                "duration_secs": self.started.elapsed().as_secs_f64(),
+                "closed_by": closed_by,
+                "close_code": close_code,
+                "close_reason": close_reason,
+                "transcript": {
+                    "user_turns": conversation.user_turns,
+                    "agent_turns": conversation.agent_turns,
+                    "truncated": conversation.lines_seen > conversation.transcript.len(),
+                    "lines": conversation.transcript,
+                },
+            }),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"whsec-0123456789abcdef";
+
+    #[test]
+    fn signatures_verify() {
+        let signature = sign(SECRET, "1700000000", b"{\"id\":1}");
+        assert!(signature.starts_with("sha256="));
+        assert_eq!(signature.len(), "sha256=".len() + 64);
+        assert!(verify_signature(
+            SECRET,
+            "1700000000",
+            b"{\"id\":1}",
+            &signature
+        ));
+    }
+
+    #[test]
+    fn signatures_cover_the_secret_timestamp_and_body() {
+        let signature = sign(SECRET, "1700000000", b"{\"id\":1}");
+        assert!(!verify_signature(
+            b"another-secret",
+            "1700000000",
+            b"{\"id\":1}",
+            &signature
+        ));
+        assert!(!verify_signature(
+            SECRET,
+            "1700000001",
+            b"{\"id\":1}",
+            &signature
+        ));
+        assert!(!verify_signature(
+            SECRET,
+            "1700000000",
+            b"{\"id\":2}",
+            &signature
+        ));
+    }
+
+    #[test]
+    fn malformed_signatures_are_rejected() {
+        let signature = sign(SECRET, "1", b"body");
+        let hex = signature.strip_prefix("sha256=").unwrap();
+        assert!(!verify_signature(SECRET, "1", b"body", hex));
+        assert!(!verify_signature(SECRET, "1", b"body", "sha256=zz"));
+        assert!(!verify_signature(SECRET, "1", b"body", "sha256="));
+        assert!(!verify_signature(
+            SECRET,
+            "1",
+            b"body",
+            &signature[..signature.len() - 2]
+        ));
+    }
+
+    #[test]
+    fn the_transcript_keeps_the_latest_lines() {
+        let mut conversation = Conversation::default();
+        for i in 0..MAX_TRANSCRIPT_LINES + 5 {
+            let role = if i % 2 == 0 {
+                Role::User
+            } else {
+                Role::Assistant
+            };
+            conversation.add_line(role, format!("line {}", i));
+        }
+        assert_eq!(conversation.lines_seen, MAX_TRANSCRIPT_LINES + 5);
+        assert_eq!(conversation.user_turns, 103);
+        assert_eq!(conversation.agent_turns, 102);
+        assert_eq!(conversation.transcript.len(), MAX_TRANSCRIPT_LINES);
+        assert_eq!(conversation.transcript.front().unwrap().content, "line 5");
+        assert_eq!(
+            conversation.transcript.back().unwrap().content,
+            format!("line {}", MAX_TRANSCRIPT_LINES + 4)
+        );
+    }
+
+    #[test]
+    fn executed_calls_are_no_longer_pending() {
+        let mut conversation = Conversation::default();
+        let Some(AgentMessage::FunctionCallRequest { functions }) = AgentMessage::parse(
+            r#"{"type":"FunctionCallRequest","functions":[
+                {"id":"f1","name":"get_time","arguments":"{}","client_side":true},
+                {"id":"f2","name":"lookup","arguments":"{\"q\":1}","client_side":false}
+            ]}"#,
+        ) else {
+            panic!("expected a FunctionCallRequest");
+        };
+        for call in functions {
+            conversation.pending_calls.insert(call.id.clone(), call);
+        }
+
+        let data = conversation.call_executed("f2".into(), "lookup".into(), "42".into());
+        assert_eq!(
+            data,
+            json!({ "id": "f2", "name": "lookup", "arguments": "{\"q\":1}", "result": "42" })
+        );
+        assert_eq!(conversation.pending_calls.len(), 1);
+
+        let data = conversation.call_executed("f9".into(), "unknown".into(), "".into());
+        assert_eq!(data["arguments"], Value::Null);
+        assert!(conversation.pending_calls.contains_key("f1"));
+    }
+}